@@ -0,0 +1,357 @@
+// control plane served on a separate listener, every request has to be signed by the operator
+//
+// requests carry X-Oyster-Admin-Timestamp (unix seconds), X-Oyster-Admin-Nonce (unique per request,
+// up to MAX_NONCE_LENGTH bytes) and X-Oyster-Admin-Signature, a hex encoded recoverable secp256k1
+// signature (r || s || v, v = 27 or 28) over the keccak256 hash of
+//   |oyster-serverless-admin|
+//   |node|         <signer address of the node, lowercase 0x prefixed hex>
+//   |timestamp|    <timestamp as 8 bytes big endian>
+//   |nonce|        <nonce>
+//   |method|       <request method>
+//   |pathandquery| <request path and query>
+//   |body|         <request body>
+// requests are only accepted within MAX_CLOCK_SKEW seconds of the node clock, and each nonce only once
+// within that window, so the same command can be sent again with a fresh nonce
+// the node address keeps a request for one node from being replayed against others sharing the operator
+
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use k256::ecdsa::SigningKey;
+use k256::elliptic_curve::generic_array::sequence::Lengthen;
+use serde::Serialize;
+use thiserror::Error;
+use tiny_keccak::{Hasher, Keccak};
+
+use crate::config;
+use crate::drain;
+use crate::error::{error_response, ErrorCode};
+use crate::model::AppState;
+use crate::pool;
+use crate::verify::{self, VerifyError};
+
+pub const MAX_CLOCK_SKEW: u64 = 30;
+
+pub const MAX_NONCE_LENGTH: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum AdminError {
+    #[error("missing header {0}")]
+    MissingHeader(&'static str),
+    #[error("invalid header {0}")]
+    InvalidHeader(&'static str),
+    #[error("request timestamp is too far from the node clock")]
+    Expired,
+    #[error("invalid signature")]
+    InvalidSignature(#[source] VerifyError),
+    #[error("request is not signed by the operator")]
+    NotOperator,
+    #[error("nonce has already been used")]
+    Replayed,
+}
+
+pub fn admin_hash(
+    node: &str,
+    timestamp: u64,
+    nonce: &[u8],
+    method: &str,
+    path_and_query: &str,
+    body: &[u8],
+) -> [u8; 32] {
+    let mut hasher = Keccak::v256();
+    hasher.update(b"|oyster-serverless-admin|");
+    hasher.update(b"|node|");
+    hasher.update(node.to_ascii_lowercase().as_bytes());
+    hasher.update(b"|timestamp|");
+    hasher.update(&timestamp.to_be_bytes());
+    hasher.update(b"|nonce|");
+    hasher.update(nonce);
+    hasher.update(b"|method|");
+    hasher.update(method.as_bytes());
+    hasher.update(b"|pathandquery|");
+    hasher.update(path_and_query.as_bytes());
+    hasher.update(b"|body|");
+    hasher.update(body);
+
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash);
+
+    hash
+}
+
+// hex encoded signature for the X-Oyster-Admin-Signature header, used by operator tooling
+pub fn sign(
+    key: &SigningKey,
+    node: &str,
+    timestamp: u64,
+    nonce: &str,
+    method: &str,
+    path_and_query: &str,
+    body: &[u8],
+) -> Result<String, k256::ecdsa::Error> {
+    let hash = admin_hash(
+        node,
+        timestamp,
+        nonce.as_bytes(),
+        method,
+        path_and_query,
+        body,
+    );
+    let (rs, v) = key.sign_prehash_recoverable(&hash)?;
+
+    Ok(hex::encode(rs.to_bytes().append(27 + v.to_byte())))
+}
+
+// random nonce for the X-Oyster-Admin-Nonce header, used by operator tooling
+pub fn nonce() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+pub struct AdminAuth {
+    // lowercase 0x prefixed address
+    operator: String,
+    // signer address of this node, requests are only accepted if they were signed for it
+    node: String,
+    // nonces of accepted requests along with their timestamp, kept until they would expire anyway
+    seen: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl AdminAuth {
+    pub fn new(operator: &str, node: &str) -> AdminAuth {
+        AdminAuth {
+            operator: operator.to_ascii_lowercase(),
+            node: node.to_ascii_lowercase(),
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // checks the request is signed by the operator and its nonce has not been seen before
+    pub fn check(&self, req: &HttpRequest, body: &[u8], now: u64) -> Result<(), AdminError> {
+        let timestamp = req
+            .headers()
+            .get("X-Oyster-Admin-Timestamp")
+            .ok_or(AdminError::MissingHeader("X-Oyster-Admin-Timestamp"))?
+            .to_str()
+            .ok()
+            .and_then(|x| x.parse::<u64>().ok())
+            .ok_or(AdminError::InvalidHeader("X-Oyster-Admin-Timestamp"))?;
+        if timestamp.abs_diff(now) > MAX_CLOCK_SKEW {
+            return Err(AdminError::Expired);
+        }
+
+        let nonce = req
+            .headers()
+            .get("X-Oyster-Admin-Nonce")
+            .ok_or(AdminError::MissingHeader("X-Oyster-Admin-Nonce"))?
+            .as_bytes();
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LENGTH {
+            return Err(AdminError::InvalidHeader("X-Oyster-Admin-Nonce"));
+        }
+
+        let signature = req
+            .headers()
+            .get("X-Oyster-Admin-Signature")
+            .ok_or(AdminError::MissingHeader("X-Oyster-Admin-Signature"))?
+            .to_str()
+            .ok()
+            .and_then(|x| hex::decode(x.trim_start_matches("0x")).ok())
+            .ok_or(AdminError::InvalidHeader("X-Oyster-Admin-Signature"))?;
+
+        let hash = admin_hash(
+            &self.node,
+            timestamp,
+            nonce,
+            req.method().as_str(),
+            req.uri().path_and_query().map(|x| x.as_str()).unwrap_or(""),
+            body,
+        );
+        let signer = verify::recover(&hash, &signature).map_err(AdminError::InvalidSignature)?;
+        if signer != self.operator {
+            return Err(AdminError::NotOperator);
+        }
+
+        // checked after the signature so others cannot use up nonces
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, timestamp| timestamp.abs_diff(now) <= MAX_CLOCK_SKEW);
+        if seen.insert(nonce.to_vec(), timestamp).is_some() {
+            return Err(AdminError::Replayed);
+        }
+
+        Ok(())
+    }
+}
+
+fn authorize(auth: &AdminAuth, req: &HttpRequest, body: &[u8]) -> Result<(), AdminError> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+
+    auth.check(req, body, now)
+}
+
+#[derive(Serialize)]
+struct DrainStatus {
+    running: bool,
+    in_flight: usize,
+    drained: bool,
+}
+
+fn drain_status(appstate: &AppState) -> HttpResponse {
+    HttpResponse::Ok().json(DrainStatus {
+        running: appstate.running.load(Ordering::Relaxed),
+        in_flight: appstate.in_flight.load(Ordering::Relaxed),
+        drained: drain::drained(appstate),
+    })
+}
+
+// lets deploy tooling wait for in flight executions to finish after draining
+pub async fn status(
+    auth: web::Data<AdminAuth>,
+    appstate: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, AdminError> {
+    authorize(&auth, &req, &body)?;
+
+    Ok(drain_status(&appstate))
+}
+
+// stop accepting new executions
+pub async fn drain(
+    auth: web::Data<AdminAuth>,
+    appstate: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, AdminError> {
+    authorize(&auth, &req, &body)?;
+
+    drain::drain(&appstate).await;
+
+    Ok(drain_status(&appstate))
+}
+
+// accept executions again, e.g. when a deploy is rolled back
+pub async fn undrain(
+    auth: web::Data<AdminAuth>,
+    appstate: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, AdminError> {
+    authorize(&auth, &req, &body)?;
+
+    drain::set_running(&appstate, true);
+
+    Ok(drain_status(&appstate))
+}
+
+#[derive(Serialize)]
+struct IdleWorker {
+    tx_hash: String,
+    cgroup: String,
+    port: u16,
+    idle_secs: u64,
+}
+
+#[derive(Serialize)]
+struct Workers {
+    in_flight: usize,
+    idle: Vec<IdleWorker>,
+}
+
+// idle warm workers, busy workers are only counted through the in flight executions
+pub async fn workers(
+    auth: web::Data<AdminAuth>,
+    appstate: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, AdminError> {
+    authorize(&auth, &req, &body)?;
+
+    let idle = match &appstate.worker_pool {
+        Some(pool) => pool
+            .lock()
+            .unwrap()
+            .idle()
+            .iter()
+            .map(|worker| IdleWorker {
+                tx_hash: worker.resources.tx_hash.clone(),
+                cgroup: worker.resources.cgroup().to_owned(),
+                port: worker.port,
+                idle_secs: worker.last_used.elapsed().as_secs(),
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    Ok(HttpResponse::Ok().json(Workers {
+        in_flight: appstate.in_flight.load(Ordering::Relaxed),
+        idle,
+    }))
+}
+
+#[derive(Serialize)]
+struct RetireIdle {
+    retired: usize,
+}
+
+// retire all idle warm workers so the next executions start fresh ones,
+// e.g. to pick up an updated workerd binary in the runtime path
+// busy workers and the node configuration are left alone
+pub async fn retire_idle(
+    auth: web::Data<AdminAuth>,
+    appstate: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, AdminError> {
+    authorize(&auth, &req, &body)?;
+
+    // their cgroups are free once we respond
+    let retired = pool::retire_idle(&appstate).await;
+
+    Ok(HttpResponse::Ok().json(RetireIdle { retired }))
+}
+
+#[derive(Serialize)]
+struct ReloadConfig {
+    egress: String,
+    egress_allow: Vec<String>,
+    default_compatibility_date: String,
+    max_compatibility_date: String,
+    compatibility_flags: Vec<String>,
+    console_limit: Option<usize>,
+    code_cache_size: u64,
+}
+
+// read the config file again, new executions use the new settings
+// a broken file is refused and the current settings are kept
+pub async fn reload_config(
+    auth: web::Data<AdminAuth>,
+    appstate: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, AdminError> {
+    authorize(&auth, &req, &body)?;
+
+    let config = match config::reload(&appstate).await {
+        Ok(config) => config,
+        Err(err) => return Ok(error_response(ErrorCode::InvalidConfig, err)),
+    };
+
+    Ok(HttpResponse::Ok().json(ReloadConfig {
+        egress: config.egress.mode.to_string(),
+        egress_allow: config
+            .egress
+            .rules
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+        default_compatibility_date: config.compatibility.default_date,
+        max_compatibility_date: config.compatibility.max_date,
+        compatibility_flags: config.compatibility.allowed_flags,
+        console_limit: config.console_limit,
+        code_cache_size: config.code_cache_size,
+    }))
+}
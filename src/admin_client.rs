@@ -0,0 +1,94 @@
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
+
+use serverless::admin;
+
+/// Send a signed request to the admin listener of the serverless application
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// base url of the admin listener
+    #[clap(long, value_parser, default_value = "http://127.0.0.1:6002")]
+    url: String,
+
+    /// file containing the raw 32 byte secret key of the operator
+    #[clap(long, value_parser)]
+    key: String,
+
+    /// signer address of the node, as returned by /oyster/identity, requests are only valid for that node
+    #[clap(long, value_parser)]
+    node: String,
+
+    /// admin request to send
+    #[clap(value_enum)]
+    command: Command,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum Command {
+    /// report the drain progress
+    Status,
+    /// stop accepting new executions
+    Drain,
+    /// accept executions again
+    Undrain,
+    /// retire idle warm workers
+    RetireIdle,
+    /// read the --config file of the node again
+    ReloadConfig,
+    /// list idle warm workers and the executions in flight
+    Workers,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Args::parse();
+
+    let key = k256::ecdsa::SigningKey::from_slice(
+        std::fs::read(cli.key)
+            .context("failed to read operator key")?
+            .as_slice(),
+    )
+    .context("invalid operator key")?;
+
+    let (method, path) = match cli.command {
+        Command::Status => (reqwest::Method::GET, "/admin/status"),
+        Command::Drain => (reqwest::Method::POST, "/admin/drain"),
+        Command::Undrain => (reqwest::Method::POST, "/admin/undrain"),
+        Command::RetireIdle => (reqwest::Method::POST, "/admin/retire-idle"),
+        Command::ReloadConfig => (reqwest::Method::POST, "/admin/reload-config"),
+        Command::Workers => (reqwest::Method::GET, "/admin/workers"),
+    };
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let nonce = admin::nonce();
+    let signature = admin::sign(
+        &key,
+        &cli.node,
+        timestamp,
+        &nonce,
+        method.as_str(),
+        path,
+        b"",
+    )
+    .context("failed to sign")?;
+
+    let response = reqwest::Client::new()
+        .request(method, cli.url.trim_end_matches('/').to_owned() + path)
+        .header("X-Oyster-Admin-Timestamp", timestamp.to_string())
+        .header("X-Oyster-Admin-Nonce", nonce)
+        .header("X-Oyster-Admin-Signature", signature)
+        .send()
+        .await
+        .context("failed to send request")?;
+
+    println!("{}", response.status());
+    println!(
+        "{}",
+        response.text().await.context("failed to read response")?
+    );
+
+    Ok(())
+}
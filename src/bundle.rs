@@ -0,0 +1,215 @@
+// bundle format for functions made of several modules
+//
+// magic    4 bytes  "\0oyb"
+// version  1 byte   1
+// entries  until the end of the payload, each made of
+//   kind         1 byte   modules     0 = es module, 1 = wasm, 2 = text, 3 = data, 4 = json
+//                         bindings    5 = text, 6 = json, 7 = secret text
+//                         settings    8 = compatibility date, 9 = compatibility flag, 10 = egress rule
+//   name length  2 bytes  big endian
+//   name         utf8
+//   data length  4 bytes  big endian
+//   data
+//
+// the first module is the entrypoint of the worker and has to be an es module
+// secrets are encrypted to the secrets key of the enclave, see secrets.rs
+// settings have an empty name and carry their value as data
+
+use std::collections::HashSet;
+
+use crate::egress::EgressRule;
+use crate::workerd::{
+    Binding, BindingValue, Deployment, Module, ModuleKind, Secret, ServerlessError,
+};
+
+pub const BUNDLE_MAGIC: &[u8] = b"\0oyb";
+pub const BUNDLE_VERSION: u8 = 1;
+
+pub fn parse(bundle: &[u8]) -> Result<Deployment, ServerlessError> {
+    let mut reader = bundle
+        .strip_prefix(BUNDLE_MAGIC)
+        .ok_or(ServerlessError::InvalidBundle("missing magic"))?;
+
+    if read(&mut reader, 1)?[0] != BUNDLE_VERSION {
+        return Err(ServerlessError::InvalidBundle("unsupported version"));
+    }
+
+    let mut deployment = Deployment::default();
+    let mut module_names = HashSet::new();
+    let mut binding_names = HashSet::new();
+    while !reader.is_empty() {
+        let kind = read(&mut reader, 1)?[0];
+
+        let name_len = u16::from_be_bytes(read(&mut reader, 2)?.try_into().unwrap());
+        let name = std::str::from_utf8(read(&mut reader, name_len.into())?)
+            .map_err(|_| ServerlessError::InvalidBundle("entry name is not valid utf8"))?
+            .to_owned();
+
+        let data_len = u32::from_be_bytes(read(&mut reader, 4)?.try_into().unwrap());
+        let data = read(&mut reader, data_len as usize)?;
+
+        let module_kind = match kind {
+            0 => Some(ModuleKind::EsModule),
+            1 => Some(ModuleKind::Wasm),
+            2 => Some(ModuleKind::Text),
+            3 => Some(ModuleKind::Data),
+            4 => Some(ModuleKind::Json),
+            5..=10 => None,
+            _ => return Err(ServerlessError::InvalidBundle("unknown entry kind")),
+        };
+
+        if (8..=10).contains(&kind) {
+            if !name.is_empty() {
+                return Err(ServerlessError::InvalidBundle("setting has a name"));
+            }
+            let value = String::from_utf8(data.to_vec())
+                .map_err(|_| ServerlessError::InvalidBundle("setting is not valid utf8"))?;
+
+            match kind {
+                8 => {
+                    if deployment.compatibility_date.replace(value).is_some() {
+                        return Err(ServerlessError::InvalidBundle(
+                            "duplicate compatibility date",
+                        ));
+                    }
+                }
+                9 => deployment.compatibility_flags.push(value),
+                _ => deployment.egress.push(
+                    value
+                        .parse::<EgressRule>()
+                        .map_err(ServerlessError::InvalidBundle)?,
+                ),
+            }
+            continue;
+        }
+
+        if let Some(kind) = module_kind {
+            if !is_valid_module_name(&name) {
+                return Err(ServerlessError::InvalidBundle("invalid module name"));
+            }
+            if !module_names.insert(name.clone()) {
+                return Err(ServerlessError::InvalidBundle("duplicate module name"));
+            }
+
+            deployment.modules.push(Module {
+                name,
+                kind,
+                content: data.to_vec(),
+            });
+            continue;
+        }
+
+        if !is_valid_binding_name(&name) {
+            return Err(ServerlessError::InvalidBundle("invalid binding name"));
+        }
+        if !binding_names.insert(name.clone()) {
+            return Err(ServerlessError::InvalidBundle("duplicate binding name"));
+        }
+
+        match kind {
+            5 | 6 => {
+                let value = String::from_utf8(data.to_vec())
+                    .map_err(|_| ServerlessError::InvalidBundle("binding is not valid utf8"))?;
+                let value = match kind {
+                    5 => BindingValue::Text(value),
+                    _ => {
+                        serde_json::from_str::<serde_json::Value>(&value).map_err(|_| {
+                            ServerlessError::InvalidBundle("binding is not valid json")
+                        })?;
+                        BindingValue::Json(value)
+                    }
+                };
+                deployment.bindings.push(Binding { name, value });
+            }
+            _ => deployment.secrets.push(Secret {
+                name,
+                ciphertext: data.to_vec(),
+            }),
+        }
+    }
+
+    match deployment.modules.first() {
+        Some(module) if module.kind == ModuleKind::EsModule => Ok(deployment),
+        Some(_) => Err(ServerlessError::InvalidBundle(
+            "entrypoint is not an es module",
+        )),
+        None => Err(ServerlessError::InvalidBundle("no modules")),
+    }
+}
+
+pub fn encode(deployment: &Deployment) -> Vec<u8> {
+    let mut bundle = BUNDLE_MAGIC.to_vec();
+    bundle.push(BUNDLE_VERSION);
+
+    let mut push = |kind: u8, name: &str, data: &[u8]| {
+        bundle.push(kind);
+        bundle.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        bundle.extend_from_slice(name.as_bytes());
+        bundle.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        bundle.extend_from_slice(data);
+    };
+
+    for module in &deployment.modules {
+        let kind = match module.kind {
+            ModuleKind::EsModule => 0,
+            ModuleKind::Wasm => 1,
+            ModuleKind::Text => 2,
+            ModuleKind::Data => 3,
+            ModuleKind::Json => 4,
+        };
+        push(kind, &module.name, &module.content);
+    }
+    for binding in &deployment.bindings {
+        match &binding.value {
+            BindingValue::Text(value) => push(5, &binding.name, value.as_bytes()),
+            BindingValue::Json(value) => push(6, &binding.name, value.as_bytes()),
+        }
+    }
+    for secret in &deployment.secrets {
+        push(7, &secret.name, &secret.ciphertext);
+    }
+    if let Some(date) = &deployment.compatibility_date {
+        push(8, "", date.as_bytes());
+    }
+    for flag in &deployment.compatibility_flags {
+        push(9, "", flag.as_bytes());
+    }
+    for rule in &deployment.egress {
+        push(10, "", rule.to_string().as_bytes());
+    }
+
+    bundle
+}
+
+fn read<'a>(reader: &mut &'a [u8], len: usize) -> Result<&'a [u8], ServerlessError> {
+    if reader.len() < len {
+        return Err(ServerlessError::InvalidBundle("truncated entry"));
+    }
+    let (data, rest) = reader.split_at(len);
+    *reader = rest;
+
+    Ok(data)
+}
+
+// names become paths inside the runtime directory and strings inside the workerd config,
+// only allow relative paths made of a conservative set of characters
+fn is_valid_module_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 255
+        && name
+            .bytes()
+            .all(|x| x.is_ascii_alphanumeric() || b"._-/".contains(&x))
+        && name
+            .split('/')
+            .all(|segment| !segment.is_empty() && segment != "." && segment != "..")
+}
+
+// bindings are accessed as properties of `env`, only allow identifiers
+pub(crate) fn is_valid_binding_name(name: &str) -> bool {
+    name.len() <= 255
+        && name
+            .bytes()
+            .next()
+            .is_some_and(|x| x.is_ascii_alphabetic() || x == b'_')
+        && name.bytes().all(|x| x.is_ascii_alphanumeric() || x == b'_')
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CodeCacheError {
+    #[error("failed to initialize code cache directory")]
+    Init(#[source] std::io::Error),
+    #[error("failed to write code cache entry")]
+    Write(#[source] std::io::Error),
+    #[error("failed to evict code cache entry")]
+    Evict(#[source] std::io::Error),
+}
+
+struct CacheEntry {
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Index {
+    size: u64,
+    clock: u64,
+    entries: HashMap<String, CacheEntry>,
+    hits: u64,
+    misses: u64,
+}
+
+impl Index {
+    // drop least recently used entries until there is space for `incoming` more bytes,
+    // returns the keys whose files have to be removed
+    fn evict(&mut self, capacity: u64, incoming: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.size + incoming > capacity {
+            let Some(key) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+
+            let entry = self.entries.remove(&key).unwrap();
+            self.size -= entry.size;
+            evicted.push(key);
+        }
+
+        evicted
+    }
+}
+
+// persistent code cache keyed by the cache key of the code source (see `CodeSource::cache_key`), bounded by the total size of cached code
+// entries are stored as files in the cache directory so the cache survives restarts,
+// only the index (sizes and recency) is held in memory and locked, files are read and written outside of it
+// the modification time of a file is its last use, so the recency survives restarts too
+pub struct CodeCache {
+    dir: PathBuf,
+    // changed when the config is reloaded
+    capacity: AtomicU64,
+    index: Mutex<Index>,
+}
+
+impl CodeCache {
+    pub fn new(dir: impl Into<PathBuf>, capacity: u64) -> Result<CodeCache, CodeCacheError> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).map_err(CodeCacheError::Init)?;
+
+        // rebuild the index from the files left over by a previous run,
+        // oldest modification time is treated as least recently used
+        let mut existing = fs::read_dir(&dir)
+            .map_err(CodeCacheError::Init)?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let key = entry
+                    .file_name()
+                    .to_str()?
+                    .strip_suffix(".code")?
+                    .to_owned();
+                if !is_valid_key(&key) {
+                    return None;
+                }
+                let metadata = entry.metadata().ok()?;
+                Some((key, metadata.len(), metadata.modified().ok()?))
+            })
+            .collect::<Vec<_>>();
+        existing.sort_by_key(|x| x.2);
+
+        let mut index = Index::default();
+        for (key, size, _) in existing {
+            index.clock += 1;
+            index.size += size;
+            index.entries.insert(
+                key,
+                CacheEntry {
+                    size,
+                    last_used: index.clock,
+                },
+            );
+        }
+        for key in index.evict(capacity, 0) {
+            remove_entry_file(&path(&dir, &key))?;
+        }
+
+        Ok(CodeCache {
+            dir,
+            capacity: capacity.into(),
+            index: Mutex::new(index),
+        })
+    }
+
+    // entries over the new capacity are evicted right away
+    pub async fn set_capacity(&self, capacity: u64) -> Result<(), CodeCacheError> {
+        self.capacity.store(capacity, Ordering::Relaxed);
+        let evicted = self.index.lock().unwrap().evict(capacity, 0);
+
+        self.remove(evicted).await
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let last_used = {
+            let mut index = self.index.lock().unwrap();
+            if !index.entries.contains_key(key) {
+                index.misses += 1;
+                return None;
+            }
+            index.clock += 1;
+            index.hits += 1;
+            let clock = index.clock;
+            index.entries.get_mut(key).unwrap().last_used = clock;
+            clock
+        };
+
+        let path = self.path(key);
+        let code = tokio::task::spawn_blocking(move || {
+            let code = fs::read(&path)?;
+            // best effort, only affects the eviction order after a restart
+            let _ = fs::File::options()
+                .append(true)
+                .open(&path)
+                .and_then(|file| file.set_modified(SystemTime::now()));
+            Ok::<_, std::io::Error>(code)
+        })
+        .await;
+
+        match code {
+            Ok(Ok(code)) => Some(code),
+            _ => {
+                // entry went missing from disk, forget about it unless it was used or replaced in between
+                let mut index = self.index.lock().unwrap();
+                index.hits -= 1;
+                index.misses += 1;
+                if index
+                    .entries
+                    .get(key)
+                    .is_some_and(|entry| entry.last_used == last_used)
+                {
+                    let entry = index.entries.remove(key).unwrap();
+                    index.size -= entry.size;
+                }
+                None
+            }
+        }
+    }
+
+    pub async fn insert(&self, key: &str, code: &[u8]) -> Result<(), CodeCacheError> {
+        let size = code.len() as u64;
+        // keys end up as file names, only accept tx hashes
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        if !is_valid_key(key) || size > capacity {
+            return Ok(());
+        }
+
+        // write to a temporary file first so a crash never leaves a truncated entry behind,
+        // the name is unique so concurrent inserts of the same key do not clobber each other
+        let tmp_path = self
+            .dir
+            .join(format!("{key}-{:016x}.tmp", rand::random::<u64>()));
+        tokio::fs::write(&tmp_path, code)
+            .await
+            .map_err(CodeCacheError::Write)?;
+        if let Err(err) = tokio::fs::rename(&tmp_path, self.path(key)).await {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(CodeCacheError::Write(err));
+        }
+
+        let evicted = {
+            let mut index = self.index.lock().unwrap();
+            if let Some(entry) = index.entries.remove(key) {
+                index.size -= entry.size;
+            }
+            let evicted = index.evict(capacity, size);
+
+            index.clock += 1;
+            index.size += size;
+            let last_used = index.clock;
+            index
+                .entries
+                .insert(key.to_owned(), CacheEntry { size, last_used });
+
+            evicted
+        };
+
+        self.remove(evicted).await
+    }
+
+    // files of evicted entries
+    async fn remove(&self, evicted: Vec<String>) -> Result<(), CodeCacheError> {
+        for key in evicted {
+            let path = self.path(&key);
+            tokio::task::spawn_blocking(move || remove_entry_file(&path))
+                .await
+                .map_err(|err| CodeCacheError::Evict(err.into()))??;
+        }
+
+        Ok(())
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.index.lock().unwrap().entries.contains_key(key)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.index.lock().unwrap().size
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // hits and misses so far
+    pub fn stats(&self) -> (u64, u64) {
+        let index = self.index.lock().unwrap();
+        (index.hits, index.misses)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        path(&self.dir, key)
+    }
+}
+
+fn path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(key.to_owned() + ".code")
+}
+
+fn remove_entry_file(path: &Path) -> Result<(), CodeCacheError> {
+    match fs::remove_file(path) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(CodeCacheError::Evict(err)),
+    }
+}
+
+fn is_valid_key(key: &str) -> bool {
+    key.len() == 66
+        && key.starts_with("0x")
+        && key[2..]
+            .bytes()
+            .all(|x| x.is_ascii_digit() || (b'a'..=b'f').contains(&x))
+}
@@ -1,5 +1,6 @@
 use std::ffi::OsStr;
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
 
 use thiserror::Error;
@@ -14,6 +15,8 @@ pub enum CgroupsError {
     Execute(#[source] std::io::Error),
 }
 
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
 pub struct Cgroups {
     pub free: Vec<String>,
 }
@@ -26,7 +29,7 @@ impl Cgroups {
     }
 
     pub fn reserve(&mut self) -> Result<String, CgroupsError> {
-        if self.free.len() == 0 {
+        if self.free.is_empty() {
             return Err(CgroupsError::NoFree);
         }
 
@@ -45,16 +48,22 @@ impl Cgroups {
             .arg("-g")
             .arg("memory,cpu:".to_string() + cgroup)
             .args(args)
+            .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()
             .map_err(CgroupsError::Execute)?;
 
         Ok(child)
     }
+
+    // directory with the interface files of the cgroup
+    pub fn path(cgroup: &str) -> PathBuf {
+        Path::new(CGROUP_ROOT).join(cgroup)
+    }
 }
 
 fn get_cgroups() -> Result<Vec<String>, std::io::Error> {
-    Ok(fs::read_dir("/sys/fs/cgroup")?
+    Ok(fs::read_dir(CGROUP_ROOT)?
         .filter_map(|dir| {
             dir.ok().and_then(|dir| {
                 dir.path().file_name().and_then(|name| {
@@ -0,0 +1,162 @@
+// node settings that can be changed without a restart, read from the --config file
+//
+// the file is a json object, every field is optional and falls back to its command line value
+//   {
+//     "egress": "per-function",
+//     "egress_allow": ["8.8.8.8", "API=https://api.example.com"],
+//     "default_compatibility_date": "2023-03-07",
+//     "max_compatibility_date": "2024-01-01",
+//     "compatibility_flags": ["nodejs_compat"],
+//     "console_limit": 4096,
+//     "code_cache_size": 104857600
+//   }
+// console_limit can be null to refuse debug requests
+//
+// the file is read on startup and again on POST /admin/reload-config or SIGHUP,
+// executions already running keep the settings they started with
+
+use std::path::PathBuf;
+
+use actix_web::web;
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+
+use crate::egress::{EgressPolicy, EgressRule};
+use crate::logging;
+use crate::model::AppState;
+use crate::workerd::CompatibilityPolicy;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("node was started without --config")]
+    NoFile,
+    #[error("failed to read config file")]
+    Read(#[source] std::io::Error),
+    #[error("failed to parse config file")]
+    Parse(#[source] serde_json::Error),
+    #[error("invalid egress mode {0}")]
+    EgressMode(String),
+    #[error("invalid egress rule {0}: {1}")]
+    EgressRule(String, &'static str),
+    #[error("failed to resize code cache")]
+    CodeCache(#[source] crate::cache::CodeCacheError),
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    pub compatibility: CompatibilityPolicy,
+    pub egress: EgressPolicy,
+    // max bytes of console output returned to debug requests, debug requests are refused when unset
+    pub console_limit: Option<usize>,
+    // max total bytes of cached code
+    pub code_cache_size: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    egress: Option<String>,
+    egress_allow: Option<Vec<String>>,
+    default_compatibility_date: Option<String>,
+    max_compatibility_date: Option<String>,
+    compatibility_flags: Option<Vec<String>>,
+    // a missing field keeps the command line value, null disables debug requests
+    #[serde(default, deserialize_with = "present")]
+    console_limit: Option<Option<usize>>,
+    code_cache_size: Option<u64>,
+}
+
+fn present<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+impl NodeConfig {
+    // settings of the file on top of these ones
+    pub fn apply(&self, file: &[u8]) -> Result<NodeConfig, ConfigError> {
+        let file: ConfigFile = serde_json::from_slice(file).map_err(ConfigError::Parse)?;
+        let mut config = self.clone();
+
+        if let Some(mode) = file.egress {
+            config.egress.mode = mode
+                .parse()
+                .map_err(|_| ConfigError::EgressMode(mode.clone()))?;
+        }
+        if let Some(rules) = file.egress_allow {
+            config.egress.rules = rules
+                .into_iter()
+                .map(|rule| {
+                    rule.parse::<EgressRule>()
+                        .map_err(|err| ConfigError::EgressRule(rule.clone(), err))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(date) = file.default_compatibility_date {
+            config.compatibility.default_date = date;
+        }
+        if let Some(date) = file.max_compatibility_date {
+            config.compatibility.max_date = date;
+        }
+        if let Some(flags) = file.compatibility_flags {
+            config.compatibility.allowed_flags = flags;
+        }
+        if let Some(limit) = file.console_limit {
+            config.console_limit = limit;
+        }
+        if let Some(size) = file.code_cache_size {
+            config.code_cache_size = size;
+        }
+
+        Ok(config)
+    }
+}
+
+// where the settings come from, the command line ones are kept so fields removed from the file fall back to them
+pub struct ConfigSource {
+    pub path: Option<PathBuf>,
+    pub command_line: NodeConfig,
+}
+
+impl ConfigSource {
+    pub async fn load(&self) -> Result<NodeConfig, ConfigError> {
+        let Some(path) = &self.path else {
+            return Ok(self.command_line.clone());
+        };
+
+        let file = tokio::fs::read(path).await.map_err(ConfigError::Read)?;
+        self.command_line.apply(&file)
+    }
+}
+
+// read the config file again and switch the node over to it
+pub async fn reload(appstate: &AppState) -> Result<NodeConfig, ConfigError> {
+    if appstate.config_source.path.is_none() {
+        return Err(ConfigError::NoFile);
+    }
+
+    let config = appstate.config_source.load().await?;
+    appstate
+        .code_cache
+        .set_capacity(config.code_cache_size)
+        .await
+        .map_err(ConfigError::CodeCache)?;
+    *appstate.config.write().unwrap() = config.clone();
+
+    Ok(config)
+}
+
+// reload on SIGHUP, a broken file keeps the current settings
+pub async fn reload_on_signal(appstate: web::Data<AppState>) -> std::io::Result<()> {
+    let mut signal = signal(SignalKind::hangup())?;
+    loop {
+        signal.recv().await;
+
+        info!("Received SIGHUP, reloading config");
+        if let Err(err) = reload(&appstate).await {
+            error!(error = logging::chain(err), "failed to reload config");
+        }
+    }
+}
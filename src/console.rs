@@ -0,0 +1,124 @@
+use std::io::{BufRead, BufReader, Read};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::{HeaderName, HeaderValue};
+
+pub const CONSOLE_HEADER: &str = "x-oyster-console";
+pub const TRUNCATED_HEADER: &str = "x-oyster-console-truncated";
+
+// set by the node, workers cannot set them
+pub const CONSOLE_HEADERS: [&str; 2] = [CONSOLE_HEADER, TRUNCATED_HEADER];
+
+// the worker writes its output before responding, but the capture threads may not have read it yet
+// once the response is in, output is collected until no line arrives for QUIET, for up to MAX_GRACE
+const QUIET: Duration = Duration::from_millis(20);
+const MAX_GRACE: Duration = Duration::from_millis(200);
+
+// console output of a worker, only kept while a request asked for it
+// a worker serves a single request at a time, so the output captured in between belongs to that request
+#[derive(Default)]
+pub struct Console(Mutex<ConsoleState>);
+
+#[derive(Default)]
+struct ConsoleState {
+    capturing: bool,
+    output: String,
+    truncated: bool,
+    // max bytes of output kept
+    limit: usize,
+    // lines that arrived while capturing, kept or not
+    lines: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Captured {
+    pub output: String,
+    // lines were dropped since the output would have been larger than the limit
+    pub truncated: bool,
+}
+
+impl Captured {
+    // the output can span lines and is not necessarily ascii, it is base64 encoded
+    pub fn headers(&self) -> Vec<(HeaderName, HeaderValue)> {
+        let mut headers = vec![(
+            HeaderName::from_static(CONSOLE_HEADER),
+            HeaderValue::from_str(&data_encoding::BASE64.encode(self.output.as_bytes())).unwrap(),
+        )];
+        if self.truncated {
+            headers.push((
+                HeaderName::from_static(TRUNCATED_HEADER),
+                HeaderValue::from_static("true"),
+            ));
+        }
+
+        headers
+    }
+}
+
+impl Console {
+    // start capturing for a new request, anything captured before is discarded
+    pub fn start(&self, limit: usize) {
+        let mut state = self.0.lock().unwrap();
+        state.capturing = true;
+        state.output.clear();
+        state.truncated = false;
+        state.limit = limit;
+        state.lines = 0;
+    }
+
+    // stop once the output of the worker has been read, see QUIET
+    pub async fn finish(&self) -> Captured {
+        let deadline = Instant::now() + MAX_GRACE;
+        loop {
+            let lines = self.0.lock().unwrap().lines;
+            tokio::time::sleep(QUIET).await;
+            if self.0.lock().unwrap().lines == lines || Instant::now() >= deadline {
+                return self.stop();
+            }
+        }
+    }
+
+    pub fn stop(&self) -> Captured {
+        let mut state = self.0.lock().unwrap();
+        state.capturing = false;
+
+        Captured {
+            output: std::mem::take(&mut state.output),
+            truncated: state.truncated,
+        }
+    }
+
+    // whole lines are kept or dropped, lines after a dropped one are dropped too
+    pub fn push(&self, line: &str) {
+        let mut state = self.0.lock().unwrap();
+        if !state.capturing {
+            return;
+        }
+        state.lines += 1;
+        if state.truncated {
+            return;
+        }
+        if state.output.len() + line.len() + 1 > state.limit {
+            state.truncated = true;
+            return;
+        }
+        state.output.push_str(line);
+        state.output.push('\n');
+    }
+}
+
+// read a pipe of the worker in the background until it is closed, so the worker never blocks on a full pipe
+pub fn capture(console: Arc<Console>, pipe: impl Read + Send + 'static) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(pipe);
+        let mut line = Vec::new();
+        // output is not guaranteed to be valid utf8, read raw lines
+        while let Ok(1..) = reader.read_until(b'\n', &mut line) {
+            let text = String::from_utf8_lossy(&line);
+            console.push(text.trim_end_matches(['\r', '\n']));
+            line.clear();
+        }
+    })
+}
@@ -0,0 +1,69 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use actix_web::web;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::info;
+
+use crate::model::AppState;
+use crate::pool;
+
+// marks an execution as in flight for as long as it is alive, including its streamed body
+pub struct InFlight(web::Data<AppState>);
+
+impl InFlight {
+    pub fn new(appstate: web::Data<AppState>) -> InFlight {
+        appstate.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(appstate)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// draining and no executions left
+// IMPORTANT: we use Relaxed ordering here, a request racing with a drain might be missed
+// this is fine since the http server finishes in flight requests before shutting down anyway
+pub fn drained(appstate: &AppState) -> bool {
+    !appstate.running.load(Ordering::Relaxed) && appstate.in_flight.load(Ordering::Relaxed) == 0
+}
+
+// a draining server refuses new executions, the ones in flight finish
+pub fn set_running(appstate: &AppState, running: bool) {
+    appstate.running.store(running, Ordering::Relaxed);
+}
+
+// stop accepting new executions and retire the idle warm workers,
+// busy workers are retired instead of going back to the pool once they are done
+pub async fn drain(appstate: &AppState) {
+    set_running(appstate, false);
+    pool::retire_idle(appstate).await;
+}
+
+// drain on SIGUSR1, lets nodes without an operator be drained by whoever runs them
+pub async fn drain_on_signal(appstate: web::Data<AppState>) -> std::io::Result<()> {
+    let mut signal = signal(SignalKind::user_defined1())?;
+    loop {
+        signal.recv().await;
+
+        info!("Received SIGUSR1, draining");
+        drain(&appstate).await;
+    }
+}
+
+// resolves once the server has drained and no workers are left behind
+pub async fn wait_drained(appstate: web::Data<AppState>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(100));
+    loop {
+        interval.tick().await;
+
+        if drained(&appstate) {
+            // workers that went back to the pool while the drain started
+            pool::retire_idle(&appstate).await;
+            return;
+        }
+    }
+}
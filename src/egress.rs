@@ -0,0 +1,217 @@
+use std::fmt;
+use std::str::FromStr;
+
+use ipnet::IpNet;
+
+use crate::bundle::is_valid_binding_name;
+use crate::workerd::ServerlessError;
+
+// outbound access granted to a worker
+//
+// workerd can only restrict fetch() by ip address, so hosts are reached through
+// service bindings that forward every request to a fixed address instead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EgressRule {
+    // addresses reachable through fetch(), written as an ip or a cidr
+    Network(IpNet),
+    // host reachable through env.<binding>.fetch(), written as <binding>=<http|https>://<host>[:<port>]
+    Host {
+        binding: String,
+        https: bool,
+        host: String,
+        port: u16,
+    },
+}
+
+impl FromStr for EgressRule {
+    type Err = &'static str;
+
+    fn from_str(rule: &str) -> Result<EgressRule, Self::Err> {
+        let Some((binding, url)) = rule.split_once('=') else {
+            return rule
+                .parse::<IpNet>()
+                .or_else(|_| rule.parse::<std::net::IpAddr>().map(IpNet::from))
+                .map(EgressRule::Network)
+                .map_err(|_| "invalid ip or cidr");
+        };
+
+        if !is_valid_binding_name(binding) {
+            return Err("invalid binding name");
+        }
+        let (https, address) = if let Some(address) = url.strip_prefix("https://") {
+            (true, address)
+        } else if let Some(address) = url.strip_prefix("http://") {
+            (false, address)
+        } else {
+            return Err("url must start with http:// or https://");
+        };
+        let address = address.strip_suffix('/').unwrap_or(address);
+
+        let (host, port) = match address.split_once(':') {
+            Some((host, port)) => (host, port.parse::<u16>().map_err(|_| "invalid port")?),
+            None => (address, if https { 443 } else { 80 }),
+        };
+        if host.is_empty()
+            || !host
+                .bytes()
+                .all(|x| x.is_ascii_alphanumeric() || x == b'.' || x == b'-')
+        {
+            return Err("invalid host");
+        }
+        // addresses are written as network rules, hosts have to be names
+        let lowercase = host.to_ascii_lowercase();
+        if host.parse::<std::net::IpAddr>().is_ok()
+            || lowercase == "localhost"
+            || lowercase.ends_with(".localhost")
+        {
+            return Err("host must not be an ip or localhost");
+        }
+
+        Ok(EgressRule::Host {
+            binding: binding.to_owned(),
+            https,
+            host: lowercase,
+            port,
+        })
+    }
+}
+
+impl fmt::Display for EgressRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EgressRule::Network(net) => write!(f, "{net}"),
+            EgressRule::Host {
+                binding,
+                https,
+                host,
+                port,
+            } => {
+                let scheme = if *https { "https" } else { "http" };
+                write!(f, "{binding}={scheme}://{host}:{port}")
+            }
+        }
+    }
+}
+
+// ranges that are never reachable through rules declared by a deployment,
+// since they reach the host, the enclave itself or the network around it
+const NON_PUBLIC: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "::ffff:0:0/96",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+fn is_public(net: &IpNet) -> bool {
+    NON_PUBLIC.iter().all(|range| {
+        let range = range.parse::<IpNet>().unwrap();
+        !range.contains(&net.network()) && !net.contains(&range.network())
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EgressMode {
+    // default workerd access, any public address
+    Public,
+    // no outbound access at all
+    Deny,
+    // only the rules configured by the operator
+    Allowlist,
+    // the rules declared by the deployment, limited to the operator rules if there are any
+    // and to public addresses otherwise, hosts always need an operator rule
+    PerFunction,
+}
+
+impl fmt::Display for EgressMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EgressMode::Public => "public",
+            EgressMode::Deny => "deny",
+            EgressMode::Allowlist => "allowlist",
+            EgressMode::PerFunction => "per-function",
+        })
+    }
+}
+
+// written as on the command line, used by the config file
+impl FromStr for EgressMode {
+    type Err = &'static str;
+
+    fn from_str(mode: &str) -> Result<EgressMode, Self::Err> {
+        match mode {
+            "public" => Ok(EgressMode::Public),
+            "deny" => Ok(EgressMode::Deny),
+            "allowlist" => Ok(EgressMode::Allowlist),
+            "per-function" => Ok(EgressMode::PerFunction),
+            _ => Err("egress mode must be public, deny, allowlist or per-function"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EgressPolicy {
+    pub mode: EgressMode,
+    pub rules: Vec<EgressRule>,
+}
+
+impl EgressPolicy {
+    // rules the worker is restricted to, None keeps the default workerd network access
+    pub fn resolve(
+        &self,
+        requested: &[EgressRule],
+    ) -> Result<Option<Vec<EgressRule>>, ServerlessError> {
+        match self.mode {
+            EgressMode::Public => Ok(None),
+            EgressMode::Deny => Ok(Some(Vec::new())),
+            EgressMode::Allowlist => Ok(Some(self.rules.clone())),
+            EgressMode::PerFunction => {
+                if let Some(rule) = requested.iter().find(|rule| !self.permits(rule)) {
+                    return Err(ServerlessError::EgressNotAllowed(rule.to_string()));
+                }
+
+                Ok(Some(requested.to_vec()))
+            }
+        }
+    }
+
+    fn permits(&self, requested: &EgressRule) -> bool {
+        // without operator rules, deployments can only reach public addresses
+        // hosts are resolved by workerd when connecting, a name can resolve to an internal address at any time
+        if self.rules.is_empty() {
+            return match requested {
+                EgressRule::Network(net) => is_public(net),
+                EgressRule::Host { .. } => false,
+            };
+        }
+
+        self.rules.iter().any(|rule| match (rule, requested) {
+            (EgressRule::Network(allowed), EgressRule::Network(requested)) => {
+                allowed.contains(requested)
+            }
+            // binding names are up to the deployment
+            (
+                EgressRule::Host {
+                    https, host, port, ..
+                },
+                EgressRule::Host {
+                    https: requested_https,
+                    host: requested_host,
+                    port: requested_port,
+                    ..
+                },
+            ) => https == requested_https && host == requested_host && port == requested_port,
+            _ => false,
+        })
+    }
+}
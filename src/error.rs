@@ -0,0 +1,193 @@
+use std::fmt;
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+
+use crate::admin::AdminError;
+use crate::cgroups::CgroupsError;
+use crate::workerd::ServerlessError;
+
+// machine readable errors returned by the node itself, clients match on the code
+// IMPORTANT: codes are part of the api, never rename or repurpose them, add new ones instead
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // the node is draining and no longer accepts requests
+    Draining,
+    InvalidHost,
+    InvalidTxHash,
+    InvalidNonce,
+    InvalidSignatureVersion,
+    InvalidBody,
+    BodyTooLarge,
+    // the tx or the code it points to does not exist
+    TxNotFound,
+    CodeNotFound,
+    // the tx is not a call to the code contract
+    InvalidTx,
+    WrongContract,
+    InvalidCalldata,
+    CodeHashMismatch,
+    InvalidBundle,
+    InvalidSecret,
+    CompatibilityNotAllowed,
+    EgressNotAllowed,
+    // the request asked for debug output the node does not provide
+    DebugNotAllowed,
+    // the rpc or code storage could not be reached
+    UpstreamUnavailable,
+    NoCapacity,
+    SyntaxError,
+    WorkerStartFailed,
+    WorkerTimeout,
+    WorkerFailed,
+    AttestationUnavailable,
+    // admin request not signed by the operator, expired or replayed
+    Unauthorized,
+    // the config file could not be reloaded, the node keeps its current settings
+    InvalidConfig,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> StatusCode {
+        use ErrorCode::*;
+        match self {
+            Draining => StatusCode::GONE,
+            InvalidHost
+            | InvalidTxHash
+            | InvalidNonce
+            | InvalidSignatureVersion
+            | InvalidBody
+            | TxNotFound
+            | CodeNotFound
+            | InvalidTx
+            | WrongContract
+            | InvalidCalldata
+            | CodeHashMismatch
+            | InvalidBundle
+            | InvalidSecret
+            | CompatibilityNotAllowed
+            | EgressNotAllowed
+            | DebugNotAllowed
+            | InvalidConfig
+            | SyntaxError => StatusCode::BAD_REQUEST,
+            BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            UpstreamUnavailable | AttestationUnavailable => StatusCode::BAD_GATEWAY,
+            NoCapacity => StatusCode::TOO_MANY_REQUESTS,
+            Unauthorized => StatusCode::UNAUTHORIZED,
+            WorkerTimeout => StatusCode::REQUEST_TIMEOUT,
+            WorkerStartFailed | WorkerFailed | Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // whether the same request can succeed later or on another node
+    pub fn retryable(&self) -> bool {
+        use ErrorCode::*;
+        matches!(
+            self,
+            Draining
+                | UpstreamUnavailable
+                | NoCapacity
+                | WorkerStartFailed
+                | AttestationUnavailable
+                | Internal
+        )
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.serialize(f)
+    }
+}
+
+impl From<&ServerlessError> for ErrorCode {
+    fn from(err: &ServerlessError) -> ErrorCode {
+        use ServerlessError::*;
+        match err {
+            CalldataRetrieve(_) | CodeFetch(_) => ErrorCode::UpstreamUnavailable,
+            TxNotFound => ErrorCode::TxNotFound,
+            InvalidTxToType | InvalidTxCalldataType => ErrorCode::InvalidTx,
+            InvalidTxToValue(_, _) => ErrorCode::WrongContract,
+            BadCalldata(_) | CalldataTooShort | InvalidSelector(_) | InvalidCodeOffset
+            | InvalidCodeLength => ErrorCode::InvalidCalldata,
+            CodeNotFound => ErrorCode::CodeNotFound,
+            CodeHashMismatch => ErrorCode::CodeHashMismatch,
+            InvalidBundle(_) | EgressBindingConflict(_) => ErrorCode::InvalidBundle,
+            SecretDecrypt(_, _) => ErrorCode::InvalidSecret,
+            CompatibilityDateNotAllowed(_) | CompatibilityFlagNotAllowed(_) => {
+                ErrorCode::CompatibilityNotAllowed
+            }
+            EgressNotAllowed(_) => ErrorCode::EgressNotAllowed,
+            Execute(err) => err.into(),
+            NoFreeCgroup => ErrorCode::NoCapacity,
+            SyntaxError(_) => ErrorCode::SyntaxError,
+            WorkerStart(_) => ErrorCode::WorkerStartFailed,
+            CodeRead(_) | CodeFileCreate(_) | ConfigFileCreate(_) | Terminate(_)
+            | CodeFileDelete(_) | ConfigFileDelete(_) | BadPort(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl From<&CgroupsError> for ErrorCode {
+    fn from(err: &CgroupsError) -> ErrorCode {
+        match err {
+            CgroupsError::Fetch(_) => ErrorCode::Internal,
+            CgroupsError::NoFree => ErrorCode::NoCapacity,
+            CgroupsError::Execute(_) => ErrorCode::WorkerStartFailed,
+        }
+    }
+}
+
+impl ResponseError for ServerlessError {
+    fn status_code(&self) -> StatusCode {
+        ErrorCode::from(self).status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        error_response(self.into(), self)
+    }
+}
+
+impl ResponseError for CgroupsError {
+    fn status_code(&self) -> StatusCode {
+        ErrorCode::from(self).status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        error_response(self.into(), self)
+    }
+}
+
+impl ResponseError for AdminError {
+    fn status_code(&self) -> StatusCode {
+        ErrorCode::Unauthorized.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        error_response(ErrorCode::Unauthorized, self)
+    }
+}
+
+// body of every error response returned by the node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+    pub retryable: bool,
+}
+
+// message is meant for humans and can change, only the top level error is included
+// the code is also kept in the response extensions for metrics
+pub fn error_response(code: ErrorCode, message: impl fmt::Display) -> HttpResponse {
+    let mut resp = HttpResponse::build(code.status()).json(ErrorBody {
+        code,
+        message: message.to_string(),
+        retryable: code.retryable(),
+    });
+    resp.extensions_mut().insert(code);
+
+    resp
+}
@@ -0,0 +1,205 @@
+// registration with the gateway routing executions to nodes
+//
+// every call is a json POST to <url>/<call>, signed by the node so the gateway knows it comes from the
+// enclave, with X-Oyster-Timestamp (unix seconds) and X-Oyster-Signature, a hex encoded recoverable
+// secp256k1 signature (r || s || v, v = 27 or 28) over the keccak256 hash of
+//   |oyster-serverless-gateway|
+//   |timestamp|    <timestamp as 8 bytes big endian>
+//   |call|         <register, heartbeat or deregister>
+//   |body|         <json body as sent>
+// the signer has to match the address in the body, gateways should also refuse stale timestamps
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use actix_web::web;
+use k256::ecdsa::SigningKey;
+use k256::elliptic_curve::generic_array::sequence::Lengthen;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tiny_keccak::{Hasher, Keccak};
+use tracing::warn;
+
+use crate::logging;
+use crate::model::AppState;
+use crate::verify;
+
+#[derive(Error, Debug)]
+pub enum GatewayError {
+    #[error("failed to reach gateway")]
+    Request(#[from] reqwest::Error),
+    #[error("gateway rejected the request with status {0}")]
+    Rejected(u16),
+    #[error("failed to sign the request")]
+    Sign(#[source] anyhow::Error),
+}
+
+pub fn gateway_hash(timestamp: u64, call: &str, body: &[u8]) -> [u8; 32] {
+    let mut hasher = Keccak::v256();
+    hasher.update(b"|oyster-serverless-gateway|");
+    hasher.update(b"|timestamp|");
+    hasher.update(&timestamp.to_be_bytes());
+    hasher.update(b"|call|");
+    hasher.update(call.as_bytes());
+    hasher.update(b"|body|");
+    hasher.update(body);
+
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash);
+
+    hash
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Registration {
+    // signer address, identifies the node
+    pub address: String,
+    pub version: String,
+    // port serving executions
+    pub port: u16,
+    // max number of concurrent executions
+    pub capacity: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub address: String,
+    pub in_flight: usize,
+    pub free_cgroups: usize,
+    pub idle_workers: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Deregistration {
+    pub address: String,
+}
+
+// client for the gateway, calls are signed with the enclave key
+pub struct GatewayClient {
+    url: String,
+    signer: SigningKey,
+    client: reqwest::Client,
+}
+
+impl GatewayClient {
+    pub fn new(url: &str, signer: SigningKey) -> GatewayClient {
+        GatewayClient {
+            url: url.trim_end_matches('/').to_owned(),
+            signer,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .unwrap(),
+        }
+    }
+
+    // timestamp and hex encoded signature of the call
+    fn sign(&self, call: &str, body: &[u8]) -> Result<(u64, String), anyhow::Error> {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        let (rs, v) = self
+            .signer
+            .sign_prehash_recoverable(&gateway_hash(timestamp, call, body))?;
+
+        Ok((
+            timestamp,
+            hex::encode(rs.to_bytes().append(27 + v.to_byte())),
+        ))
+    }
+
+    async fn post(&self, call: &str, body: &impl Serialize) -> Result<(), GatewayError> {
+        let body = serde_json::to_vec(body).map_err(|err| GatewayError::Sign(err.into()))?;
+        let (timestamp, signature) = self.sign(call, &body).map_err(GatewayError::Sign)?;
+
+        let response = self
+            .client
+            .post(format!("{}/{call}", self.url))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Oyster-Timestamp", timestamp.to_string())
+            .header("X-Oyster-Signature", signature)
+            .body(body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(GatewayError::Rejected(response.status().as_u16()));
+        }
+
+        Ok(())
+    }
+
+    pub async fn register(&self, registration: &Registration) -> Result<(), GatewayError> {
+        self.post("register", registration).await
+    }
+
+    pub async fn heartbeat(&self, heartbeat: &Heartbeat) -> Result<(), GatewayError> {
+        self.post("heartbeat", heartbeat).await
+    }
+
+    pub async fn deregister(&self, deregistration: &Deregistration) -> Result<(), GatewayError> {
+        self.post("deregister", deregistration).await
+    }
+}
+
+pub fn registration(appstate: &AppState, port: u16) -> Registration {
+    Registration {
+        address: verify::address(appstate.signer.verifying_key()),
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        port,
+        capacity: appstate.cgroups.lock().unwrap().free.len(),
+    }
+}
+
+fn heartbeat(appstate: &AppState, address: &str) -> Heartbeat {
+    Heartbeat {
+        address: address.to_owned(),
+        in_flight: appstate.in_flight.load(Ordering::Relaxed),
+        free_cgroups: appstate.cgroups.lock().unwrap().free.len(),
+        idle_workers: appstate
+            .worker_pool
+            .as_ref()
+            .map(|pool| pool.lock().unwrap().len())
+            .unwrap_or_default(),
+    }
+}
+
+// keep the node registered with the gateway while it is running
+// registers on startup and after being undrained, heartbeats every interval and deregisters on drain,
+// failed calls are logged and retried on the next tick
+pub async fn run(
+    client: GatewayClient,
+    appstate: web::Data<AppState>,
+    registration: Registration,
+    interval: Duration,
+) {
+    let deregistration = Deregistration {
+        address: registration.address.clone(),
+    };
+    let mut registered = false;
+
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+
+        let running = appstate.running.load(Ordering::Relaxed);
+        let result = match (running, registered) {
+            (true, false) => client.register(&registration).await,
+            (true, true) => {
+                client
+                    .heartbeat(&heartbeat(&appstate, &registration.address))
+                    .await
+            }
+            (false, true) => client.deregister(&deregistration).await,
+            (false, false) => continue,
+        };
+
+        match result {
+            // a failed heartbeat does not change the registration
+            Ok(()) => registered = running,
+            Err(err) => warn!(
+                error = logging::chain(err),
+                "failed to update gateway registration"
+            ),
+        }
+    }
+}
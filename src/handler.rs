@@ -1,27 +1,132 @@
-use crate::{cgroups, model::AppState, workerd};
+use crate::cgroups::{self, Cgroups};
+use crate::console::{self, Captured, Console};
+use crate::drain::InFlight;
+use crate::error::{error_response, ErrorCode};
+use crate::logging;
+use crate::pool::{Resources, Worker};
+use crate::usage::UsageMeter;
+use crate::verify::{self, SignatureScheme, SignatureVersion};
+use crate::workerd::ServerlessError;
+use crate::{model::AppState, workerd};
 
-use actix_web::http::{header, StatusCode};
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
-use anyhow::{anyhow, Context};
-use std::io::{BufRead, BufReader};
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::http::header;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError};
+use anyhow::Context;
+use futures::Stream;
+use serde::Serialize;
+use std::io::Read;
+use std::pin::Pin;
 use std::sync::atomic::Ordering;
-use std::time::Duration;
-// use std::time::Instant;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
 use tokio::time::timeout;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+
+// max time the worker gets to respond, and to send each chunk of a streamed response
+const WORKER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// same as the default limit of the web::Bytes extractor
+const BODY_LIMIT: usize = 256 * 1024;
+
+const MAX_NONCE_LENGTH: usize = 256;
+
+// body of a buffered request, kept in the request extensions so errors can be signed over it
+struct RequestBody(web::Bytes);
 
 pub async fn serverless(
-    body: web::Bytes,
+    payload: web::Payload,
     appstate: web::Data<AppState>,
     req: HttpRequest,
 ) -> impl Responder {
-    // check if the server is draining
-    // IMPORTANT: we use Relaxed ordering here since we do not need to synchronize any memory
-    // not even with reads/writes to the same atomic (we just serve a few more requests at worst)
-    // be very careful adding more operations associated with the draining state
-    if !appstate.running.load(Ordering::Relaxed) {
-        return HttpResponse::Gone().body("worker unregistered");
+    // every call gets a request id, it tags the logs of the call and the files of its worker
+    let slug = hex::encode(rand::random::<u32>().to_ne_bytes());
+    let span = info_span!("request", request_id = %slug, tx_hash = tracing::field::Empty);
+
+    let start = Instant::now();
+    let mut payload = Some(payload);
+    let mut resp = serve(&mut payload, appstate.clone(), req.clone(), &slug)
+        .instrument(span.clone())
+        .await;
+
+    // errors produced by the node carry their code
+    let outcome = resp
+        .extensions()
+        .get::<ErrorCode>()
+        .map_or("ok".to_owned(), ErrorCode::to_string);
+    appstate.metrics.requests.inc(&outcome);
+    // streamed responses are done once their headers are in
+    span.in_scope(|| {
+        info!(
+            status = resp.status().as_u16(),
+            outcome,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "request done"
+        )
+    });
+
+    // not covered by the signature, it is only meant for correlating logs
+    resp.headers_mut().insert(
+        header::HeaderName::from_static("x-oyster-request-id"),
+        header::HeaderValue::from_str(&slug).unwrap(),
+    );
+
+    // responses from the worker are already signed, sign anything the node returned itself
+    if resp.headers().contains_key("X-Oyster-Signature")
+        || resp.headers().contains_key("X-Oyster-Stream")
+    {
+        return resp;
     }
 
+    let host_header = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or("");
+    let scheme = req
+        .headers()
+        .get("X-Oyster-Signature-Version")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse().ok())
+        .and_then(|x| appstate.signature.with_version(x).ok())
+        .unwrap_or_else(|| appstate.signature.clone());
+
+    // errors cover the request body, buffered requests refused before it was read are read here,
+    // streamed ones are not since clients may wait for the response before sending all of it
+    let request_body = match payload {
+        Some(payload) if !is_streaming(&req) => payload
+            .to_bytes_limited(BODY_LIMIT)
+            .await
+            .ok()
+            .and_then(Result::ok),
+        Some(_) => None,
+        None => req.extensions().get::<RequestBody>().map(|x| x.0.clone()),
+    };
+
+    workerd::sign_error(
+        resp,
+        &req,
+        request_body.as_deref(),
+        host_header,
+        &appstate.signer,
+        &scheme,
+    )
+}
+
+fn is_streaming(req: &HttpRequest) -> bool {
+    req.headers()
+        .get("X-Oyster-Stream")
+        .is_some_and(|x| x == "true")
+}
+
+// the payload is taken once the request body is read or forwarded
+async fn serve(
+    payload: &mut Option<web::Payload>,
+    appstate: web::Data<AppState>,
+    req: HttpRequest,
+    slug: &str,
+) -> HttpResponse {
     // get the host header value
     let host_header = req
         .headers()
@@ -29,247 +134,582 @@ pub async fn serverless(
         .context("could not find Host header")
         .and_then(|x| x.to_str().context("could not parse Host header"));
     if let Err(err) = host_header {
-        return HttpResponse::BadRequest().body(format!("{:?}", err));
+        return error_response(ErrorCode::InvalidHost, err);
     }
     let host_header = host_header.unwrap();
 
     // get tx hash by splitting, will always have at least one element
     let tx_hash = host_header.split('.').next().unwrap().to_owned();
 
-    // handle unregister here
-    if tx_hash == "unregister" {
-        // IMPORTANT: we use Relaxed ordering here since we do not need to synchronize any memory
-        // not even with reads/writes to the same atomic (we just serve a few more requests at worst)
-        // be very careful adding more operations associated with the draining state
-        appstate.running.store(false, Ordering::Relaxed);
+    // counted before checking the draining state so a drained server has really nothing in flight
+    let in_flight = InFlight::new(appstate.clone());
 
-        return HttpResponse::Ok()
-            .status(StatusCode::OK)
-            .body("successfully set server in draining state");
+    // check if the server is draining
+    if !appstate.running.load(Ordering::Relaxed) {
+        return error_response(ErrorCode::Draining, "server is draining");
     }
 
-    let slug = &hex::encode(rand::random::<u32>().to_ne_bytes());
-    let workerd_runtime_path = &appstate.runtime_path;
-
     // decode base32 into hex
     let tx_hash = data_encoding::BASE32_NOPAD.decode(tx_hash.to_uppercase().as_bytes());
     if let Err(err) = tx_hash {
-        return HttpResponse::BadRequest().body(format!("invalid tx hash encoding: {:?}", err));
+        return error_response(
+            ErrorCode::InvalidTxHash,
+            format!("invalid tx hash encoding: {err}"),
+        );
     }
     let tx_hash = tx_hash.unwrap();
     let tx_hash = &("0x".to_owned() + &data_encoding::HEXLOWER.encode(&tx_hash));
+    Span::current().record("tx_hash", tracing::field::display(tx_hash));
 
-    // create code file
-    if let Err(err) = workerd::create_code_file(
-        tx_hash,
-        slug,
-        workerd_runtime_path,
-        &appstate.rpc,
-        &appstate.contract,
-    )
-    .await
+    // nonces are signed and echoed back so clients know the response is fresh
+    if req
+        .headers()
+        .get("X-Oyster-Nonce")
+        .is_some_and(|nonce| nonce.len() > MAX_NONCE_LENGTH)
     {
-        use workerd::ServerlessError::*;
-        return match err {
-            CalldataRetrieve(_)
-            | TxNotFound
-            | InvalidTxToType
-            | InvalidTxToValue(_, _)
-            | InvalidTxCalldataType
-            | BadCalldata(_) => HttpResponse::BadRequest().body(format!(
-                "{:?}",
-                anyhow!(err).context("failed to create code file")
-            )),
-            CodeFileCreate(_) => HttpResponse::InternalServerError().body(format!(
-                "{:?}",
-                anyhow!(err).context("failed to create code file")
-            )),
-            _ => HttpResponse::InternalServerError().body(format!(
-                "{:?}",
-                anyhow!(err).context("unexpected error while trying to create code file")
-            )),
-        };
+        return error_response(
+            ErrorCode::InvalidNonce,
+            format!("nonce is longer than {MAX_NONCE_LENGTH} bytes"),
+        );
     }
 
-    // let execution_timer_start = Instant::now();
+    // clients can ask for a specific signature version, the operator picks the default and the minimum
+    let signature = match req.headers().get("X-Oyster-Signature-Version") {
+        Some(version) => match version.to_str().map(str::parse) {
+            Ok(Ok(version)) => match appstate.signature.with_version(version) {
+                Ok(signature) => signature,
+                Err(err) => {
+                    return error_response(ErrorCode::InvalidSignatureVersion, err.to_string())
+                }
+            },
+            _ => {
+                return error_response(
+                    ErrorCode::InvalidSignatureVersion,
+                    "invalid signature version",
+                )
+            }
+        },
+        None => appstate.signature.clone(),
+    };
 
-    // reserve cgroup
-    let cgroup = appstate.cgroups.lock().unwrap().reserve();
-    if let Err(err) = cgroup {
-        // cleanup
-        workerd::cleanup_code_file(tx_hash, slug, workerd_runtime_path)
-            .await
-            .context("CRITICAL: failed to clean up code file")
-            .unwrap_or_else(|err| println!("{err:?}"));
-
-        return match err {
-            cgroups::CgroupsError::NoFree => {
-                return HttpResponse::TooManyRequests().body(format!(
-                    "{:?}",
-                    anyhow!("no free cgroup available to run request")
-                ))
+    // buffered requests are read up front, streamed requests are forwarded as they arrive
+    let streaming = is_streaming(&req);
+
+    // developers can ask for the console output of their function, if the operator allows it
+    let debug = req
+        .headers()
+        .get("X-Oyster-Debug")
+        .is_some_and(|x| x == "true");
+    let console_limit = match (debug, appstate.config.read().unwrap().console_limit) {
+        (false, _) => None,
+        (true, None) => {
+            return error_response(
+                ErrorCode::DebugNotAllowed,
+                "debug mode is not enabled on this node",
+            )
+        }
+        // the output is only complete once the response is, headers of streamed responses are sent before that
+        (true, Some(_)) if streaming => {
+            return error_response(
+                ErrorCode::DebugNotAllowed,
+                "debug mode is not supported for streamed requests",
+            )
+        }
+        // the output is returned in signed headers, which version 1 does not cover
+        (true, Some(_)) if signature.version == SignatureVersion::V1 => {
+            return error_response(
+                ErrorCode::DebugNotAllowed,
+                "debug mode needs signature version 2 or later",
+            )
+        }
+        (true, Some(limit)) => Some(limit),
+    };
+
+    let (body, payload) = match streaming {
+        true => (web::Bytes::new(), payload.take()),
+        false => match payload.take().unwrap().to_bytes_limited(BODY_LIMIT).await {
+            Ok(Ok(body)) => {
+                req.extensions_mut().insert(RequestBody(body.clone()));
+                (body, None)
             }
-            _ => HttpResponse::InternalServerError().body(format!(
-                "{:?}",
-                anyhow!(err).context("unexpected error while trying to reserve cgroup")
-            )),
-        };
+            Ok(Err(err)) => {
+                return error_response(
+                    ErrorCode::InvalidBody,
+                    format!("failed to read request body: {err}"),
+                )
+            }
+            Err(_) => {
+                return error_response(
+                    ErrorCode::BodyTooLarge,
+                    format!("request body is larger than {BODY_LIMIT} bytes"),
+                )
+            }
+        },
+    };
+
+    // reuse an idle warm worker for this tx hash if there is one
+    let warm_worker = appstate
+        .worker_pool
+        .as_ref()
+        .and_then(|pool| pool.lock().unwrap().take(tx_hash));
+    // a worker that exited while idle is dropped, which retires it, and we fall back to a cold start
+    let warm_worker = warm_worker.and_then(|mut worker| {
+        matches!(worker.resources.child().try_wait(), Ok(None)).then_some(worker)
+    });
+
+    let (worker, cache_status) = match warm_worker {
+        Some(worker) => (worker, None),
+        None => match start_worker(&appstate, tx_hash, slug).await {
+            Ok((worker, cache_status)) => (worker, Some(cache_status)),
+            Err(err) => return err.error_response(),
+        },
+    };
+
+    // worker is ready, make the request
+    let host_header = host_header.to_owned();
+    if let Some(payload) = payload {
+        return serve_streamed(
+            worker,
+            cache_status,
+            req,
+            payload,
+            appstate,
+            &host_header,
+            &signature,
+            in_flight,
+        )
+        .await;
     }
-    let cgroup = cgroup.unwrap();
+    if let Some(limit) = console_limit {
+        worker.console.start(limit);
+    }
+    let usage = meter_usage(&worker, &appstate).await;
+    let execution_start = Instant::now();
+    let response = timeout(
+        WORKER_TIMEOUT,
+        workerd::get_workerd_response(
+            worker.port,
+            req,
+            body,
+            &appstate.signer,
+            &host_header,
+            &signature,
+            usage,
+            console_limit.map(|_| worker.console.as_ref()),
+        )
+        .instrument(info_span!("proxy", port = worker.port)),
+    )
+    .await;
 
-    // get port for cgroup
-    let port = workerd::get_port(&cgroup);
-    if let Err(err) = port {
-        // cleanup
-        appstate.cgroups.lock().unwrap().release(cgroup);
-        workerd::cleanup_code_file(tx_hash, slug, workerd_runtime_path)
-            .await
-            .context("CRITICAL: failed to clean up code file")
-            .unwrap_or_else(|err| println!("{err:?}"));
-
-        return match err {
-            workerd::ServerlessError::BadPort(_) => {
-                return HttpResponse::InternalServerError().body(format!(
-                    "{:?}",
-                    anyhow!(err).context("failed to get port for cgroup")
-                ))
-            }
-            _ => HttpResponse::InternalServerError().body(format!(
-                "{:?}",
-                anyhow!(err).context("unexpected error while trying to get port for cgroup")
-            )),
-        };
+    // responses carry the output already, it is also returned when the worker fails,
+    // that is when it is needed the most
+    let console = match response {
+        Ok(Ok(_)) => None,
+        _ => match console_limit {
+            Some(_) => Some(worker.console.finish().await),
+            None => None,
+        },
+    };
+    release(worker, &appstate, matches!(response, Ok(Ok(_))));
+
+    if response.is_err() {
+        appstate.metrics.timeouts.inc();
+        return with_console(
+            error_response(ErrorCode::WorkerTimeout, "worker timed out"),
+            console,
+        );
     }
-    let port = port.unwrap();
+    let response = response.unwrap();
 
-    // create config file
-    if let Err(err) = workerd::create_config_file(tx_hash, slug, workerd_runtime_path, port).await {
-        // cleanup
-        appstate.cgroups.lock().unwrap().release(cgroup);
-        workerd::cleanup_code_file(tx_hash, slug, workerd_runtime_path)
-            .await
-            .context("CRITICAL: failed to clean up code file")
-            .unwrap_or_else(|err| println!("{err:?}"));
-
-        use workerd::ServerlessError::*;
-        return match err {
-            CalldataRetrieve(_)
-            | TxNotFound
-            | InvalidTxToType
-            | InvalidTxToValue(_, _)
-            | InvalidTxCalldataType
-            | BadCalldata(_) => HttpResponse::BadRequest().body(format!(
-                "{:?}",
-                anyhow!(err).context("failed to create code file")
-            )),
-            CodeFileCreate(_) => HttpResponse::InternalServerError().body(format!(
-                "{:?}",
-                anyhow!(err).context("failed to create code file")
-            )),
-            _ => HttpResponse::InternalServerError().body(format!(
-                "{:?}",
-                anyhow!(err).context("unexpected error while trying to create code file")
-            )),
-        };
+    if let Err(err) = response {
+        error!(error = logging::chain(err), "failed to get a response");
+        return with_console(
+            error_response(ErrorCode::WorkerFailed, "failed to get a response"),
+            console,
+        );
     }
+    let mut response = response.unwrap();
 
-    // start worker
-    let child = workerd::execute(tx_hash, slug, workerd_runtime_path, &cgroup).await;
-    if let Err(err) = child {
-        // cleanup
-        workerd::cleanup_config_file(tx_hash, slug, workerd_runtime_path)
-            .await
-            .context("CRITICAL: failed to clean up config file")
-            .unwrap_or_else(|err| println!("{err:?}"));
-        appstate.cgroups.lock().unwrap().release(cgroup);
-        workerd::cleanup_code_file(tx_hash, slug, workerd_runtime_path)
-            .await
-            .context("CRITICAL: failed to clean up code file")
-            .unwrap_or_else(|err| println!("{err:?}"));
+    appstate
+        .metrics
+        .execution_seconds
+        .observe_duration(execution_start.elapsed());
+    if let BodySize::Sized(size) = response.body().size() {
+        appstate.metrics.response_bytes.observe(size as f64);
+    }
 
-        return HttpResponse::BadRequest().body(format!(
-            "{:?}",
-            anyhow!(err).context("failed to execute worker")
-        ));
+    if let Some(cache_status) = cache_status {
+        response.headers_mut().insert(
+            header::HeaderName::from_static("x-oyster-code-cache"),
+            header::HeaderValue::from_static(cache_status),
+        );
     }
-    let mut child = child.unwrap();
 
-    // wait for worker to be available
-    let res = workerd::wait_for_port(port).await;
+    response
+}
 
-    if !res {
-        // cleanup
-        child
-            .kill()
-            .context("CRITICAL: failed to kill worker {cgroup}")
-            .unwrap_or_else(|err| println!("{err:?}"));
-        workerd::cleanup_config_file(tx_hash, slug, workerd_runtime_path)
-            .await
-            .context("CRITICAL: failed to clean up config file")
-            .unwrap_or_else(|err| println!("{err:?}"));
-        appstate.cgroups.lock().unwrap().release(cgroup);
-        workerd::cleanup_code_file(tx_hash, slug, workerd_runtime_path)
-            .await
-            .context("CRITICAL: failed to clean up code file")
-            .unwrap_or_else(|err| println!("{err:?}"));
+// console output of failed requests, error signatures do not cover headers so it is not signed
+fn with_console(mut response: HttpResponse, console: Option<Captured>) -> HttpResponse {
+    for (name, value) in console.iter().flat_map(Captured::headers) {
+        response.headers_mut().insert(name, value);
+    }
+
+    response
+}
+
+#[derive(Serialize)]
+struct Identity {
+    address: String,
+    public_key: String,
+    secrets_public_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attestation: Option<String>,
+}
 
-        let stderr = child.stderr.take().unwrap();
-        let reader = BufReader::new(stderr);
-        let stderr_lines: Vec<String> = reader.lines().map(|l| l.unwrap()).collect();
-        let stderr_output = stderr_lines.join("\n");
+// keys of this node, along with an attestation document binding the signer and the secrets key
+// to the enclave when an attestation server is configured
+pub async fn identity(appstate: web::Data<AppState>) -> impl Responder {
+    let public_key = appstate.signer.verifying_key().to_encoded_point(false);
+    let secrets_public_key = appstate.secrets_key.public_key().to_sec1_bytes();
 
-        if stderr_output != "" && stderr_output.contains("SyntaxError") {
-            return HttpResponse::BadRequest()
-                .body(format!("syntax error in the code: {stderr_output}"));
+    let attestation = match &appstate.attestation_url {
+        Some(url) => {
+            match fetch_attestation(url, &public_key.as_bytes()[1..], &secrets_public_key).await {
+                Ok(attestation) => Some("0x".to_owned() + &hex::encode(attestation)),
+                Err(err) => {
+                    error!(error = logging::chain(err), "failed to fetch attestation");
+                    return error_response(
+                        ErrorCode::AttestationUnavailable,
+                        "failed to fetch attestation",
+                    );
+                }
+            }
         }
+        None => None,
+    };
 
-        return HttpResponse::InternalServerError()
-            .body(format!("failed to execute worker: {stderr_output}"));
-    }
+    HttpResponse::Ok().json(Identity {
+        address: verify::address(appstate.signer.verifying_key()),
+        public_key: "0x".to_owned() + &hex::encode(public_key.as_bytes()),
+        secrets_public_key: "0x".to_owned() + &hex::encode(secrets_public_key),
+        attestation,
+    })
+}
 
-    // worker is ready, make the request
-    let host_header = host_header.to_owned();
+// ask the attestation server for a document containing the public key, the key is sent as 64 bytes
+// of uncompressed coordinates, and the secrets public key as user data
+async fn fetch_attestation(
+    url: &str,
+    public_key: &[u8],
+    secrets_public_key: &[u8],
+) -> Result<Vec<u8>, anyhow::Error> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()?;
+    let attestation = client
+        .get(url)
+        .query(&[
+            ("public_key", hex::encode(public_key)),
+            ("user_data", hex::encode(secrets_public_key)),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    Ok(attestation.to_vec())
+}
+
+// streamed counterpart of the tail of serverless, the worker is released once the body is done
+#[allow(clippy::too_many_arguments)]
+async fn serve_streamed(
+    worker: Worker,
+    cache_status: Option<&'static str>,
+    req: HttpRequest,
+    payload: web::Payload,
+    appstate: web::Data<AppState>,
+    host_header: &str,
+    signature: &SignatureScheme,
+    in_flight: InFlight,
+) -> HttpResponse {
+    let usage = meter_usage(&worker, &appstate).await;
+    let execution_start = Instant::now();
     let response = timeout(
-        Duration::from_secs(5),
-        workerd::get_workerd_response(port, req, body, &appstate.signer, &host_header),
+        WORKER_TIMEOUT,
+        workerd::get_workerd_stream(
+            worker.port,
+            req,
+            payload,
+            appstate.signer.clone(),
+            host_header,
+            signature,
+            WORKER_TIMEOUT,
+            usage,
+        )
+        .instrument(info_span!("proxy", port = worker.port)),
     )
     .await;
 
-    // cleanup
-    child
-        .kill()
-        .context("CRITICAL: failed to kill worker {cgroup}")
-        .unwrap_or_else(|err| println!("{err:?}"));
-    workerd::cleanup_config_file(tx_hash, slug, workerd_runtime_path)
-        .await
-        .context("CRITICAL: failed to clean up config file")
-        .unwrap_or_else(|err| println!("{err:?}"));
-    appstate.cgroups.lock().unwrap().release(cgroup);
-    workerd::cleanup_code_file(tx_hash, slug, workerd_runtime_path)
-        .await
-        .context("CRITICAL: failed to clean up code file")
-        .unwrap_or_else(|err| println!("{err:?}"));
+    let (mut response, body) = match response {
+        Ok(Ok(response)) => response,
+        Err(_) => {
+            release(worker, &appstate, false);
+            appstate.metrics.timeouts.inc();
+            return error_response(ErrorCode::WorkerTimeout, "worker timed out");
+        }
+        Ok(Err(err)) => {
+            release(worker, &appstate, false);
+            error!(error = logging::chain(err), "failed to get a response");
+            return error_response(ErrorCode::WorkerFailed, "failed to get a response");
+        }
+    };
 
-    if let Err(err) = response {
-        return HttpResponse::RequestTimeout()
-            .body(format!("{:?}", anyhow!(err).context("worker timed out")));
+    appstate
+        .metrics
+        .execution_seconds
+        .observe_duration(execution_start.elapsed());
+
+    if let Some(cache_status) = cache_status {
+        response.insert_header(("X-Oyster-Code-Cache", cache_status));
     }
-    let response = response.unwrap();
 
-    if let Err(err) = response {
-        return HttpResponse::InternalServerError().body(format!(
-            "{:?}",
-            anyhow!(err).context("failed to get a response")
-        ));
+    response.streaming(WorkerStream {
+        body: Box::pin(body),
+        worker: Some(worker),
+        appstate,
+        size: 0,
+        _in_flight: in_flight,
+    })
+}
+
+// usage is only measured if the operator reports it
+async fn meter_usage(worker: &Worker, appstate: &AppState) -> Option<UsageMeter> {
+    match appstate.report_usage {
+        true => Some(UsageMeter::start(Cgroups::path(worker.resources.cgroup())).await),
+        false => None,
     }
-    let response = response.unwrap();
+}
 
-    // let execution_timer_end = Instant::now();
-    // let execution_time = execution_timer_end
-    //     .duration_since(execution_timer_start)
-    //     .as_millis()
-    //     .to_string();
-    // println!("Execution time: {}ms", execution_time);
+// keep the worker around for the next request if it served this one successfully,
+// draining servers retire workers eagerly
+fn release(mut worker: Worker, appstate: &AppState, served: bool) {
+    match &appstate.worker_pool {
+        Some(pool) if served && appstate.running.load(Ordering::Relaxed) => {
+            worker.last_used = Instant::now();
+            pool.lock().unwrap().put(worker);
+        }
+        // dropping the worker retires it
+        _ => drop(worker),
+    }
+}
+
+// streamed response body, holds on to the worker until the body is done with it
+// the worker is only reused if the body was sent out completely
+struct WorkerStream<S> {
+    body: Pin<Box<S>>,
+    worker: Option<Worker>,
+    appstate: web::Data<AppState>,
+    // bytes sent so far, including the signature
+    size: usize,
+    _in_flight: InFlight,
+}
+
+impl<S> WorkerStream<S> {
+    fn finish(&mut self, served: bool) {
+        if let Some(worker) = self.worker.take() {
+            release(worker, &self.appstate, served);
+            self.appstate
+                .metrics
+                .response_bytes
+                .observe(self.size as f64);
+        }
+    }
+}
+
+impl<S: Stream<Item = Result<web::Bytes, anyhow::Error>>> Stream for WorkerStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        let item = self.body.as_mut().poll_next(cx);
+        match &item {
+            Poll::Ready(Some(Ok(chunk))) => self.size += chunk.len(),
+            Poll::Ready(None) => self.finish(true),
+            Poll::Ready(Some(Err(_))) => self.finish(false),
+            _ => {}
+        }
+
+        item
+    }
+}
+
+impl<S> Drop for WorkerStream<S> {
+    fn drop(&mut self) {
+        // client went away before the body was done
+        self.finish(false);
+    }
+}
+
+// cold start a worker for the tx hash
+// everything acquired along the way is tracked by the worker resources, so it is released on failure
+async fn start_worker(
+    appstate: &AppState,
+    tx_hash: &str,
+    slug: &str,
+) -> Result<(Worker, &'static str), ServerlessError> {
+    let workerd_runtime_path = &appstate.runtime_path;
+
+    // get code, from the cache if possible
+    let code_source = &appstate.code_source;
+    let cacheable = code_source.cacheable() && appstate.code_cache.capacity() > 0;
+    let cache_key = code_source.cache_key(tx_hash);
+    let cached_code = match cacheable {
+        true => appstate.code_cache.get(&cache_key).await,
+        false => None,
+    };
+    let (code, cache_status) = match cached_code {
+        Some(code) => (code, "hit"),
+        None => {
+            let fetch_start = Instant::now();
+            let code = code_source
+                .fetch(tx_hash)
+                .instrument(info_span!("fetch"))
+                .await;
+            appstate
+                .metrics
+                .code_fetch_seconds
+                .observe_duration(fetch_start.elapsed());
+            let code = code?;
+
+            match cacheable {
+                true => (code, "miss"),
+                false => (code, "bypass"),
+            }
+        }
+    };
+
+    // get modules, bindings and settings
+    let deployment = workerd::get_deployment(code.clone())?;
+
+    // only code that parses is cached
+    if cache_status == "miss" {
+        // a failure to cache should not fail the request
+        if let Err(err) = appstate.code_cache.insert(&cache_key, &code).await {
+            warn!(error = logging::chain(err), "failed to cache code");
+        }
+    }
+
+    let config = {
+        let node = appstate.config.read().unwrap();
+        deployment.into_config(&appstate.secrets_key, &node.compatibility, &node.egress)?
+    };
+
+    let mut resources = Resources::new(
+        tx_hash,
+        slug,
+        workerd_runtime_path,
+        appstate.cgroups.clone(),
+    );
+
+    // create code file
+    resources.track_code_file();
+    workerd::create_code_file(&config.modules, tx_hash, slug, workerd_runtime_path).await?;
+
+    // reserve cgroup, evicting the least recently used idle worker if none are free
+    let mut cgroup = appstate.cgroups.lock().unwrap().reserve();
+    while let Err(cgroups::CgroupsError::NoFree) = cgroup {
+        let Some(idle) = appstate
+            .worker_pool
+            .as_ref()
+            .and_then(|pool| pool.lock().unwrap().evict())
+        else {
+            break;
+        };
+        // retiring the idle worker frees its cgroup
+        idle.resources.release().await;
+        cgroup = appstate.cgroups.lock().unwrap().reserve();
+    }
+    resources.track_cgroup(cgroup.map_err(|_| ServerlessError::NoFreeCgroup)?);
+
+    // get port for cgroup
+    let port = workerd::get_port(resources.cgroup())?;
+
+    // create config file
+    resources.track_config_file();
+    workerd::create_config_file(&config, tx_hash, slug, workerd_runtime_path, port).await?;
+
+    // start worker
+    let spawn_start = Instant::now();
+    resources.track_child(
+        workerd::execute(tx_hash, slug, workerd_runtime_path, resources.cgroup())
+            .instrument(info_span!("spawn", cgroup = resources.cgroup()))
+            .await?,
+    );
+    // stdout is read right away, stderr is kept for the startup error until the worker is up
+    let console = Arc::<Console>::default();
+    if let Some(stdout) = resources.child().stdout.take() {
+        console::capture(console.clone(), stdout);
+    }
+    appstate
+        .metrics
+        .spawn_seconds
+        .observe_duration(spawn_start.elapsed());
+
+    // wait for worker to be available
+    let wait_start = Instant::now();
+    let res = workerd::wait_for_port(port)
+        .instrument(info_span!("wait", port))
+        .await;
+    appstate
+        .metrics
+        .wait_for_port_seconds
+        .observe_duration(wait_start.elapsed());
+
+    if !res {
+        // kill the worker first so its stderr is complete, it is reaped when the resources are released
+        let cgroup = resources.cgroup().to_owned();
+        if let Err(err) = resources.child().kill() {
+            error!(error = logging::chain(err), cgroup, "failed to kill worker");
+        }
+
+        let stderr = resources.child().stderr.take();
+        let stderr_output = tokio::task::spawn_blocking(move || {
+            let mut output = Vec::new();
+            if let Some(mut stderr) = stderr {
+                // a partial output is still worth returning
+                let _ = stderr.read_to_end(&mut output);
+            }
+            // output is not guaranteed to be valid utf8
+            String::from_utf8_lossy(&output).trim_end().to_owned()
+        })
+        .await
+        .unwrap_or_default();
+
+        if !stderr_output.is_empty() && stderr_output.contains("SyntaxError") {
+            return Err(ServerlessError::SyntaxError(stderr_output));
+        }
+
+        return Err(ServerlessError::WorkerStart(stderr_output));
+    }
+
+    debug!(
+        cgroup = resources.cgroup(),
+        port,
+        cache = cache_status,
+        "worker started"
+    );
+
+    if let Some(stderr) = resources.child().stderr.take() {
+        console::capture(console.clone(), stderr);
+    }
 
-    return response;
+    Ok((
+        Worker {
+            port,
+            last_used: Instant::now(),
+            resources,
+            console,
+        },
+        cache_status,
+    ))
 }
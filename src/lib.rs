@@ -1,5 +1,21 @@
+pub mod admin;
+pub mod bundle;
+pub mod cache;
 pub mod cgroups;
+pub mod config;
+pub mod console;
+pub mod drain;
+pub mod egress;
+pub mod error;
+pub mod gateway;
 pub mod handler;
+pub mod logging;
+pub mod metrics;
 pub mod model;
+pub mod pool;
+pub mod secrets;
+pub mod source;
 mod tests;
+pub mod usage;
+pub mod verify;
 pub mod workerd;
@@ -0,0 +1,44 @@
+// one line per event, either human readable or json, every line carries the fields of the spans it
+// happened in, e.g. the request id of an execution
+//
+// text: <timestamp> <level> <span>{<field>=<value> ...}:<span>{...}: <target>: <message> <field>=<value> ...
+// json: {"timestamp":..,"level":..,"fields":{"message":..,..},"target":..,"spans":[{"name":..,<field>:<value>,..}]}
+
+use tracing::{Level, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+pub fn subscriber<W>(
+    format: LogFormat,
+    level: Level,
+    writer: W,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_ansi(false)
+        .with_writer(writer);
+
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(
+            builder
+                .json()
+                .with_current_span(false)
+                .with_span_list(true)
+                .finish(),
+        ),
+    }
+}
+
+// error along with its causes on a single line, for the error field of log events
+pub fn chain(err: impl Into<anyhow::Error>) -> String {
+    format!("{:#}", err.into())
+}
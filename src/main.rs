@@ -1,24 +1,49 @@
 use actix_web::{web, App, HttpServer};
 use anyhow::{anyhow, Context};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
+use tracing::{error, info};
 
+use serverless::admin::{self, AdminAuth};
+use serverless::cache::CodeCache;
 use serverless::cgroups::Cgroups;
+use serverless::config::{self, ConfigSource, NodeConfig};
+use serverless::egress::{EgressMode, EgressPolicy, EgressRule};
+use serverless::gateway::{self, GatewayClient};
+use serverless::logging::{self, LogFormat};
+use serverless::metrics::{self, Metrics};
 use serverless::model::AppState;
+use serverless::pool::{self, WorkerPool};
+use serverless::secrets::derive_key;
+use serverless::source::{self, CodeSource, DirSource, HttpSource, TxSource};
+use serverless::verify::{self, Eip712Domain, SignatureScheme, SignatureVersion};
+use serverless::workerd::CompatibilityPolicy;
 
-/// Simple program to greet a person
+/// Run JS and WASM functions deployed on chain in workerd and sign their responses
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// port serving executions
     #[clap(long, value_parser, default_value = "6001")]
     port: u16,
 
+    /// directory holding the workerd binary, the code and config files of workers
     #[clap(long, value_parser, default_value = "./runtime/")]
     runtime_path: String,
 
-    #[clap(long, value_parser, default_value = "www.marlin.org")]
-    gateway: String,
+    /// gateway to register with, e.g. http://gateway.example.com, the node does not register when unset
+    #[clap(long, value_parser)]
+    gateway: Option<String>,
+
+    /// seconds between heartbeats sent to the gateway
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value = "10")]
+    heartbeat_interval: u64,
 
+    /// rpc the deployment transactions are fetched from
     #[clap(
         long,
         value_parser,
@@ -26,6 +51,7 @@ struct Args {
     )]
     rpc: String,
 
+    /// deploy contract, deployment transactions must be sent to it
     #[clap(
         long,
         value_parser,
@@ -33,25 +59,203 @@ struct Args {
     )]
     contract: String,
 
+    /// chain of the deploy contract, eip712 signatures are bound to it and the contract
+    #[clap(long, value_parser, default_value = "421614")]
+    chain_id: u64,
+
+    /// hex encoded function selector accepted for deployment calls, can be repeated,
+    /// defaults to the selector of the deploy contract function
+    #[clap(long, value_parser = parse_selector)]
+    code_selector: Vec<[u8; 4]>,
+
+    /// accept deployment calls to any function of the contract
+    #[clap(long, value_parser, conflicts_with = "code_selector")]
+    any_code_selector: bool,
+
+    /// file containing the raw 32 byte secret key responses are signed with
     #[clap(long, value_parser)]
     signer: String,
+
+    /// attestation server asked for a document containing the signer key, e.g. http://127.0.0.1:1300/attestation/raw,
+    /// the identity endpoint only returns the keys when unset
+    #[clap(long, value_parser)]
+    attestation_url: Option<String>,
+
+    /// where function code is loaded from
+    #[clap(long, value_enum, default_value = "tx")]
+    code_source: CodeSourceKind,
+
+    /// directory to read code from when using the dir code source
+    #[clap(long, value_parser, required_if_eq("code_source", "dir"))]
+    code_dir: Option<String>,
+
+    /// base url of the content addressed store when using the http code source
+    #[clap(long, value_parser, required_if_eq("code_source", "http"))]
+    code_url: Option<String>,
+
+    /// directory of the code cache, defaults to <runtime_path>/code-cache
+    #[clap(long, value_parser)]
+    code_cache_path: Option<String>,
+
+    /// max total size of cached code in bytes, 0 disables the cache
+    #[clap(long, value_parser, default_value = "67108864")]
+    code_cache_size: u64,
+
+    /// json file overriding the egress, compatibility, console and code cache settings,
+    /// read again on SIGHUP and POST /admin/reload-config, see src/config.rs
+    #[clap(long, value_parser)]
+    config: Option<PathBuf>,
+
+    /// keep workers alive for this many seconds after a request to serve the same tx hash,
+    /// workers are killed right after every request when unset,
+    /// warm workers are shared by all callers of a function, module-global js state leaks between them
+    #[clap(long, value_parser)]
+    warm_ttl: Option<u64>,
+
+    /// compatibility date of deployments that do not declare one
+    #[clap(long, value_parser, default_value = "2023-03-07")]
+    default_compatibility_date: String,
+
+    /// latest compatibility date deployments are allowed to declare,
+    /// should not be later than the release date of the workerd binary
+    #[clap(long, value_parser, default_value = "2023-03-07")]
+    max_compatibility_date: String,
+
+    /// compatibility flag deployments are allowed to declare, can be repeated
+    #[clap(long, value_parser)]
+    compatibility_flag: Vec<String>,
+
+    /// default signature version, 1 does not cover the status and headers,
+    /// eip712 signs the same fields as EIP-712 typed data
+    #[clap(long, value_parser = SignatureVersion::from_str, default_value = "2")]
+    signature_version: SignatureVersion,
+
+    /// lowest signature version clients can ask for, eip712 > 2 > 1
+    #[clap(long, value_parser = SignatureVersion::from_str, default_value = "1")]
+    min_signature_version: SignatureVersion,
+
+    /// request header covered by response signatures, can be repeated
+    #[clap(long, value_parser, default_value = "content-type")]
+    signed_request_header: Vec<String>,
+
+    /// response header covered by response signatures, can be repeated
+    #[clap(long, value_parser, default_value = "content-type")]
+    signed_response_header: Vec<String>,
+
+    /// port of the admin listener, only used with --operator
+    #[clap(long, value_parser, default_value = "6002")]
+    admin_port: u16,
+
+    /// address allowed to sign admin requests, the admin listener is only started when set
+    #[clap(long, value_parser = parse_address)]
+    operator: Option<String>,
+
+    /// port serving prometheus metrics at /metrics, metrics are not served when unset
+    #[clap(long, value_parser)]
+    metrics_port: Option<u16>,
+
+    /// stop the server once it has been drained and in flight executions are done
+    #[clap(long, value_parser)]
+    shutdown_when_drained: bool,
+
+    /// format of the logs
+    #[clap(long, value_enum, default_value = "text")]
+    log_format: LogFormatKind,
+
+    /// most verbose level logged, one of error, warn, info, debug or trace
+    #[clap(long, value_parser, default_value = "info")]
+    log_level: tracing::Level,
+
+    /// max bytes of worker console output returned to requests with X-Oyster-Debug: true,
+    /// debug requests are refused when unset
+    #[clap(long, value_parser)]
+    console_limit: Option<usize>,
+
+    /// return the cpu time, peak memory and wall time of workers with their responses,
+    /// needs --min-signature-version 2 or later since version 1 does not sign headers
+    #[clap(long, value_parser)]
+    report_usage: bool,
+
+    /// outbound network access of workers
+    #[clap(long, value_enum, default_value = "public")]
+    egress: EgressKind,
+
+    /// ip, cidr or <binding>=<url> workers are allowed to reach, can be repeated
+    #[clap(long, value_parser = parse_egress_rule)]
+    egress_allow: Vec<EgressRule>,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum CodeSourceKind {
+    /// calldata of a transaction to --contract, fetched from --rpc
+    Tx,
+    /// files named by id in --code-dir
+    Dir,
+    /// content addressed store at --code-url
+    Http,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum LogFormatKind {
+    /// one human readable line per event
+    Text,
+    /// one json object per line, for log collectors
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum EgressKind {
+    /// any public address
+    Public,
+    /// no outbound access
+    Deny,
+    /// only --egress-allow
+    Allowlist,
+    /// rules declared by the deployment, limited to --egress-allow if set
+    PerFunction,
+}
+
+fn parse_egress_rule(rule: &str) -> Result<EgressRule, String> {
+    rule.parse().map_err(|err: &str| err.to_owned())
+}
+
+fn parse_address(address: &str) -> Result<String, String> {
+    match address.strip_prefix("0x") {
+        Some(hex) if hex.len() == 40 && hex.bytes().all(|x| x.is_ascii_hexdigit()) => {
+            Ok(address.to_ascii_lowercase())
+        }
+        _ => Err("address must be 0x followed by 40 hex characters".to_owned()),
+    }
+}
+
+fn parse_selector(selector: &str) -> Result<[u8; 4], String> {
+    let selector = hex::decode(selector.strip_prefix("0x").unwrap_or(selector))
+        .map_err(|err| err.to_string())?;
+    selector
+        .try_into()
+        .map_err(|_| "selector must be 4 bytes".to_owned())
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Args::parse();
 
-    // let gateway_url = cli.gateway;
-    // let client = awc::Client::default();
-    // let response = client
-    //     .get("http://".to_owned() + &gateway_url + "/register")
-    //     .insert_header(("worker-id", "test"))
-    //     .send()
-    //     .await;
-
-    // println!("{:?}", response);
+    let log_format = match cli.log_format {
+        LogFormatKind::Text => LogFormat::Text,
+        LogFormatKind::Json => LogFormat::Json,
+    };
+    tracing::subscriber::set_global_default(logging::subscriber(
+        log_format,
+        cli.log_level,
+        std::io::stdout,
+    ))
+    .context("failed to set up logging")?;
 
     let port: u16 = cli.port;
+    let shutdown_when_drained = cli.shutdown_when_drained;
+    let admin_port = cli.admin_port;
+    let operator = cli.operator;
+    let metrics_port = cli.metrics_port;
 
     let cgroups = Cgroups::new().context("failed to construct cgroups")?;
     if cgroups.free.is_empty() {
@@ -66,27 +270,230 @@ async fn main() -> anyhow::Result<()> {
     )
     .context("invalid signer key")?;
 
+    if cli.report_usage && cli.min_signature_version < SignatureVersion::V2 {
+        return Err(anyhow!(
+            "--report-usage needs --min-signature-version 2 or later, version 1 does not sign the usage"
+        ));
+    }
+
+    let domain = Eip712Domain::new(cli.chain_id, &cli.contract).context("invalid contract")?;
+    let signature = SignatureScheme::new(
+        cli.signature_version,
+        cli.min_signature_version,
+        domain,
+        cli.signed_request_header,
+        cli.signed_response_header,
+    )
+    .context("invalid signature scheme")?;
+
+    let secrets_key = derive_key(&signer).context("failed to derive secrets key")?;
+    info!(
+        "Secrets public key: 0x{}",
+        hex::encode(secrets_key.public_key().to_sec1_bytes())
+    );
+
+    // the command line values can be overridden by the config file, which can be reloaded later on
+    let config_source = ConfigSource {
+        path: cli.config,
+        command_line: NodeConfig {
+            compatibility: CompatibilityPolicy {
+                default_date: cli.default_compatibility_date,
+                max_date: cli.max_compatibility_date,
+                allowed_flags: cli.compatibility_flag,
+            },
+            egress: EgressPolicy {
+                mode: match cli.egress {
+                    EgressKind::Public => EgressMode::Public,
+                    EgressKind::Deny => EgressMode::Deny,
+                    EgressKind::Allowlist => EgressMode::Allowlist,
+                    EgressKind::PerFunction => EgressMode::PerFunction,
+                },
+                rules: cli.egress_allow,
+            },
+            console_limit: cli.console_limit,
+            code_cache_size: cli.code_cache_size,
+        },
+    };
+    let config = config_source
+        .load()
+        .await
+        .context("failed to load config")?;
+
+    let code_cache = CodeCache::new(
+        cli.code_cache_path
+            .unwrap_or(cli.runtime_path.clone() + "/code-cache"),
+        config.code_cache_size,
+    )
+    .context("failed to construct code cache")?;
+
+    let code_source: Box<dyn CodeSource> = match cli.code_source {
+        CodeSourceKind::Tx => {
+            let selectors = match (cli.any_code_selector, cli.code_selector.is_empty()) {
+                (true, _) => None,
+                (false, true) => Some(vec![source::selector(source::DEPLOY_FUNCTION)]),
+                (false, false) => Some(cli.code_selector),
+            };
+            Box::new(TxSource::new(cli.rpc, cli.contract, selectors))
+        }
+        CodeSourceKind::Dir => Box::new(DirSource::new(cli.code_dir.unwrap())),
+        CodeSourceKind::Http => Box::new(HttpSource::new(cli.code_url.unwrap())),
+    };
+
+    let metrics = Metrics::new(cgroups.free.len());
     let app_data = web::Data::new(AppState {
-        cgroups: cgroups.into(),
+        cgroups: Arc::new(cgroups.into()),
         running: std::sync::atomic::AtomicBool::new(true),
+        in_flight: std::sync::atomic::AtomicUsize::new(0),
         runtime_path: cli.runtime_path,
-        rpc: cli.rpc,
-        contract: cli.contract,
+        code_source,
+        config: config.into(),
+        config_source,
+        report_usage: cli.report_usage,
         signer,
+        signature,
+        secrets_key,
+        attestation_url: cli.attestation_url,
+        code_cache,
+        worker_pool: cli
+            .warm_ttl
+            .map(|ttl| WorkerPool::new(Duration::from_secs(ttl)).into()),
+        metrics,
+    });
+
+    // retire warm workers once they have been idle for too long
+    tokio::spawn(pool::reap(app_data.clone()));
+
+    // nodes can be drained without the admin listener too
+    let signal_state = app_data.clone();
+    tokio::spawn(async move {
+        if let Err(err) = serverless::drain::drain_on_signal(signal_state).await {
+            error!(
+                error = logging::chain(err),
+                "failed to listen for the drain signal"
+            );
+        }
+    });
+    // same for reloading the config
+    let signal_state = app_data.clone();
+    tokio::spawn(async move {
+        if let Err(err) = config::reload_on_signal(signal_state).await {
+            error!(
+                error = logging::chain(err),
+                "failed to listen for the reload signal"
+            );
+        }
     });
 
+    let gateway_state = app_data.clone();
+    let drain_state = app_data.clone();
+    let shutdown_state = app_data.clone();
+    let admin_state = app_data.clone();
+    let metrics_state = app_data.clone();
     let server = HttpServer::new(move || {
         App::new()
             .app_data(app_data.clone())
+            .route(
+                "/oyster/identity",
+                web::get().to(serverless::handler::identity),
+            )
             .default_service(web::to(serverless::handler::serverless))
     })
     .bind(("0.0.0.0", port))
     .context(format!("could not bind to port {port}"))?
     .run();
 
-    println!("Server started on port {}", port);
+    info!("Server started on port {}", port);
+
+    // only registered once the port is bound, so the gateway never routes to a node that is not listening
+    if let Some(gateway) = cli.gateway {
+        tokio::spawn(gateway::run(
+            GatewayClient::new(&gateway, gateway_state.signer.clone()),
+            gateway_state.clone(),
+            gateway::registration(&gateway_state, port),
+            Duration::from_secs(cli.heartbeat_interval),
+        ));
+    }
+
+    // control plane, only started when there is an operator to sign requests
+    let admin_server = match operator {
+        Some(operator) => {
+            let node = verify::address(admin_state.signer.verifying_key());
+            let auth = web::Data::new(AdminAuth::new(&operator, &node));
+            let admin_server = HttpServer::new(move || {
+                App::new()
+                    .app_data(admin_state.clone())
+                    .app_data(auth.clone())
+                    .route("/admin/status", web::get().to(admin::status))
+                    .route("/admin/drain", web::post().to(admin::drain))
+                    .route("/admin/undrain", web::post().to(admin::undrain))
+                    .route("/admin/retire-idle", web::post().to(admin::retire_idle))
+                    .route("/admin/reload-config", web::post().to(admin::reload_config))
+                    .route("/admin/workers", web::get().to(admin::workers))
+            })
+            .bind(("0.0.0.0", admin_port))
+            .context(format!("could not bind to admin port {admin_port}"))?
+            .run();
+
+            info!("Admin server started on port {admin_port} for operator {operator}");
+
+            Some(admin_server)
+        }
+        None => None,
+    };
+
+    // not behind the admin signatures so prometheus can scrape it, keep the port private
+    let metrics_server = match metrics_port {
+        Some(metrics_port) => {
+            let metrics_server = HttpServer::new(move || {
+                App::new()
+                    .app_data(metrics_state.clone())
+                    .route("/metrics", web::get().to(metrics::metrics))
+            })
+            .bind(("0.0.0.0", metrics_port))
+            .context(format!("could not bind to metrics port {metrics_port}"))?
+            .run();
+
+            info!("Metrics server started on port {metrics_port}");
+
+            Some(metrics_server)
+        }
+        None => None,
+    };
+
+    if shutdown_when_drained {
+        let handles = [
+            Some(server.handle()),
+            admin_server.as_ref().map(|x| x.handle()),
+            metrics_server.as_ref().map(|x| x.handle()),
+        ];
+        tokio::spawn(async move {
+            serverless::drain::wait_drained(drain_state).await;
+            info!("Drained, shutting down");
+            for handle in handles.into_iter().flatten() {
+                handle.stop(true).await;
+            }
+        });
+    }
+
+    // servers that are not started finish right away
+    tokio::try_join!(
+        server,
+        async {
+            match admin_server {
+                Some(admin_server) => admin_server.await,
+                None => Ok(()),
+            }
+        },
+        async {
+            match metrics_server {
+                Some(metrics_server) => metrics_server.await,
+                None => Ok(()),
+            }
+        },
+    )?;
 
-    server.await?;
+    // do not leave workerd processes and their cgroups behind
+    pool::retire_idle(&shutdown_state).await;
 
     Ok(())
 }
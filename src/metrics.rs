@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::{web, HttpResponse, Responder};
+
+use crate::model::AppState;
+
+const SECONDS_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const BYTES_BUCKETS: &[f64] = &[
+    256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0,
+];
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// counters keyed by the value of a single label
+#[derive(Default)]
+pub struct LabeledCounter(Mutex<BTreeMap<String, u64>>);
+
+impl LabeledCounter {
+    pub fn inc(&self, label: &str) {
+        *self.0.lock().unwrap().entry(label.to_owned()).or_default() += 1;
+    }
+
+    pub fn get(&self, label: &str) -> u64 {
+        self.0
+            .lock()
+            .unwrap()
+            .get(label)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+struct HistogramState {
+    // not cumulative, bucket i counts observations in (buckets[i - 1], buckets[i]]
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+pub struct Histogram {
+    buckets: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram {
+            buckets,
+            state: Mutex::new(HistogramState {
+                counts: vec![0; buckets.len()],
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(idx) = self.buckets.iter().position(|bucket| value <= *bucket) {
+            state.counts[idx] += 1;
+        }
+        state.sum += value;
+        state.count += 1;
+    }
+
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+
+    pub fn count(&self) -> u64 {
+        self.state.lock().unwrap().count
+    }
+}
+
+pub struct Metrics {
+    // executions by outcome, ok or the error code
+    pub requests: LabeledCounter,
+    // workers that did not respond in time
+    pub timeouts: Counter,
+    pub code_fetch_seconds: Histogram,
+    pub spawn_seconds: Histogram,
+    pub wait_for_port_seconds: Histogram,
+    // until the response headers of the worker are in
+    pub execution_seconds: Histogram,
+    pub response_bytes: Histogram,
+    // cgroups available when the server started
+    cgroups: usize,
+}
+
+impl Metrics {
+    pub fn new(cgroups: usize) -> Metrics {
+        Metrics {
+            requests: LabeledCounter::default(),
+            timeouts: Counter::default(),
+            code_fetch_seconds: Histogram::new(SECONDS_BUCKETS),
+            spawn_seconds: Histogram::new(SECONDS_BUCKETS),
+            wait_for_port_seconds: Histogram::new(SECONDS_BUCKETS),
+            execution_seconds: Histogram::new(SECONDS_BUCKETS),
+            response_bytes: Histogram::new(BYTES_BUCKETS),
+            cgroups,
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP oyster_serverless_{name} {help}").unwrap();
+    writeln!(out, "# TYPE oyster_serverless_{name} {kind}").unwrap();
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    header(out, name, "histogram", help);
+
+    let state = histogram.state.lock().unwrap();
+    let mut cumulative = 0;
+    for (bucket, count) in histogram.buckets.iter().zip(&state.counts) {
+        cumulative += count;
+        writeln!(
+            out,
+            "oyster_serverless_{name}_bucket{{le=\"{bucket}\"}} {cumulative}"
+        )
+        .unwrap();
+    }
+    writeln!(
+        out,
+        "oyster_serverless_{name}_bucket{{le=\"+Inf\"}} {}",
+        state.count
+    )
+    .unwrap();
+    writeln!(out, "oyster_serverless_{name}_sum {}", state.sum).unwrap();
+    writeln!(out, "oyster_serverless_{name}_count {}", state.count).unwrap();
+}
+
+// prometheus text exposition format
+pub fn render(appstate: &AppState) -> String {
+    let metrics = &appstate.metrics;
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "requests_total",
+        "counter",
+        "Executions by outcome, ok or the error code",
+    );
+    for (outcome, count) in metrics.requests.0.lock().unwrap().iter() {
+        writeln!(
+            out,
+            "oyster_serverless_requests_total{{outcome=\"{outcome}\"}} {count}"
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "timeouts_total",
+        "counter",
+        "Workers that did not respond in time",
+    );
+    writeln!(
+        out,
+        "oyster_serverless_timeouts_total {}",
+        metrics.timeouts.get()
+    )
+    .unwrap();
+
+    histogram(
+        &mut out,
+        "code_fetch_seconds",
+        "Time taken to fetch code on a cache miss",
+        &metrics.code_fetch_seconds,
+    );
+    histogram(
+        &mut out,
+        "spawn_seconds",
+        "Time taken to spawn workerd",
+        &metrics.spawn_seconds,
+    );
+    histogram(
+        &mut out,
+        "wait_for_port_seconds",
+        "Time taken by workerd to start listening",
+        &metrics.wait_for_port_seconds,
+    );
+    histogram(
+        &mut out,
+        "execution_seconds",
+        "Time taken by the worker to respond",
+        &metrics.execution_seconds,
+    );
+    histogram(
+        &mut out,
+        "response_bytes",
+        "Size of the worker response body",
+        &metrics.response_bytes,
+    );
+
+    let free = appstate.cgroups.lock().unwrap().free.len();
+    header(
+        &mut out,
+        "cgroups_free",
+        "gauge",
+        "Cgroups free to run workers",
+    );
+    writeln!(out, "oyster_serverless_cgroups_free {free}").unwrap();
+    header(
+        &mut out,
+        "cgroups_reserved",
+        "gauge",
+        "Cgroups held by running workers, including idle warm ones",
+    );
+    writeln!(
+        out,
+        "oyster_serverless_cgroups_reserved {}",
+        metrics.cgroups.saturating_sub(free)
+    )
+    .unwrap();
+
+    out
+}
+
+pub async fn metrics(appstate: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render(&appstate))
+}
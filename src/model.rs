@@ -1,14 +1,37 @@
+use crate::cache::CodeCache;
 use crate::cgroups::Cgroups;
-use std::sync::{atomic::AtomicBool, Mutex};
+use crate::config::{ConfigSource, NodeConfig};
+use crate::metrics::Metrics;
+use crate::pool::WorkerPool;
+use crate::source::CodeSource;
+use crate::verify::SignatureScheme;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::{Arc, Mutex, RwLock};
 
 pub struct AppState {
-    pub cgroups: Mutex<Cgroups>,
+    // shared with the resources of running workers, which release their cgroup when dropped
+    pub cgroups: Arc<Mutex<Cgroups>>,
     // IMPORTANT: we use Relaxed ordering here since we do not need to synchronize any memory
     // not even with reads/writes to the same atomic (we just serve a few more requests at worst)
     // be very careful adding more operations associated with the draining state
     pub running: AtomicBool,
+    // executions that have not finished yet, streamed ones count until their body is done
+    pub in_flight: AtomicUsize,
     pub runtime_path: String,
-    pub rpc: String,
-    pub contract: String,
+    pub code_source: Box<dyn CodeSource>,
+    // settings that can be reloaded, requests read them once when they start
+    pub config: RwLock<NodeConfig>,
+    pub config_source: ConfigSource,
+    // usage of workers is returned in signed headers, needs a minimum signature version of 2
+    pub report_usage: bool,
     pub signer: k256::ecdsa::SigningKey,
+    pub signature: SignatureScheme,
+    // derived from the signer, used to decrypt secret bindings
+    pub secrets_key: k256::SecretKey,
+    // local attestation server relayed by the identity endpoint
+    pub attestation_url: Option<String>,
+    pub code_cache: CodeCache,
+    // only set when warm workers are enabled
+    pub worker_pool: Option<Mutex<WorkerPool>>,
+    pub metrics: Metrics,
 }
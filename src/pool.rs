@@ -0,0 +1,275 @@
+use std::process::Child;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use tracing::{error, info_span};
+
+use crate::cgroups::Cgroups;
+use crate::console::Console;
+use crate::logging;
+use crate::model::AppState;
+use crate::workerd;
+
+// everything held on behalf of a worker, released exactly once when dropped
+// cleanup is started on drop so it also happens on panics and when a request is dropped midway,
+// it blocks on the process and the file system so it runs on a blocking thread when there is a runtime
+pub struct Resources {
+    pub tx_hash: String,
+    pub slug: String,
+    runtime_path: String,
+    cgroups: Arc<Mutex<Cgroups>>,
+    code_file: bool,
+    config_file: bool,
+    cgroup: Option<String>,
+    child: Option<Child>,
+}
+
+impl Resources {
+    pub fn new(
+        tx_hash: &str,
+        slug: &str,
+        runtime_path: &str,
+        cgroups: Arc<Mutex<Cgroups>>,
+    ) -> Resources {
+        Resources {
+            tx_hash: tx_hash.to_owned(),
+            slug: slug.to_owned(),
+            runtime_path: runtime_path.to_owned(),
+            cgroups,
+            code_file: false,
+            config_file: false,
+            cgroup: None,
+            child: None,
+        }
+    }
+
+    // track before creating, whatever was written is cleaned up even if creation fails midway
+    pub fn track_code_file(&mut self) {
+        self.code_file = true;
+    }
+
+    pub fn track_config_file(&mut self) {
+        self.config_file = true;
+    }
+
+    pub fn track_cgroup(&mut self, cgroup: String) {
+        self.cgroup = Some(cgroup);
+    }
+
+    pub fn track_child(&mut self, child: Child) {
+        self.child = Some(child);
+    }
+
+    // panics if no cgroup is tracked
+    pub fn cgroup(&self) -> &str {
+        self.cgroup.as_ref().unwrap()
+    }
+
+    // panics if no child is tracked
+    pub fn child(&mut self) -> &mut Child {
+        self.child.as_mut().unwrap()
+    }
+
+    // release everything and wait until it is done, e.g. to reuse the cgroup right away
+    pub async fn release(mut self) {
+        let cleanup = self.take_cleanup();
+        if let Err(err) = tokio::task::spawn_blocking(move || cleanup.run()).await {
+            error!(error = logging::chain(err), "failed to clean up worker");
+        }
+    }
+
+    // leaves nothing to clean up behind
+    fn take_cleanup(&mut self) -> Cleanup {
+        Cleanup {
+            tx_hash: self.tx_hash.clone(),
+            slug: self.slug.clone(),
+            runtime_path: self.runtime_path.clone(),
+            cgroups: self.cgroups.clone(),
+            code_file: std::mem::take(&mut self.code_file),
+            config_file: std::mem::take(&mut self.config_file),
+            cgroup: self.cgroup.take(),
+            child: self.child.take(),
+        }
+    }
+}
+
+impl Drop for Resources {
+    fn drop(&mut self) {
+        let cleanup = self.take_cleanup();
+        if cleanup.is_empty() {
+            return;
+        }
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(move || cleanup.run());
+            }
+            Err(_) => cleanup.run(),
+        }
+    }
+}
+
+// what is left to release of the resources, moved out so it can be released on another thread
+struct Cleanup {
+    tx_hash: String,
+    slug: String,
+    runtime_path: String,
+    cgroups: Arc<Mutex<Cgroups>>,
+    code_file: bool,
+    config_file: bool,
+    cgroup: Option<String>,
+    child: Option<Child>,
+}
+
+impl Cleanup {
+    fn is_empty(&self) -> bool {
+        !self.code_file && !self.config_file && self.cgroup.is_none() && self.child.is_none()
+    }
+
+    fn run(mut self) {
+        let (tx_hash, slug) = (&self.tx_hash, &self.slug);
+        // warm workers outlive the request that started them, the slug is its request id
+        let _span = info_span!("cleanup", worker = %slug).entered();
+
+        if let Some(mut child) = self.child.take() {
+            if let Err(err) = workerd::terminate(&mut child) {
+                error!(
+                    error = logging::chain(err),
+                    cgroup = self.cgroup.as_deref().unwrap_or_default(),
+                    "failed to kill worker"
+                );
+            }
+        }
+        if self.config_file {
+            if let Err(err) = workerd::cleanup_config_file(tx_hash, slug, &self.runtime_path) {
+                error!(
+                    error = logging::chain(err),
+                    "failed to clean up config file"
+                );
+            }
+        }
+        if let Some(cgroup) = self.cgroup.take() {
+            // the lock can be poisoned if we are dropped during a panic, the cgroups are still valid
+            self.cgroups
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .release(cgroup);
+        }
+        if self.code_file {
+            if let Err(err) = workerd::cleanup_code_file(tx_hash, slug, &self.runtime_path) {
+                error!(error = logging::chain(err), "failed to clean up code file");
+            }
+        }
+    }
+}
+
+// a running workerd process, dropping it kills the process and releases everything it holds
+pub struct Worker {
+    pub port: u16,
+    pub last_used: Instant,
+    pub resources: Resources,
+    // fed by the stdout and stderr of the process
+    pub console: Arc<Console>,
+}
+
+// idle workers kept alive between requests for the same tx hash
+// workers are taken out of the pool while serving a request, so each one serves a single request at a time
+// IMPORTANT: reuse is keyed by tx hash only, module-global js state of a worker is seen by every caller of the function
+pub struct WorkerPool {
+    pub ttl: Duration,
+    idle: Vec<Worker>,
+}
+
+impl WorkerPool {
+    pub fn new(ttl: Duration) -> WorkerPool {
+        WorkerPool {
+            ttl,
+            idle: Vec::new(),
+        }
+    }
+
+    // take the most recently used idle worker for the tx hash
+    pub fn take(&mut self, tx_hash: &str) -> Option<Worker> {
+        let idx = self
+            .idle
+            .iter()
+            .enumerate()
+            .filter(|(_, worker)| worker.resources.tx_hash == tx_hash)
+            .max_by_key(|(_, worker)| worker.last_used)
+            .map(|(idx, _)| idx)?;
+
+        Some(self.idle.swap_remove(idx))
+    }
+
+    pub fn put(&mut self, mut worker: Worker) {
+        worker.last_used = Instant::now();
+        self.idle.push(worker);
+    }
+
+    // take the least recently used idle worker, used to free up a cgroup
+    pub fn evict(&mut self) -> Option<Worker> {
+        let idx = self
+            .idle
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, worker)| worker.last_used)
+            .map(|(idx, _)| idx)?;
+
+        Some(self.idle.swap_remove(idx))
+    }
+
+    // take all workers that have been idle for longer than the ttl
+    pub fn expire(&mut self) -> Vec<Worker> {
+        let (expired, idle) = self
+            .idle
+            .drain(..)
+            .partition(|worker| worker.last_used.elapsed() >= self.ttl);
+        self.idle = idle;
+
+        expired
+    }
+
+    // take all idle workers
+    pub fn clear(&mut self) -> Vec<Worker> {
+        self.idle.drain(..).collect()
+    }
+
+    pub fn idle(&self) -> &[Worker] {
+        &self.idle
+    }
+
+    pub fn len(&self) -> usize {
+        self.idle.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.idle.is_empty()
+    }
+}
+
+// retire all idle workers and wait until their cgroups are free and their processes are gone
+pub async fn retire_idle(appstate: &AppState) -> usize {
+    let retired = match &appstate.worker_pool {
+        Some(pool) => pool.lock().unwrap().clear(),
+        None => Vec::new(),
+    };
+    let count = retired.len();
+    futures::future::join_all(retired.into_iter().map(|worker| worker.resources.release())).await;
+
+    count
+}
+
+// periodically retire workers that have been idle for longer than the ttl
+pub async fn reap(appstate: actix_web::web::Data<AppState>) {
+    let Some(pool) = &appstate.worker_pool else {
+        return;
+    };
+
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+
+        // dropping the workers retires them
+        let expired = pool.lock().unwrap().expire();
+        drop(expired);
+    }
+}
@@ -0,0 +1,115 @@
+// secrets are encrypted to a key derived from the signer so they can only be decrypted inside the enclave
+//
+// ciphertext layout
+//   ephemeral public key  33 bytes  compressed secp256k1 point
+//   nonce                 12 bytes
+//   aes-256-gcm ciphertext with the tag appended
+//
+// the associated data is the code hash of the deployment followed by the binding name,
+// so a secret can not be copied into another deployment, see Deployment::code_hash
+
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use hkdf::Hkdf;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use k256::{PublicKey, SecretKey};
+use sha2::Sha256;
+use thiserror::Error;
+
+const KEY_INFO: &[u8] = b"oyster-serverless-secrets-key";
+const CIPHER_INFO: &[u8] = b"oyster-serverless-secrets-cipher";
+
+#[derive(Error, Debug)]
+pub enum SecretsError {
+    #[error("failed to derive secrets key")]
+    Derive,
+    #[error("secret is too short")]
+    TooShort,
+    #[error("invalid ephemeral public key")]
+    BadPublicKey,
+    #[error("failed to decrypt secret")]
+    Decrypt,
+    #[error("secret is not valid utf8")]
+    NotText,
+}
+
+pub fn derive_key(signer: &k256::ecdsa::SigningKey) -> Result<SecretKey, SecretsError> {
+    let hkdf = Hkdf::<Sha256>::new(None, &signer.to_bytes());
+    let mut key = [0u8; 32];
+    hkdf.expand(KEY_INFO, &mut key)
+        .map_err(|_| SecretsError::Derive)?;
+
+    SecretKey::from_slice(&key).map_err(|_| SecretsError::Derive)
+}
+
+pub fn decrypt(
+    key: &SecretKey,
+    code_hash: &[u8; 32],
+    name: &str,
+    secret: &[u8],
+) -> Result<String, SecretsError> {
+    if secret.len() < 33 + 12 {
+        return Err(SecretsError::TooShort);
+    }
+    let (ephemeral, rest) = secret.split_at(33);
+    let (nonce, ciphertext) = rest.split_at(12);
+
+    let ephemeral =
+        PublicKey::from_sec1_bytes(ephemeral).map_err(|_| SecretsError::BadPublicKey)?;
+    let shared = k256::ecdh::diffie_hellman(key.to_nonzero_scalar(), ephemeral.as_affine());
+    let plaintext = cipher(shared.raw_secret_bytes())?
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &aad(code_hash, name),
+            },
+        )
+        .map_err(|_| SecretsError::Decrypt)?;
+
+    String::from_utf8(plaintext).map_err(|_| SecretsError::NotText)
+}
+
+// counterpart of decrypt for tooling, `public_key` is the public key of the derived secrets key
+pub fn encrypt(
+    public_key: &PublicKey,
+    code_hash: &[u8; 32],
+    name: &str,
+    plaintext: &str,
+) -> Result<Vec<u8>, SecretsError> {
+    let ephemeral = k256::ecdh::EphemeralSecret::random(&mut rand::rngs::OsRng);
+    let shared = ephemeral.diffie_hellman(public_key);
+    let nonce = rand::random::<[u8; 12]>();
+    let ciphertext = cipher(shared.raw_secret_bytes())?
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext.as_bytes(),
+                aad: &aad(code_hash, name),
+            },
+        )
+        .map_err(|_| SecretsError::Decrypt)?;
+
+    let mut secret = ephemeral
+        .public_key()
+        .to_encoded_point(true)
+        .as_bytes()
+        .to_vec();
+    secret.extend_from_slice(&nonce);
+    secret.extend_from_slice(&ciphertext);
+
+    Ok(secret)
+}
+
+fn aad(code_hash: &[u8; 32], name: &str) -> Vec<u8> {
+    [code_hash.as_slice(), name.as_bytes()].concat()
+}
+
+fn cipher(shared: &[u8]) -> Result<Aes256Gcm, SecretsError> {
+    let hkdf = Hkdf::<Sha256>::new(None, shared);
+    let mut key = [0u8; 32];
+    hkdf.expand(CIPHER_INFO, &mut key)
+        .map_err(|_| SecretsError::Derive)?;
+
+    Aes256Gcm::new_from_slice(&key).map_err(|_| SecretsError::Derive)
+}
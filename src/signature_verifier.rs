@@ -0,0 +1,70 @@
+use anyhow::Context;
+use clap::Parser;
+use std::str::FromStr;
+
+use serverless::verify::{Eip712Domain, Exchange, SignatureVersion};
+
+/// Verify the signature of a response from the serverless application
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// raw http request as sent, including the Host header
+    #[clap(long, value_parser)]
+    request: String,
+
+    /// raw http response as received, e.g. saved with `curl -i`
+    #[clap(long, value_parser)]
+    response: String,
+
+    /// expected signer address
+    #[clap(long, value_parser)]
+    address: String,
+
+    /// lowest accepted signature version, eip712 > 2 > 1,
+    /// the version is claimed by the response, so a lower one could be a downgrade
+    #[clap(long, value_parser = SignatureVersion::from_str, default_value = "2")]
+    min_version: SignatureVersion,
+
+    /// chain of the node, only used by eip712 signatures
+    #[clap(long, value_parser, default_value = "421614")]
+    chain_id: u64,
+
+    /// deploy contract of the node, only used by eip712 signatures
+    #[clap(
+        long,
+        value_parser,
+        default_value = "0x44fe06d2940b8782a0a9a9ffd09c65852c0156b1"
+    )]
+    contract: String,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Args::parse();
+
+    let request = std::fs::read(cli.request).context("failed to read request")?;
+    let response = std::fs::read(cli.response).context("failed to read response")?;
+
+    let domain = Eip712Domain::new(cli.chain_id, &cli.contract).context("invalid contract")?;
+
+    let exchange = Exchange::parse(&request, &response).context("failed to parse exchange")?;
+    exchange
+        .verify(&cli.address, cli.min_version, &domain)
+        .context("failed to verify signature")?;
+
+    println!(
+        "valid {} by {} at timestamp {}",
+        match exchange.error {
+            true => "error signature",
+            false => "signature",
+        },
+        cli.address,
+        exchange.timestamp
+    );
+
+    // signed streams carry the usage at the end of the body
+    if let (_, Some(usage)) = exchange.split_usage().context("invalid appended usage")? {
+        print!("{}", String::from_utf8_lossy(usage));
+    }
+
+    Ok(())
+}
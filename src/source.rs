@@ -0,0 +1,265 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+use tiny_keccak::{Hasher, Keccak};
+
+use crate::workerd::ServerlessError;
+
+// where the code of a function comes from
+// `id` is the 0x prefixed hex identifier decoded from the Host header
+#[async_trait]
+pub trait CodeSource: Send + Sync {
+    async fn fetch(&self, id: &str) -> Result<Vec<u8>, ServerlessError>;
+
+    // whether code fetched from this source can be reused for later requests with the same id
+    fn cacheable(&self) -> bool {
+        true
+    }
+
+    // settings that decide which code an id resolves to, e.g. the chain and contract
+    fn fingerprint(&self) -> String {
+        String::new()
+    }
+
+    // key of the code of `id` in the code cache
+    // the fingerprint is part of it so a node restarted with other settings does not serve code cached under the old ones
+    fn cache_key(&self, id: &str) -> String {
+        let mut hasher = Keccak::v256();
+        hasher.update(self.fingerprint().as_bytes());
+        hasher.update(b"|");
+        hasher.update(id.as_bytes());
+        let mut hash = [0u8; 32];
+        hasher.finalize(&mut hash);
+
+        "0x".to_owned() + &hex::encode(hash)
+    }
+}
+
+// function of the deploy contract that takes the code as calldata
+pub const DEPLOY_FUNCTION: &str = "saveCodeInCallData(string)";
+
+// first 4 bytes of the keccak hash of a function signature, e.g. `saveCodeInCallData(string)`
+pub fn selector(signature: &str) -> [u8; 4] {
+    let mut hasher = Keccak::v256();
+    hasher.update(signature.as_bytes());
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash);
+
+    hash[..4].try_into().unwrap()
+}
+
+// code stored in the calldata of a transaction to the given contract
+// the calldata is expected to encode a call with a single `bytes` argument holding the code
+pub struct TxSource {
+    client: Client,
+    rpc: String,
+    contract: String,
+    // accepted function selectors, any selector is accepted when None
+    selectors: Option<Vec<[u8; 4]>>,
+}
+
+impl TxSource {
+    pub fn new(rpc: String, contract: String, selectors: Option<Vec<[u8; 4]>>) -> TxSource {
+        TxSource {
+            client: Client::new(),
+            rpc,
+            contract,
+            selectors,
+        }
+    }
+
+    async fn get_transaction_data(&self, tx_hash: &str) -> Result<Value, reqwest::Error> {
+        let method = "eth_getTransactionByHash";
+        let params = json!([&tx_hash]);
+        let id = 1;
+
+        let request = json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+            "id": id,
+        });
+
+        let response = self.client.post(&self.rpc).json(&request).send().await?;
+        let json_response = response.json::<Value>().await?;
+
+        Ok(json_response)
+    }
+}
+
+#[async_trait]
+impl CodeSource for TxSource {
+    async fn fetch(&self, tx_hash: &str) -> Result<Vec<u8>, ServerlessError> {
+        // get tx data
+        let mut tx_data = match self.get_transaction_data(tx_hash).await?["result"].take() {
+            Value::Null => Err(ServerlessError::TxNotFound),
+            other => Ok(other),
+        }?;
+
+        // get contract address
+        let contract_address = match tx_data["to"].take() {
+            Value::String(value) => Ok(value),
+            _ => Err(ServerlessError::InvalidTxToType),
+        }?;
+
+        // check contract address matches expected
+        if contract_address != self.contract {
+            return Err(ServerlessError::InvalidTxToValue(
+                contract_address,
+                self.contract.clone(),
+            ));
+        }
+
+        // get calldata
+        let calldata = match tx_data["input"].take() {
+            Value::String(calldata) => Ok(calldata),
+            _ => Err(ServerlessError::InvalidTxCalldataType),
+        }?;
+
+        decode_calldata(&calldata, self.selectors.as_deref())
+    }
+
+    fn fingerprint(&self) -> String {
+        let selectors = match &self.selectors {
+            Some(selectors) => selectors
+                .iter()
+                .map(hex::encode)
+                .collect::<Vec<_>>()
+                .join(","),
+            None => "any".to_owned(),
+        };
+
+        format!("tx|{}|{}|{}", self.rpc, self.contract, selectors)
+    }
+}
+
+// abi decode the `bytes` argument of a single argument call
+// any selector is accepted when `selectors` is None
+pub fn decode_calldata(
+    calldata: &str,
+    selectors: Option<&[[u8; 4]]>,
+) -> Result<Vec<u8>, ServerlessError> {
+    let calldata = hex::decode(calldata.strip_prefix("0x").unwrap_or(calldata))?;
+
+    // check selector
+    if calldata.len() < 4 {
+        return Err(ServerlessError::CalldataTooShort);
+    }
+    let (selector, args) = calldata.split_at(4);
+    if selectors.is_some_and(|selectors| !selectors.iter().any(|x| x == selector)) {
+        return Err(ServerlessError::InvalidSelector(hex::encode(selector)));
+    }
+
+    // head of the arguments is the offset of the bytes argument
+    let offset = read_word(args, 0).ok_or(ServerlessError::CalldataTooShort)?;
+    if offset < 32 {
+        return Err(ServerlessError::InvalidCodeOffset);
+    }
+
+    // tail starts with the length followed by the data itself
+    if offset.checked_add(32).is_none_or(|x| x > args.len()) {
+        return Err(ServerlessError::InvalidCodeOffset);
+    }
+    let length = read_word(args, offset).ok_or(ServerlessError::InvalidCodeLength)?;
+    let start = offset + 32;
+    let end = start
+        .checked_add(length)
+        .filter(|end| *end <= args.len())
+        .ok_or(ServerlessError::InvalidCodeLength)?;
+
+    Ok(args[start..end].to_vec())
+}
+
+// read a 32 byte big endian word at the given offset as a usize,
+// None if it is out of bounds or does not fit
+fn read_word(data: &[u8], offset: usize) -> Option<usize> {
+    let word = data.get(offset..offset.checked_add(32)?)?;
+    let (high, low) = word.split_at(32 - std::mem::size_of::<usize>());
+    if high.iter().any(|x| *x != 0) {
+        return None;
+    }
+
+    Some(usize::from_be_bytes(low.try_into().ok()?))
+}
+
+// code stored as files named by id in a local directory, meant for development and CI
+pub struct DirSource {
+    dir: PathBuf,
+}
+
+impl DirSource {
+    pub fn new(dir: impl Into<PathBuf>) -> DirSource {
+        DirSource { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl CodeSource for DirSource {
+    async fn fetch(&self, id: &str) -> Result<Vec<u8>, ServerlessError> {
+        tokio::fs::read(self.dir.join(id))
+            .await
+            .map_err(|err| match err.kind() {
+                std::io::ErrorKind::NotFound => ServerlessError::CodeNotFound,
+                _ => ServerlessError::CodeRead(err),
+            })
+    }
+
+    // files can be edited in place, always read the latest version
+    fn cacheable(&self) -> bool {
+        false
+    }
+}
+
+// content addressed store served over http at <base url>/<id>
+// the id is the keccak256 hash of the code, which is verified after fetching
+pub struct HttpSource {
+    client: Client,
+    base_url: String,
+}
+
+impl HttpSource {
+    pub fn new(base_url: String) -> HttpSource {
+        HttpSource {
+            client: Client::new(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl CodeSource for HttpSource {
+    async fn fetch(&self, id: &str) -> Result<Vec<u8>, ServerlessError> {
+        let response = self
+            .client
+            .get(self.base_url.clone() + "/" + id)
+            .send()
+            .await
+            .map_err(ServerlessError::CodeFetch)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(ServerlessError::CodeNotFound);
+        }
+        let code = response
+            .error_for_status()
+            .map_err(ServerlessError::CodeFetch)?
+            .bytes()
+            .await
+            .map_err(ServerlessError::CodeFetch)?;
+
+        let mut hasher = Keccak::v256();
+        hasher.update(&code);
+        let mut hash = [0u8; 32];
+        hasher.finalize(&mut hash);
+        if "0x".to_owned() + &hex::encode(hash) != id {
+            return Err(ServerlessError::CodeHashMismatch);
+        }
+
+        Ok(code.into())
+    }
+
+    // the id is the hash of the code, so every store serves the same code for it
+    fn fingerprint(&self) -> String {
+        "http".to_owned()
+    }
+}
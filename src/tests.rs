@@ -5,9 +5,17 @@
 
 #[cfg(test)]
 pub mod serverlesstest {
+    use crate::cache::CodeCache;
     use crate::cgroups::Cgroups;
+    use crate::config::{ConfigSource, NodeConfig};
+    use crate::egress::{EgressMode, EgressPolicy};
+    use crate::error::{ErrorBody, ErrorCode};
     use crate::handler;
+    use crate::metrics::Metrics;
     use crate::model::AppState;
+    use crate::source::TxSource;
+    use crate::verify::{Eip712Domain, SignatureScheme, SignatureVersion};
+    use crate::workerd::CompatibilityPolicy;
     use actix_web::{
         body::MessageBody,
         dev::{ServiceFactory, ServiceRequest, ServiceResponse},
@@ -15,7 +23,63 @@ pub mod serverlesstest {
         http, test, web, App,
     };
     use serde_json::json;
-    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use std::sync::Arc;
+
+    pub fn domain() -> Eip712Domain {
+        Eip712Domain::new(421614, "0x44fe06d2940b8782a0a9a9ffd09c65852c0156b1").unwrap()
+    }
+
+    pub fn node_config() -> NodeConfig {
+        NodeConfig {
+            compatibility: CompatibilityPolicy {
+                default_date: "2023-03-07".to_owned(),
+                max_date: "2023-03-07".to_owned(),
+                allowed_flags: vec![],
+            },
+            egress: EgressPolicy {
+                mode: EgressMode::Public,
+                rules: vec![],
+            },
+            console_limit: None,
+            code_cache_size: 1 << 26,
+        }
+    }
+
+    pub fn app_state(cgroups: Cgroups) -> AppState {
+        let metrics = Metrics::new(cgroups.free.len());
+        AppState {
+            cgroups: Arc::new(cgroups.into()),
+            running: AtomicBool::new(true),
+            in_flight: AtomicUsize::new(0),
+            runtime_path: "./runtime/".to_owned(),
+            code_source: Box::new(TxSource::new(
+                "https://sepolia-rollup.arbitrum.io/rpc".to_owned(),
+                "0x44fe06d2940b8782a0a9a9ffd09c65852c0156b1".to_owned(),
+                None,
+            )),
+            config: node_config().into(),
+            config_source: ConfigSource {
+                path: None,
+                command_line: node_config(),
+            },
+            report_usage: false,
+            signer: k256::ecdsa::SigningKey::random(&mut rand::rngs::OsRng),
+            signature: SignatureScheme::new(
+                SignatureVersion::V2,
+                SignatureVersion::V1,
+                domain(),
+                vec!["content-type".to_owned()],
+                vec!["content-type".to_owned()],
+            )
+            .unwrap(),
+            secrets_key: k256::SecretKey::random(&mut rand::rngs::OsRng),
+            attestation_url: None,
+            code_cache: CodeCache::new("./runtime/code-cache", 1 << 26).unwrap(),
+            worker_pool: None,
+            metrics,
+        }
+    }
 
     fn new_app() -> App<
         impl ServiceFactory<
@@ -27,14 +91,8 @@ pub mod serverlesstest {
         >,
     > {
         App::new()
-            .app_data(web::Data::new(AppState {
-                cgroups: Cgroups::new().unwrap().into(),
-                running: AtomicBool::new(true),
-                runtime_path: "./runtime/".to_owned(),
-                rpc: "https://sepolia-rollup.arbitrum.io/rpc".to_owned(),
-                contract: "0x44fe06d2940b8782a0a9a9ffd09c65852c0156b1".to_owned(),
-                signer: k256::ecdsa::SigningKey::random(&mut rand::rngs::OsRng),
-            }))
+            .app_data(web::Data::new(app_state(Cgroups::new().unwrap())))
+            .route("/oyster/identity", web::get().to(handler::identity))
             .default_service(web::to(handler::serverless))
     }
 
@@ -49,7 +107,7 @@ pub mod serverlesstest {
                 // "0x9468bb6a8e85ed11e292c8cac0c1539df691c8d8ec62e7dbfa9f1bd7f504e46e.oyster.run",
                 "SRULW2UOQXWRDYUSZDFMBQKTTX3JDSGY5RROPW72T4N5P5IE4RXA.oyster.run",
             ))
-            .set_json(&json!({
+            .set_json(json!({
                 "num": 10
             }))
             .to_request();
@@ -66,7 +124,7 @@ pub mod serverlesstest {
                 // "0x9468bb6a8e85ed11e292c8cac0c1539df691c8d8ec62e7dbfa9f1bd7f504e46e.oyster.run",
                 "SRULW2UOQXWRDYUSZDFMBQKTTX3JDSGY5RROPW72T4N5P5IE4RXA.oyster.run",
             ))
-            .set_json(&json!({
+            .set_json(json!({
                 "num": 20
             }))
             .to_request();
@@ -83,7 +141,7 @@ pub mod serverlesstest {
                 // "0x9468bb6a8e85ed11e292c8cac0c1539df691c8d8ec62e7dbfa9f1bd7f504e46e.oyster.run",
                 "SRULW2UOQXWRDYUSZDFMBQKTTX3JDSGY5RROPW72T4N5P5IE4RXA.oyster.run",
             ))
-            .set_json(&json!({
+            .set_json(json!({
                 "num": 600
             }))
             .to_request();
@@ -105,7 +163,7 @@ pub mod serverlesstest {
                 // "0x9468bb6a8e85ed11e292c8cac0c1539df691c8d8ec62e7dbfa9f1bd7f504e46e.oyster.run",
                 "srulw2uoqxwrdyuszdfmbqkttx3jdsgy5rropw72t4n5p5ie4rxa.oyster.run",
             ))
-            .set_json(&json!({
+            .set_json(json!({
                 "num": 10
             }))
             .to_request();
@@ -122,7 +180,7 @@ pub mod serverlesstest {
                 // "0x9468bb6a8e85ed11e292c8cac0c1539df691c8d8ec62e7dbfa9f1bd7f504e46e.oyster.run",
                 "srulw2uoqxwrdyuszdfmbqkttx3jdsgy5rropw72t4n5p5ie4rxa.oyster.run",
             ))
-            .set_json(&json!({
+            .set_json(json!({
                 "num": 20
             }))
             .to_request();
@@ -139,7 +197,7 @@ pub mod serverlesstest {
                 // "0x9468bb6a8e85ed11e292c8cac0c1539df691c8d8ec62e7dbfa9f1bd7f504e46e.oyster.run",
                 "srulw2uoqxwrdyuszdfmbqkttx3jdsgy5rropw72t4n5p5ie4rxa.oyster.run",
             ))
-            .set_json(&json!({
+            .set_json(json!({
                 "num": 600
             }))
             .to_request();
@@ -161,7 +219,7 @@ pub mod serverlesstest {
                 // "0x9468bb6a8e85ed11e292c8cac0c1539df691c8d8ec62e7dbfa9f1bd7f504e46e.oyster.run",
                 "SRULW2UOQXWRDYUSZDFMBQKTTX3JDSGY5RROPW72T4N5P5IE4RXA.oyster.run",
             ))
-            .set_json(&json!({
+            .set_json(json!({
                 "num": 10
             }))
             .to_request();
@@ -178,7 +236,7 @@ pub mod serverlesstest {
                 // "0x9468bb6a8e85ed11e292c8cac0c1539df691c8d8ec62e7dbfa9f1bd7f504e46e.oyster.run",
                 "SRULW2UOQXWRDYUSZDFMBQKTTX3JDSGY5RROPW72T4N5P5IE4RXA.oyster.run",
             ))
-            .set_json(&json!({
+            .set_json(json!({
                 "num": 20
             }))
             .to_request();
@@ -195,7 +253,7 @@ pub mod serverlesstest {
                 // "0x9468bb6a8e85ed11e292c8cac0c1539df691c8d8ec62e7dbfa9f1bd7f504e46e.oyster.run",
                 "SRULW2UOQXWRDYUSZDFMBQKTTX3JDSGY5RROPW72T4N5P5IE4RXA.oyster.run",
             ))
-            .set_json(&json!({
+            .set_json(json!({
                 "num": 600
             }))
             .to_request();
@@ -217,7 +275,7 @@ pub mod serverlesstest {
                 // "0x9468bb6a8e85ed11e292c8cac0c1539df691c8d8ec62e7dbfa9f1bd7f504e46e.oyster.run",
                 "SRULW2UOQXWRDYUSZDFMBQKTTX3JDSGY5RROPW72T4N5P5IE4RXA.oyster.run",
             ))
-            .set_json(&json!({
+            .set_json(json!({
                 "num": 10
             }))
             .to_request();
@@ -234,7 +292,7 @@ pub mod serverlesstest {
                 // "0x9468bb6a8e85ed11e292c8cac0c1539df691c8d8ec62e7dbfa9f1bd7f504e46e.oyster.run",
                 "SRULW2UOQXWRDYUSZDFMBQKTTX3JDSGY5RROPW72T4N5P5IE4RXA.oyster.run",
             ))
-            .set_json(&json!({
+            .set_json(json!({
                 "num": 20
             }))
             .to_request();
@@ -251,7 +309,7 @@ pub mod serverlesstest {
                 // "0x9468bb6a8e85ed11e292c8cac0c1539df691c8d8ec62e7dbfa9f1bd7f504e46e.oyster.run",
                 "SRULW2UOQXWRDYUSZDFMBQKTTX3JDSGY5RROPW72T4N5P5IE4RXA.oyster.run",
             ))
-            .set_json(&json!({
+            .set_json(json!({
                 "num": 600
             }))
             .to_request();
@@ -283,10 +341,10 @@ pub mod serverlesstest {
         let resp = test::call_service(&app, req).await;
 
         assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
-        assert_eq!(
-            resp.into_body().try_into_bytes().unwrap(),
-            "failed to create code file\n\nCaused by:\n    to address 0x0784e2d4551905f66269b133aa4f43fe3d23b707 does not match expected 0x44fe06d2940b8782a0a9a9ffd09c65852c0156b1"
-        );
+        let body: ErrorBody =
+            serde_json::from_slice(&resp.into_body().try_into_bytes().unwrap()).unwrap();
+        assert_eq!(body.code, ErrorCode::WrongContract);
+        assert_eq!(body.message, "to address 0x0784e2d4551905f66269b133aa4f43fe3d23b707 does not match expected 0x44fe06d2940b8782a0a9a9ffd09c65852c0156b1");
     }
 
     #[actix_web::test]
@@ -310,10 +368,10 @@ pub mod serverlesstest {
         let resp = test::call_service(&app, req).await;
 
         assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
-        assert_eq!(
-            resp.into_body().try_into_bytes().unwrap(),
-            "failed to create code file\n\nCaused by:\n    tx not found"
-        );
+        let body: ErrorBody =
+            serde_json::from_slice(&resp.into_body().try_into_bytes().unwrap()).unwrap();
+        assert_eq!(body.code, ErrorCode::TxNotFound);
+        assert_eq!(body.message, "tx not found");
     }
 
     #[actix_web::test]
@@ -330,10 +388,10 @@ pub mod serverlesstest {
         let resp = test::call_service(&app, req).await;
 
         assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
-        assert_eq!(
-            resp.into_body().try_into_bytes().unwrap(),
-            "could not find Host header"
-        );
+        let body: ErrorBody =
+            serde_json::from_slice(&resp.into_body().try_into_bytes().unwrap()).unwrap();
+        assert_eq!(body.code, ErrorCode::InvalidHost);
+        assert_eq!(body.message, "could not find Host header");
     }
 
     #[actix_web::test]
@@ -357,10 +415,10 @@ pub mod serverlesstest {
         let resp = test::call_service(&app, req).await;
 
         assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
-        assert_eq!(
-            resp.into_body().try_into_bytes().unwrap(),
-            "syntax error in the code: service main: Uncaught SyntaxError: Unexpected token 'export'\n  at main:1:1"
-        );
+        let body: ErrorBody =
+            serde_json::from_slice(&resp.into_body().try_into_bytes().unwrap()).unwrap();
+        assert_eq!(body.code, ErrorCode::SyntaxError);
+        assert_eq!(body.message, "syntax error in the code: service main: Uncaught SyntaxError: Unexpected token 'export'\n  at main:1:1");
     }
 
     #[actix_web::test]
@@ -407,10 +465,10 @@ pub mod serverlesstest {
         let resp = test::call_service(&app, req).await;
 
         assert_eq!(resp.status(), http::StatusCode::REQUEST_TIMEOUT);
-        assert_eq!(
-            resp.into_body().try_into_bytes().unwrap(),
-            "worker timed out\n\nCaused by:\n    deadline has elapsed"
-        );
+        let body: ErrorBody =
+            serde_json::from_slice(&resp.into_body().try_into_bytes().unwrap()).unwrap();
+        assert_eq!(body.code, ErrorCode::WorkerTimeout);
+        assert_eq!(body.message, "worker timed out");
     }
 
     #[actix_web::test]
@@ -434,9 +492,3689 @@ pub mod serverlesstest {
         let resp = test::call_service(&app, req).await;
 
         assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let body: ErrorBody =
+            serde_json::from_slice(&resp.into_body().try_into_bytes().unwrap()).unwrap();
+        assert_eq!(body.code, ErrorCode::InvalidTxHash);
         assert_eq!(
-            resp.into_body().try_into_bytes().unwrap(),
-            "invalid tx hash encoding: DecodeError { position: 51, kind: Symbol }"
+            body.message,
+            "invalid tx hash encoding: invalid symbol at 51"
+        );
+    }
+}
+
+#[cfg(test)]
+pub mod codecachetest {
+    use crate::cache::CodeCache;
+    use std::path::PathBuf;
+
+    const TX_A: &str = "0x9468bb6a8e85ed11e292c8cac0c1539df691c8d8ec62e7dbfa9f1bd7f504e46e";
+    const TX_B: &str = "0xfed8ab36cc27831836f6dcb7291049158b4d8df31c0ffb05a3d36ba6555e29d7";
+    const TX_C: &str = "0x37b0b2d9dd58d9130781fc914da456c16ec403010e8d4c27b0ea4657a24c8546";
+
+    fn cache_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("oyster-code-cache-{}", rand::random::<u64>()))
+    }
+
+    #[tokio::test]
+    async fn hit_and_miss_test() {
+        let dir = cache_dir();
+        let cache = CodeCache::new(&dir, 1024).unwrap();
+
+        assert_eq!(cache.get(TX_A).await, None);
+        cache.insert(TX_A, b"export default {}").await.unwrap();
+        assert_eq!(cache.get(TX_A).await.unwrap(), b"export default {}");
+        assert_eq!(cache.stats(), (1, 1));
+
+        // entries missing from disk are forgotten
+        std::fs::remove_file(dir.join(TX_A.to_owned() + ".code")).unwrap();
+        assert_eq!(cache.get(TX_A).await, None);
+        assert!(!cache.contains(TX_A));
+        assert_eq!(cache.stats(), (1, 2));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lru_eviction_by_size_test() {
+        let dir = cache_dir();
+        let cache = CodeCache::new(&dir, 10).unwrap();
+
+        cache.insert(TX_A, &[1; 4]).await.unwrap();
+        cache.insert(TX_B, &[2; 4]).await.unwrap();
+        // touch A so that B becomes the least recently used entry
+        cache.get(TX_A).await.unwrap();
+        cache.insert(TX_C, &[3; 4]).await.unwrap();
+
+        assert!(cache.contains(TX_A));
+        assert!(!cache.contains(TX_B));
+        assert!(cache.contains(TX_C));
+        assert_eq!(cache.size(), 8);
+
+        // entries larger than the cache are never stored
+        cache.insert(TX_B, &[2; 11]).await.unwrap();
+        assert!(!cache.contains(TX_B));
+        assert!(!dir.join(TX_B.to_owned() + ".code").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn persistence_test() {
+        let dir = cache_dir();
+        let cache = CodeCache::new(&dir, 1024).unwrap();
+        cache.insert(TX_A, b"export default {}").await.unwrap();
+        drop(cache);
+
+        let cache = CodeCache::new(&dir, 1024).unwrap();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(TX_A).await.unwrap(), b"export default {}");
+
+        // shrinking the cache on restart evicts entries that no longer fit
+        let cache = CodeCache::new(&dir, 4).unwrap();
+        assert!(cache.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recency_persistence_test() {
+        let dir = cache_dir();
+        let cache = CodeCache::new(&dir, 1024).unwrap();
+        cache.insert(TX_A, &[1; 4]).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        cache.insert(TX_B, &[2; 4]).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        // A was inserted first but used last, so B is evicted after a restart
+        cache.get(TX_A).await.unwrap();
+        drop(cache);
+
+        let cache = CodeCache::new(&dir, 4).unwrap();
+        assert!(cache.contains(TX_A));
+        assert!(!cache.contains(TX_B));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+pub mod workerpooltest {
+    use crate::cgroups::Cgroups;
+    use crate::pool::{Resources, Worker, WorkerPool};
+    use std::process::Command;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    fn new_worker(tx_hash: &str, cgroup: &str) -> Worker {
+        let mut resources = Resources::new(
+            tx_hash,
+            &hex::encode(rand::random::<u32>().to_ne_bytes()),
+            "./runtime/",
+            Arc::new(Mutex::new(Cgroups { free: vec![] })),
+        );
+        resources.track_cgroup(cgroup.to_owned());
+        resources.track_child(Command::new("sleep").arg("10").spawn().unwrap());
+
+        Worker {
+            port: 11000,
+            last_used: Instant::now(),
+            resources,
+            console: Default::default(),
+        }
+    }
+
+    // dropping the worker kills it
+    fn kill(worker: Worker) -> String {
+        worker.resources.cgroup().to_owned()
+    }
+
+    #[test]
+    fn take_matches_tx_hash_test() {
+        let mut pool = WorkerPool::new(Duration::from_secs(60));
+        pool.put(new_worker("0xaa", "workerd_1"));
+        pool.put(new_worker("0xbb", "workerd_2"));
+
+        assert!(pool.take("0xcc").is_none());
+        assert_eq!(kill(pool.take("0xbb").unwrap()), "workerd_2");
+        assert!(pool.take("0xbb").is_none());
+        assert_eq!(pool.len(), 1);
+
+        kill(pool.take("0xaa").unwrap());
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn evict_least_recently_used_test() {
+        let mut pool = WorkerPool::new(Duration::from_secs(60));
+        pool.put(new_worker("0xaa", "workerd_1"));
+        std::thread::sleep(Duration::from_millis(5));
+        pool.put(new_worker("0xbb", "workerd_2"));
+
+        assert_eq!(kill(pool.evict().unwrap()), "workerd_1");
+        assert_eq!(kill(pool.evict().unwrap()), "workerd_2");
+        assert!(pool.evict().is_none());
+    }
+
+    #[test]
+    fn expire_idle_workers_test() {
+        let mut pool = WorkerPool::new(Duration::from_millis(20));
+        pool.put(new_worker("0xaa", "workerd_1"));
+        assert!(pool.expire().is_empty());
+
+        std::thread::sleep(Duration::from_millis(25));
+        pool.put(new_worker("0xbb", "workerd_2"));
+
+        let expired = pool.expire();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(pool.len(), 1);
+        expired.into_iter().for_each(|worker| {
+            kill(worker);
+        });
+        kill(pool.take("0xbb").unwrap());
+    }
+
+    #[test]
+    fn resources_cleanup_test() {
+        let dir = std::env::temp_dir().join(format!("oyster-runtime-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(dir.join("0xaa-1234")).unwrap();
+        std::fs::write(dir.join("0xaa-1234.capnp"), "").unwrap();
+        let cgroups = Arc::new(Mutex::new(Cgroups { free: vec![] }));
+
+        let mut resources = Resources::new("0xaa", "1234", dir.to_str().unwrap(), cgroups.clone());
+        resources.track_code_file();
+        resources.track_config_file();
+        resources.track_cgroup("workerd_1".to_owned());
+        resources.track_child(Command::new("sleep").arg("10").spawn().unwrap());
+        let pid = resources.child().id();
+
+        // resources are released even if the request panics while holding them
+        let cgroups_clone = cgroups.clone();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+            let _resources = resources;
+            let _lock = cgroups_clone.lock().unwrap();
+            panic!("worker panicked");
+        }));
+        assert!(result.is_err());
+
+        assert_eq!(
+            cgroups.lock().unwrap_or_else(|x| x.into_inner()).free,
+            vec!["workerd_1"]
+        );
+        assert!(!dir.join("0xaa-1234").exists());
+        assert!(!dir.join("0xaa-1234.capnp").exists());
+        assert!(!std::path::Path::new(&format!("/proc/{pid}")).exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn resources_release_test() {
+        let cgroups = Arc::new(Mutex::new(Cgroups { free: vec![] }));
+        let new_resources = |cgroup: &str| {
+            let mut resources = Resources::new("0xaa", "1234", "./runtime/", cgroups.clone());
+            resources.track_cgroup(cgroup.to_owned());
+            resources.track_child(Command::new("sleep").arg("10").spawn().unwrap());
+            resources
+        };
+
+        // releasing waits for the cleanup
+        let mut resources = new_resources("workerd_1");
+        let pid = resources.child().id();
+        resources.release().await;
+        assert_eq!(cgroups.lock().unwrap().free, vec!["workerd_1"]);
+        assert!(!std::path::Path::new(&format!("/proc/{pid}")).exists());
+
+        // dropping on the runtime cleans up on a blocking thread
+        drop(new_resources("workerd_2"));
+        let start = Instant::now();
+        while cgroups.lock().unwrap().free.len() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            actix_web::rt::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod codesourcetest {
+    use crate::source::{CodeSource, DirSource, HttpSource, TxSource};
+    use crate::workerd::ServerlessError;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use tiny_keccak::{Hasher, Keccak};
+
+    #[actix_web::test]
+    async fn dir_source_test() {
+        let dir = std::env::temp_dir().join(format!("oyster-code-dir-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("0xaa"), "export default {}").unwrap();
+
+        let source = DirSource::new(&dir);
+        assert!(!source.cacheable());
+        assert_eq!(source.fetch("0xaa").await.unwrap(), b"export default {}");
+        assert!(matches!(
+            source.fetch("0xbb").await,
+            Err(ServerlessError::CodeNotFound)
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn http_source_test() {
+        let server = HttpServer::new(|| {
+            App::new().route(
+                "/{id}",
+                web::get().to(|id: web::Path<String>| async move {
+                    match id.as_str() {
+                        "0xmissing" => HttpResponse::NotFound().finish(),
+                        _ => HttpResponse::Ok().body("export default {}"),
+                    }
+                }),
+            )
+        })
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let mut hasher = Keccak::v256();
+        hasher.update(b"export default {}");
+        let mut hash = [0u8; 32];
+        hasher.finalize(&mut hash);
+        let id = "0x".to_owned() + &hex::encode(hash);
+
+        let source = HttpSource::new(format!("http://127.0.0.1:{port}/"));
+        assert_eq!(source.fetch(&id).await.unwrap(), b"export default {}");
+        assert!(matches!(
+            source.fetch("0xbad").await,
+            Err(ServerlessError::CodeHashMismatch)
+        ));
+        assert!(matches!(
+            source.fetch("0xmissing").await,
+            Err(ServerlessError::CodeNotFound)
+        ));
+
+        handle.stop(false).await;
+    }
+
+    #[test]
+    fn cache_key_test() {
+        let id = "0x".to_owned() + &"aa".repeat(32);
+        let source = |rpc: &str, contract: &str, selectors: Option<Vec<[u8; 4]>>| {
+            TxSource::new(rpc.to_owned(), contract.to_owned(), selectors)
+        };
+        let key = source("http://rpc", "0x01", None).cache_key(&id);
+
+        // keys are valid cache file names
+        assert_eq!(key.len(), 66);
+        assert!(key.starts_with("0x"));
+        assert_eq!(key, source("http://rpc", "0x01", None).cache_key(&id));
+
+        // any change to the source settings misses code cached under the old ones
+        assert_ne!(key, source("http://other", "0x01", None).cache_key(&id));
+        assert_ne!(key, source("http://rpc", "0x02", None).cache_key(&id));
+        assert_ne!(
+            key,
+            source("http://rpc", "0x01", Some(vec![[1, 2, 3, 4]])).cache_key(&id)
+        );
+        assert_ne!(
+            key,
+            HttpSource::new("http://store".to_owned()).cache_key(&id)
+        );
+    }
+}
+
+#[cfg(test)]
+pub mod calldatatest {
+    use crate::source::{decode_calldata, selector};
+    use crate::workerd::ServerlessError;
+
+    // encode a call with a single bytes argument
+    fn encode(selector: &str, code: &[u8]) -> String {
+        let mut calldata = "0x".to_owned() + selector;
+        calldata += &format!("{:064x}", 32);
+        calldata += &format!("{:064x}", code.len());
+        calldata += &hex::encode(code);
+        calldata += &"00".repeat((32 - code.len() % 32) % 32);
+        calldata
+    }
+
+    #[test]
+    fn valid_calldata_test() {
+        let calldata = encode("12345678", b"export default {}");
+        assert_eq!(
+            decode_calldata(&calldata, Some(&[[0x12, 0x34, 0x56, 0x78]])).unwrap(),
+            b"export default {}"
+        );
+        assert_eq!(
+            decode_calldata(&calldata, None).unwrap(),
+            b"export default {}"
+        );
+    }
+
+    #[test]
+    fn trailing_zeros_preserved_test() {
+        let code = b"\0asm\x01\0\0\0";
+        let calldata = encode("12345678", code);
+        assert_eq!(decode_calldata(&calldata, None).unwrap(), code);
+    }
+
+    #[test]
+    fn selector_test() {
+        assert_eq!(
+            selector("transfer(address,uint256)"),
+            [0xa9, 0x05, 0x9c, 0xbb]
+        );
+    }
+
+    #[test]
+    fn invalid_selector_test() {
+        let calldata = encode("12345678", b"export default {}");
+        assert!(matches!(
+            decode_calldata(&calldata, Some(&[[0xde, 0xad, 0xbe, 0xef]])),
+            Err(ServerlessError::InvalidSelector(selector)) if selector == "12345678"
+        ));
+    }
+
+    #[test]
+    fn malformed_calldata_test() {
+        assert!(matches!(
+            decode_calldata("0x1234", None),
+            Err(ServerlessError::CalldataTooShort)
+        ));
+        assert!(matches!(
+            decode_calldata("0x12345678", None),
+            Err(ServerlessError::CalldataTooShort)
+        ));
+        assert!(matches!(
+            decode_calldata("0x1234567z", None),
+            Err(ServerlessError::BadCalldata(_))
+        ));
+
+        // offset pointing past the end of the calldata
+        let calldata = "0x12345678".to_owned() + &format!("{:064x}", 64);
+        assert!(matches!(
+            decode_calldata(&calldata, None),
+            Err(ServerlessError::InvalidCodeOffset)
+        ));
+
+        // length larger than the remaining calldata
+        let calldata = "0x12345678".to_owned() + &format!("{:064x}{:064x}", 32, 100) + "aabb";
+        assert!(matches!(
+            decode_calldata(&calldata, None),
+            Err(ServerlessError::InvalidCodeLength)
+        ));
+
+        // length that overflows
+        let calldata = "0x12345678".to_owned() + &format!("{:064x}", 32) + &"f".repeat(64);
+        assert!(matches!(
+            decode_calldata(&calldata, None),
+            Err(ServerlessError::InvalidCodeLength)
+        ));
+    }
+}
+
+#[cfg(test)]
+pub mod modulestest {
+    use crate::workerd::{self, ModuleKind, WorkerConfig};
+
+    #[test]
+    fn js_payload_test() {
+        let modules = workerd::get_deployment(b"export default {}".to_vec())
+            .unwrap()
+            .modules;
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].name, "main");
+        assert_eq!(modules[0].kind, ModuleKind::EsModule);
+        assert_eq!(modules[0].content, b"export default {}");
+    }
+
+    #[test]
+    fn wasm_payload_test() {
+        let wasm = b"\0asm\x01\0\0\0".to_vec();
+        let modules = workerd::get_deployment(wasm.clone()).unwrap().modules;
+        assert_eq!(modules.len(), 2);
+        assert_eq!(modules[0].name, "main");
+        assert_eq!(modules[0].kind, ModuleKind::EsModule);
+        assert_eq!(modules[1].name, "main.wasm");
+        assert_eq!(modules[1].kind, ModuleKind::Wasm);
+        assert_eq!(modules[1].content, wasm);
+    }
+
+    #[actix_web::test]
+    async fn config_file_modules_test() {
+        let dir = std::env::temp_dir().join(format!("oyster-runtime-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let runtime_path = dir.to_str().unwrap();
+
+        let modules = workerd::get_deployment(b"\0asm\x01\0\0\0".to_vec())
+            .unwrap()
+            .modules;
+        workerd::create_code_file(&modules, "0xaa", "slug", runtime_path)
+            .await
+            .unwrap();
+        let config = WorkerConfig {
+            modules,
+            bindings: vec![],
+            compatibility_date: "2023-03-07".to_owned(),
+            compatibility_flags: vec![],
+            egress: None,
+        };
+        workerd::create_config_file(&config, "0xaa", "slug", runtime_path, 11001)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read(dir.join("0xaa-slug/main.wasm")).unwrap(),
+            b"\0asm\x01\0\0\0"
+        );
+        let config = std::fs::read_to_string(dir.join("0xaa-slug.capnp")).unwrap();
+        assert!(config.contains("(name = \"main\", esModule = embed \"0xaa-slug/main\")"));
+        assert!(config.contains("(name = \"main.wasm\", wasm = embed \"0xaa-slug/main.wasm\")"));
+        assert!(config.contains("address = \"*:11001\""));
+
+        workerd::cleanup_code_file("0xaa", "slug", runtime_path).unwrap();
+        workerd::cleanup_config_file("0xaa", "slug", runtime_path).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+pub mod bundletest {
+    use crate::bundle;
+    use crate::workerd::{self, Deployment, Module, ModuleKind, ServerlessError};
+
+    fn module(name: &str, kind: ModuleKind, content: &[u8]) -> Module {
+        Module {
+            name: name.to_owned(),
+            kind,
+            content: content.to_vec(),
+        }
+    }
+
+    #[test]
+    fn roundtrip_test() {
+        let modules = vec![
+            module(
+                "main",
+                ModuleKind::EsModule,
+                b"import { add } from \"lib/math.js\";",
+            ),
+            module(
+                "lib/math.js",
+                ModuleKind::EsModule,
+                b"export const add = 1;",
+            ),
+            module("lib/add.wasm", ModuleKind::Wasm, b"\0asm\x01\0\0\0"),
+            module("config.json", ModuleKind::Json, b"{\"a\":1}"),
+            module("readme.txt", ModuleKind::Text, b"hello"),
+            module("blob.bin", ModuleKind::Data, b"\0\0\0"),
+        ];
+
+        let bundle = bundle::encode(&Deployment::from_modules(modules.clone()));
+        assert_eq!(workerd::get_deployment(bundle).unwrap().modules, modules);
+    }
+
+    #[test]
+    fn invalid_bundle_test() {
+        let invalid = |modules: &[Module]| {
+            matches!(
+                bundle::parse(&bundle::encode(&Deployment::from_modules(modules.to_vec()))),
+                Err(ServerlessError::InvalidBundle(_))
+            )
+        };
+
+        assert!(invalid(&[]));
+        assert!(invalid(&[module("main", ModuleKind::Text, b"")]));
+        for name in ["", "../main", "/main", "a//b", "a/./b", "main\"", "a b"] {
+            assert!(
+                invalid(&[module(name, ModuleKind::EsModule, b"")]),
+                "{name}"
+            );
+        }
+        assert!(invalid(&[
+            module("main", ModuleKind::EsModule, b""),
+            module("main", ModuleKind::Text, b""),
+        ]));
+
+        // truncated data
+        let mut bundle = bundle::encode(&Deployment::from_modules(vec![module(
+            "main",
+            ModuleKind::EsModule,
+            b"abc",
+        )]));
+        bundle.pop();
+        assert!(matches!(
+            bundle::parse(&bundle),
+            Err(ServerlessError::InvalidBundle("truncated entry"))
+        ));
+
+        // unknown version
+        let mut bundle = bundle::encode(&Deployment::from_modules(vec![module(
+            "main",
+            ModuleKind::EsModule,
+            b"abc",
+        )]));
+        bundle[4] = 2;
+        assert!(matches!(
+            bundle::parse(&bundle),
+            Err(ServerlessError::InvalidBundle("unsupported version"))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn nested_modules_test() {
+        let dir = std::env::temp_dir().join(format!("oyster-runtime-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let runtime_path = dir.to_str().unwrap();
+
+        let modules = vec![
+            module("main", ModuleKind::EsModule, b"export default {}"),
+            module("lib/util/math.js", ModuleKind::EsModule, b"export {}"),
+        ];
+        workerd::create_code_file(&modules, "0xaa", "slug", runtime_path)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("0xaa-slug/lib/util/math.js")).unwrap(),
+            b"export {}"
+        );
+
+        workerd::cleanup_code_file("0xaa", "slug", runtime_path).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+pub mod bindingstest {
+    use crate::bundle;
+    use crate::secrets::{self, SecretsError};
+    use crate::workerd::{
+        self, Binding, BindingValue, Deployment, Module, ModuleKind, Secret, ServerlessError,
+        WorkerConfig,
+    };
+
+    fn deployment(bindings: Vec<Binding>, secrets: Vec<Secret>) -> Deployment {
+        Deployment {
+            modules: vec![Module {
+                name: "main".to_owned(),
+                kind: ModuleKind::EsModule,
+                content: b"export default {}".to_vec(),
+            }],
+            bindings,
+            secrets,
+            ..Default::default()
+        }
+    }
+
+    fn text(name: &str, value: &str) -> Binding {
+        Binding {
+            name: name.to_owned(),
+            value: BindingValue::Text(value.to_owned()),
+        }
+    }
+
+    #[test]
+    fn secret_roundtrip_test() {
+        let signer = k256::ecdsa::SigningKey::random(&mut rand::rngs::OsRng);
+        let key = secrets::derive_key(&signer).unwrap();
+        // derivation is deterministic
+        assert_eq!(key, secrets::derive_key(&signer).unwrap());
+
+        let code_hash = [1; 32];
+        let secret = secrets::encrypt(&key.public_key(), &code_hash, "API_KEY", "hunter2").unwrap();
+        assert_eq!(
+            secrets::decrypt(&key, &code_hash, "API_KEY", &secret).unwrap(),
+            "hunter2"
+        );
+
+        // secrets are bound to their name, deployment and key
+        assert!(matches!(
+            secrets::decrypt(&key, &code_hash, "OTHER_KEY", &secret),
+            Err(SecretsError::Decrypt)
+        ));
+        assert!(matches!(
+            secrets::decrypt(&key, &[2; 32], "API_KEY", &secret),
+            Err(SecretsError::Decrypt)
+        ));
+        let other = k256::SecretKey::random(&mut rand::rngs::OsRng);
+        assert!(matches!(
+            secrets::decrypt(&other, &code_hash, "API_KEY", &secret),
+            Err(SecretsError::Decrypt)
+        ));
+        assert!(matches!(
+            secrets::decrypt(&key, &code_hash, "API_KEY", &secret[..40]),
+            Err(SecretsError::TooShort)
+        ));
+    }
+
+    #[test]
+    fn bundle_bindings_test() {
+        let key = k256::SecretKey::random(&mut rand::rngs::OsRng);
+        let mut deployment = deployment(
+            vec![
+                text("GREETING", "hello"),
+                Binding {
+                    name: "CONFIG".to_owned(),
+                    value: BindingValue::Json("{\"retries\":3}".to_owned()),
+                },
+            ],
+            vec![],
+        );
+        let code_hash = deployment.code_hash();
+        deployment.secrets.push(Secret {
+            name: "API_KEY".to_owned(),
+            ciphertext: secrets::encrypt(&key.public_key(), &code_hash, "API_KEY", "hunter2")
+                .unwrap(),
+        });
+        // secrets are not part of the code hash
+        assert_eq!(deployment.code_hash(), code_hash);
+
+        let parsed = workerd::get_deployment(bundle::encode(&deployment)).unwrap();
+        assert_eq!(parsed, deployment);
+
+        let bindings = parsed.decrypt_bindings(&key).unwrap();
+        assert_eq!(bindings.len(), 3);
+        assert_eq!(bindings[2], text("API_KEY", "hunter2"));
+
+        let other = k256::SecretKey::random(&mut rand::rngs::OsRng);
+        assert!(matches!(
+            parsed.decrypt_bindings(&other),
+            Err(ServerlessError::SecretDecrypt(name, _)) if name == "API_KEY"
+        ));
+
+        // a secret copied into another deployment does not decrypt
+        let mut copied = parsed.clone();
+        copied.modules[0].content = b"export default { fetch() {} }".to_vec();
+        assert!(matches!(
+            copied.decrypt_bindings(&key),
+            Err(ServerlessError::SecretDecrypt(name, _)) if name == "API_KEY"
+        ));
+        let mut copied = parsed;
+        copied.bindings.pop();
+        assert!(matches!(
+            copied.decrypt_bindings(&key),
+            Err(ServerlessError::SecretDecrypt(name, _)) if name == "API_KEY"
+        ));
+    }
+
+    #[test]
+    fn invalid_bindings_test() {
+        let invalid = |deployment: Deployment| {
+            matches!(
+                bundle::parse(&bundle::encode(&deployment)),
+                Err(ServerlessError::InvalidBundle(_))
+            )
+        };
+
+        for name in ["", "1KEY", "MY-KEY", "MY KEY"] {
+            assert!(invalid(deployment(vec![text(name, "")], vec![])), "{name}");
+        }
+        assert!(invalid(deployment(
+            vec![text("KEY", "a")],
+            vec![Secret {
+                name: "KEY".to_owned(),
+                ciphertext: vec![],
+            }],
+        )));
+        assert!(invalid(deployment(
+            vec![Binding {
+                name: "CONFIG".to_owned(),
+                value: BindingValue::Json("{".to_owned()),
+            }],
+            vec![],
+        )));
+    }
+
+    #[actix_web::test]
+    async fn config_file_bindings_test() {
+        let dir = std::env::temp_dir().join(format!("oyster-runtime-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let runtime_path = dir.to_str().unwrap();
+
+        let deployment = deployment(vec![text("QUOTED", "a \"b\" \\c\nd")], vec![]);
+        let config = WorkerConfig {
+            modules: deployment.modules,
+            bindings: deployment.bindings,
+            compatibility_date: "2023-03-07".to_owned(),
+            compatibility_flags: vec![],
+            egress: None,
+        };
+        workerd::create_config_file(&config, "0xaa", "slug", runtime_path, 11001)
+            .await
+            .unwrap();
+
+        let config = std::fs::read_to_string(dir.join("0xaa-slug.capnp")).unwrap();
+        assert!(config.contains(r#"(name = "QUOTED", text = "a \"b\" \\c\x0ad")"#));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+pub mod compatibilitytest {
+    use crate::bundle;
+    use crate::egress::{EgressMode, EgressPolicy};
+    use crate::workerd::{
+        self, CompatibilityPolicy, Deployment, Module, ModuleKind, ServerlessError,
+    };
+
+    fn policy() -> CompatibilityPolicy {
+        CompatibilityPolicy {
+            default_date: "2023-03-07".to_owned(),
+            max_date: "2024-06-01".to_owned(),
+            allowed_flags: vec!["nodejs_compat".to_owned()],
+        }
+    }
+
+    fn deployment(date: Option<&str>, flags: &[&str]) -> Deployment {
+        Deployment {
+            modules: vec![Module {
+                name: "main".to_owned(),
+                kind: ModuleKind::EsModule,
+                content: b"export default {}".to_vec(),
+            }],
+            compatibility_date: date.map(str::to_owned),
+            compatibility_flags: flags.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn bundle_settings_test() {
+        let deployment = deployment(Some("2024-01-15"), &["nodejs_compat"]);
+        let parsed = workerd::get_deployment(bundle::encode(&deployment)).unwrap();
+        assert_eq!(parsed, deployment);
+    }
+
+    #[test]
+    fn policy_test() {
+        let policy = policy();
+        assert_eq!(policy.check(&deployment(None, &[])).unwrap(), "2023-03-07");
+        assert_eq!(
+            policy
+                .check(&deployment(Some("2024-06-01"), &["nodejs_compat"]))
+                .unwrap(),
+            "2024-06-01"
+        );
+
+        for date in [
+            "2024-06-02",
+            "2024-13-01",
+            "2024-1-01",
+            "24-01-01",
+            "2024/01/01",
+        ] {
+            assert!(
+                matches!(
+                    policy.check(&deployment(Some(date), &[])),
+                    Err(ServerlessError::CompatibilityDateNotAllowed(x)) if x == date
+                ),
+                "{date}"
+            );
+        }
+        assert!(matches!(
+            policy.check(&deployment(None, &["nodejs_compat", "streams_enable_constructors"])),
+            Err(ServerlessError::CompatibilityFlagNotAllowed(x)) if x == "streams_enable_constructors"
+        ));
+    }
+
+    #[actix_web::test]
+    async fn config_file_settings_test() {
+        let dir = std::env::temp_dir().join(format!("oyster-runtime-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let runtime_path = dir.to_str().unwrap();
+
+        let key = k256::SecretKey::random(&mut rand::rngs::OsRng);
+        let config = deployment(Some("2024-01-15"), &["nodejs_compat"])
+            .into_config(
+                &key,
+                &policy(),
+                &EgressPolicy {
+                    mode: EgressMode::Public,
+                    rules: vec![],
+                },
+            )
+            .unwrap();
+        workerd::create_config_file(&config, "0xaa", "slug", runtime_path, 11001)
+            .await
+            .unwrap();
+
+        let config = std::fs::read_to_string(dir.join("0xaa-slug.capnp")).unwrap();
+        assert!(config.contains("compatibilityDate = \"2024-01-15\""));
+        assert!(config.contains("compatibilityFlags = [ \"nodejs_compat\" ]"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+pub mod egresstest {
+    use crate::bundle;
+    use crate::egress::{EgressMode, EgressPolicy, EgressRule};
+    use crate::workerd::{
+        self, Binding, BindingValue, CompatibilityPolicy, Deployment, Module, ModuleKind,
+        ServerlessError,
+    };
+
+    fn rules(rules: &[&str]) -> Vec<EgressRule> {
+        rules.iter().map(|x| x.parse().unwrap()).collect()
+    }
+
+    fn deployment(egress: &[&str]) -> Deployment {
+        Deployment {
+            modules: vec![Module {
+                name: "main".to_owned(),
+                kind: ModuleKind::EsModule,
+                content: b"export default {}".to_vec(),
+            }],
+            egress: rules(egress),
+            ..Default::default()
+        }
+    }
+
+    fn compatibility() -> CompatibilityPolicy {
+        CompatibilityPolicy {
+            default_date: "2023-03-07".to_owned(),
+            max_date: "2023-03-07".to_owned(),
+            allowed_flags: vec![],
+        }
+    }
+
+    #[test]
+    fn parse_rule_test() {
+        assert_eq!(
+            "10.0.0.0/8".parse::<EgressRule>().unwrap(),
+            EgressRule::Network("10.0.0.0/8".parse().unwrap())
+        );
+        assert_eq!(
+            "1.2.3.4".parse::<EgressRule>().unwrap(),
+            EgressRule::Network("1.2.3.4/32".parse().unwrap())
+        );
+        assert_eq!(
+            "API=https://API.example.com/"
+                .parse::<EgressRule>()
+                .unwrap(),
+            EgressRule::Host {
+                binding: "API".to_owned(),
+                https: true,
+                host: "api.example.com".to_owned(),
+                port: 443,
+            }
+        );
+        assert_eq!(
+            "db=http://db.internal:8080".parse::<EgressRule>().unwrap(),
+            EgressRule::Host {
+                binding: "db".to_owned(),
+                https: false,
+                host: "db.internal".to_owned(),
+                port: 8080,
+            }
+        );
+
+        for rule in [
+            "example.com",
+            "10.0.0.0/33",
+            "API=example.com",
+            "API=ftp://example.com",
+            "API=https://example.com/path",
+            "API=https://example.com:99999",
+            "API=https://",
+            "1API=https://example.com",
+            "API=http://127.0.0.1:6002",
+            "API=http://10.0.0.1",
+            "API=http://localhost:6001",
+            "API=http://api.LOCALHOST",
+        ] {
+            assert!(rule.parse::<EgressRule>().is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn bundle_egress_test() {
+        let deployment = deployment(&["10.0.0.0/8", "API=https://api.example.com:443"]);
+        let parsed = workerd::get_deployment(bundle::encode(&deployment)).unwrap();
+        assert_eq!(parsed, deployment);
+    }
+
+    #[test]
+    fn policy_test() {
+        let requested = rules(&["8.8.0.0/16", "API=https://api.example.com"]);
+
+        let policy = |mode, allowed: &[&str]| EgressPolicy {
+            mode,
+            rules: rules(allowed),
+        };
+        assert_eq!(
+            policy(EgressMode::Public, &[]).resolve(&requested).unwrap(),
+            None
+        );
+        assert_eq!(
+            policy(EgressMode::Deny, &["10.0.0.0/8"])
+                .resolve(&requested)
+                .unwrap(),
+            Some(vec![])
+        );
+        assert_eq!(
+            policy(EgressMode::Allowlist, &["10.0.0.0/8"])
+                .resolve(&requested)
+                .unwrap(),
+            Some(rules(&["10.0.0.0/8"]))
         );
+        assert_eq!(
+            policy(EgressMode::PerFunction, &[])
+                .resolve(&rules(&["8.8.0.0/16"]))
+                .unwrap(),
+            Some(rules(&["8.8.0.0/16"]))
+        );
+        // hosts can resolve to anything, they need an operator rule
+        assert!(matches!(
+            policy(EgressMode::PerFunction, &[]).resolve(&requested),
+            Err(ServerlessError::EgressNotAllowed(x)) if x == "API=https://api.example.com:443"
+        ));
+        // binding names are up to the deployment
+        assert_eq!(
+            policy(
+                EgressMode::PerFunction,
+                &["8.0.0.0/8", "OTHER=https://api.example.com"]
+            )
+            .resolve(&requested)
+            .unwrap(),
+            Some(requested.clone())
+        );
+
+        assert!(matches!(
+            policy(EgressMode::PerFunction, &["8.8.0.0/24", "API=https://api.example.com"])
+                .resolve(&requested),
+            Err(ServerlessError::EgressNotAllowed(x)) if x == "8.8.0.0/16"
+        ));
+        assert!(matches!(
+            policy(EgressMode::PerFunction, &["8.0.0.0/8", "API=http://api.example.com"])
+                .resolve(&requested),
+            Err(ServerlessError::EgressNotAllowed(x)) if x == "API=https://api.example.com:443"
+        ));
+
+        // without operator rules, only public addresses can be requested
+        for rule in [
+            "127.0.0.1",
+            "10.1.0.0/16",
+            "192.168.1.0/24",
+            "169.254.169.254",
+            "0.0.0.0/0",
+            "::1",
+            "::ffff:127.0.0.1",
+            "fd00::/64",
+        ] {
+            assert!(
+                matches!(
+                    policy(EgressMode::PerFunction, &[]).resolve(&rules(&[rule])),
+                    Err(ServerlessError::EgressNotAllowed(_))
+                ),
+                "{rule}"
+            );
+        }
+        // unless the operator allows them
+        assert!(policy(EgressMode::PerFunction, &["10.0.0.0/8"])
+            .resolve(&rules(&["10.1.0.0/16"]))
+            .is_ok());
+    }
+
+    #[test]
+    fn binding_conflict_test() {
+        let mut deployment = deployment(&["API=https://api.example.com"]);
+        deployment.bindings.push(Binding {
+            name: "API".to_owned(),
+            value: BindingValue::Text("x".to_owned()),
+        });
+
+        let key = k256::SecretKey::random(&mut rand::rngs::OsRng);
+        let policy = EgressPolicy {
+            mode: EgressMode::PerFunction,
+            rules: rules(&["API=https://api.example.com"]),
+        };
+        assert!(matches!(
+            deployment.into_config(&key, &compatibility(), &policy),
+            Err(ServerlessError::EgressBindingConflict(x)) if x == "API"
+        ));
+    }
+
+    #[actix_web::test]
+    async fn config_file_egress_test() {
+        let dir = std::env::temp_dir().join(format!("oyster-runtime-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let runtime_path = dir.to_str().unwrap();
+
+        let key = k256::SecretKey::random(&mut rand::rngs::OsRng);
+        let policy = EgressPolicy {
+            mode: EgressMode::PerFunction,
+            rules: rules(&["8.0.0.0/8", "API=https://api.example.com"]),
+        };
+        let config = deployment(&["8.0.0.0/8", "API=https://api.example.com"])
+            .into_config(&key, &compatibility(), &policy)
+            .unwrap();
+        workerd::create_config_file(&config, "0xaa", "slug", runtime_path, 11001)
+            .await
+            .unwrap();
+
+        let config = std::fs::read_to_string(dir.join("0xaa-slug.capnp")).unwrap();
+        assert!(config.contains("(name = \"egress\", network = (allow = [ \"8.0.0.0/8\" ]))"));
+        assert!(config.contains(
+            "(name = \"egress-1\", external = (address = \"api.example.com:443\", https = (certificateHost = \"api.example.com\")))"
+        ));
+        assert!(config.contains("(name = \"API\", service = \"egress-1\")"));
+        assert!(config.contains("globalOutbound = \"egress\""));
+
+        // public egress keeps the default network access
+        let config = deployment(&["10.0.0.0/8"])
+            .into_config(
+                &key,
+                &compatibility(),
+                &EgressPolicy {
+                    mode: EgressMode::Public,
+                    rules: vec![],
+                },
+            )
+            .unwrap();
+        workerd::create_config_file(&config, "0xbb", "slug", runtime_path, 11002)
+            .await
+            .unwrap();
+
+        let config = std::fs::read_to_string(dir.join("0xbb-slug.capnp")).unwrap();
+        assert!(!config.contains("egress"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+pub mod streamingtest {
+    use super::serverlesstest::domain;
+    use crate::verify::{SignatureScheme, SignatureVersion};
+    use crate::workerd;
+    use actix_web::{http, test, web, App, HttpRequest, HttpResponse, HttpServer};
+    use futures::StreamExt;
+    use k256::ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey};
+    use std::time::Duration;
+    use tiny_keccak::{Hasher, Keccak};
+
+    // echoes the request body back, standing in for workerd
+    pub async fn echo_worker() -> (u16, actix_web::dev::ServerHandle) {
+        let server = HttpServer::new(|| {
+            App::new().default_service(web::to(|payload: web::Payload| async move {
+                HttpResponse::Ok().streaming(payload)
+            }))
+        })
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        (port, handle)
+    }
+
+    fn v1() -> SignatureScheme {
+        SignatureScheme::new(
+            SignatureVersion::V1,
+            SignatureVersion::V1,
+            domain(),
+            vec![],
+            vec![],
+        )
+        .unwrap()
+    }
+
+    fn recover(timestamp: u64, request: &[u8], response: &[u8], signature: &[u8]) -> VerifyingKey {
+        let mut hasher = Keccak::v256();
+        hasher.update(b"|oyster-serverless-hasher|");
+        hasher.update(b"|timestamp|");
+        hasher.update(&timestamp.to_be_bytes());
+        hasher.update(b"|request|");
+        hasher.update(b"|method|");
+        hasher.update(b"POST");
+        hasher.update(b"|pathandquery|");
+        hasher.update(b"/echo?x=1");
+        hasher.update(b"|host|");
+        hasher.update(b"echo.example.com");
+        hasher.update(b"|body|");
+        hasher.update(request);
+        hasher.update(b"|response|");
+        hasher.update(b"|body|");
+        hasher.update(response);
+        let mut hash = [0u8; 32];
+        hasher.finalize(&mut hash);
+
+        VerifyingKey::recover_from_prehash(
+            &hash,
+            &Signature::from_slice(&signature[..64]).unwrap(),
+            RecoveryId::from_byte(signature[64] - 27).unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn stream_test() {
+        let (port, handle) = echo_worker().await;
+        let signer = SigningKey::random(&mut rand::rngs::OsRng);
+
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(signer.clone())).route(
+                "/echo",
+                web::post().to(
+                    move |req: HttpRequest,
+                          payload: web::Payload,
+                          signer: web::Data<SigningKey>| async move {
+                        let (mut response, body) = workerd::get_workerd_stream(
+                            port,
+                            req,
+                            payload,
+                            signer.get_ref().clone(),
+                            "echo.example.com",
+                            &v1(),
+                            Duration::from_secs(5),
+                            None,
+                        )
+                        .await
+                        .unwrap();
+                        response.streaming(body)
+                    },
+                ),
+            ))
+            .await;
+
+        let request = vec![7u8; 1 << 20];
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/echo?x=1")
+                .insert_header(("X-Oyster-Stream-Signature", "appended"))
+                .set_payload(request.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert_eq!(resp.headers().get("X-Oyster-Stream").unwrap(), "true");
+        assert_eq!(
+            resp.headers().get("X-Oyster-Stream-Signature").unwrap(),
+            "appended"
+        );
+        assert!(resp.headers().get("X-Oyster-Signature").is_none());
+        let timestamp = resp
+            .headers()
+            .get("X-Oyster-Timestamp")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+
+        // body is followed by the signature
+        let body = test::read_body(resp).await;
+        assert_eq!(body.len(), request.len() + 65);
+        let (response, signature) = body.split_at(request.len());
+        assert_eq!(response, request);
+        assert_eq!(
+            recover(timestamp, &request, response, signature),
+            *signer.verifying_key()
+        );
+
+        // without opting in, the body is passed through untouched
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/echo?x=1")
+                .set_payload(request.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert!(resp.headers().get("X-Oyster-Stream-Signature").is_none());
+        assert_eq!(test::read_body(resp).await, request);
+
+        handle.stop(false).await;
+    }
+
+    async fn next_chunk(
+        body: &mut (impl futures::Stream<Item = reqwest::Result<web::Bytes>> + Unpin),
+    ) -> Option<web::Bytes> {
+        tokio::time::timeout(Duration::from_secs(2), body.next())
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+    }
+
+    #[actix_web::test]
+    async fn stalled_upload_test() {
+        // responds without waiting for the request body
+        let worker = HttpServer::new(|| {
+            App::new().default_service(web::to(|| async { HttpResponse::Ok().body("done") }))
+        })
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = worker.addrs()[0].port();
+        let worker = worker.run();
+        let worker_handle = worker.handle();
+        actix_web::rt::spawn(worker);
+
+        let signer = SigningKey::random(&mut rand::rngs::OsRng);
+        let server = HttpServer::new(move || {
+            let signer = signer.clone();
+            App::new().default_service(web::to(move |req: HttpRequest, payload: web::Payload| {
+                let signer = signer.clone();
+                async move {
+                    let (mut response, body) = workerd::get_workerd_stream(
+                        port,
+                        req,
+                        payload,
+                        signer,
+                        "echo.example.com",
+                        &v1(),
+                        Duration::from_millis(200),
+                        None,
+                    )
+                    .await
+                    .unwrap();
+                    response.streaming(body)
+                }
+            }))
+        })
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let server_port = server.addrs()[0].port();
+        let server = server.run();
+        let server_handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        // the upload is never finished, the signature can never be sent
+        let (mut tx, rx) = futures::channel::mpsc::channel::<Result<Vec<u8>, std::io::Error>>(1);
+        tx.try_send(Ok(b"first".to_vec())).unwrap();
+        let response = reqwest::Client::new()
+            .post(format!("http://127.0.0.1:{server_port}/"))
+            .header("X-Oyster-Stream-Signature", "appended")
+            .body(reqwest::Body::wrap_stream(rx))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        // the response is cut off instead of waiting for the client forever
+        let mut body = Box::pin(response.bytes_stream());
+        let mut received = Vec::new();
+        let failed = tokio::time::timeout(Duration::from_secs(2), async {
+            while let Some(chunk) = body.next().await {
+                match chunk {
+                    Ok(chunk) => received.extend_from_slice(&chunk),
+                    Err(_) => return true,
+                }
+            }
+            false
+        })
+        .await
+        .unwrap();
+        assert!(failed);
+        assert_eq!(received, b"done");
+        drop(tx);
+
+        server_handle.stop(false).await;
+        worker_handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn response_before_upload_test() {
+        let (port, worker_handle) = echo_worker().await;
+        let signer = SigningKey::random(&mut rand::rngs::OsRng);
+
+        let server_signer = signer.clone();
+        let server = HttpServer::new(move || {
+            let signer = server_signer.clone();
+            App::new().default_service(web::to(move |req: HttpRequest, payload: web::Payload| {
+                let signer = signer.clone();
+                async move {
+                    let (mut response, body) = workerd::get_workerd_stream(
+                        port,
+                        req,
+                        payload,
+                        signer,
+                        "echo.example.com",
+                        &v1(),
+                        Duration::from_secs(5),
+                        None,
+                    )
+                    .await
+                    .unwrap();
+                    response.streaming(body)
+                }
+            }))
+        })
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let server_port = server.addrs()[0].port();
+        let server = server.run();
+        let server_handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        for signed in [false, true] {
+            // each chunk is echoed back while the upload is still open
+            let (mut tx, rx) =
+                futures::channel::mpsc::channel::<Result<Vec<u8>, std::io::Error>>(1);
+            tx.try_send(Ok(b"first".to_vec())).unwrap();
+            let mut request = reqwest::Client::new()
+                .post(format!("http://127.0.0.1:{server_port}/echo?x=1"))
+                .body(reqwest::Body::wrap_stream(rx));
+            if signed {
+                request = request.header("X-Oyster-Stream-Signature", "appended");
+            }
+            let response = tokio::time::timeout(Duration::from_secs(2), request.send())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            let timestamp = response.headers()["X-Oyster-Timestamp"]
+                .to_str()
+                .unwrap()
+                .parse::<u64>()
+                .unwrap();
+
+            let mut body = Box::pin(response.bytes_stream());
+            assert_eq!(next_chunk(&mut body).await.unwrap().as_ref(), b"first");
+            tx.try_send(Ok(b"second".to_vec())).unwrap();
+            assert_eq!(next_chunk(&mut body).await.unwrap().as_ref(), b"second");
+            drop(tx);
+
+            // the signature covers the whole exchange, so it is only sent once the upload is done
+            let mut rest = Vec::new();
+            while let Some(chunk) = next_chunk(&mut body).await {
+                rest.extend_from_slice(&chunk);
+            }
+            match signed {
+                false => assert!(rest.is_empty()),
+                true => assert_eq!(
+                    recover(timestamp, b"firstsecond", b"firstsecond", &rest),
+                    *signer.verifying_key()
+                ),
+            }
+        }
+
+        server_handle.stop(false).await;
+        worker_handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn buffered_matches_stream_test() {
+        let (port, handle) = echo_worker().await;
+        let signer = SigningKey::random(&mut rand::rngs::OsRng);
+
+        let req = test::TestRequest::post().uri("/echo?x=1").to_http_request();
+        let resp = workerd::get_workerd_response(
+            port,
+            req,
+            web::Bytes::from_static(b"hello"),
+            &signer,
+            "echo.example.com",
+            &v1(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let timestamp = resp
+            .headers()
+            .get("X-Oyster-Timestamp")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let signature = hex::decode(resp.headers().get("X-Oyster-Signature").unwrap()).unwrap();
+
+        // same layout as streamed responses
+        assert_eq!(
+            recover(timestamp.parse().unwrap(), b"hello", b"hello", &signature),
+            *signer.verifying_key()
+        );
+
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn reserved_headers_test() {
+        // a worker trying to pass off its own headers as the node's
+        let server = HttpServer::new(|| {
+            App::new().default_service(web::to(|| async {
+                HttpResponse::Ok()
+                    .insert_header(("X-Oyster-Error", "spoofed"))
+                    .insert_header(("X-Oyster-Stream-Usage", "spoofed"))
+                    .insert_header(("X-Oyster-Signature", "spoofed"))
+                    .insert_header(("X-Custom", "kept"))
+                    .body("hello")
+            }))
+        })
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        let signer = SigningKey::random(&mut rand::rngs::OsRng);
+
+        let req = test::TestRequest::post().uri("/echo?x=1").to_http_request();
+        let resp = workerd::get_workerd_response(
+            port,
+            req,
+            web::Bytes::from_static(b"hello"),
+            &signer,
+            "echo.example.com",
+            &v1(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(resp.headers().get("X-Oyster-Error").is_none());
+        assert!(resp.headers().get("X-Oyster-Stream-Usage").is_none());
+        assert_eq!(resp.headers().get("X-Custom").unwrap(), "kept");
+        let signatures = resp.headers().get_all("X-Oyster-Signature").count();
+        assert_eq!(signatures, 1);
+        assert_ne!(resp.headers().get("X-Oyster-Signature").unwrap(), "spoofed");
+
+        handle.stop(false).await;
+    }
+}
+
+#[cfg(test)]
+pub mod verifytest {
+    use super::serverlesstest::{app_state, domain};
+    use super::streamingtest::echo_worker;
+    use crate::cgroups::Cgroups;
+    use crate::error::{ErrorBody, ErrorCode};
+    use crate::handler;
+    use crate::verify::{
+        self, Eip712Domain, Exchange, MessageHasher, SignatureScheme, SignatureVersion, VerifyError,
+    };
+    use crate::workerd;
+    use actix_web::body::MessageBody;
+    use actix_web::test::TestRequest;
+    use actix_web::{http, web, App, HttpResponse};
+    use k256::ecdsa::SigningKey;
+    use k256::elliptic_curve::generic_array::sequence::Lengthen;
+    use tiny_keccak::{Hasher, Keccak};
+
+    const REQUEST: &[u8] =
+        b"POST /factors?x=1 HTTP/1.1\r\nHost: abcd.localhost:6000\r\nContent-Length: 10\r\n\r\n{\"num\":10}";
+
+    // save the response the way a client would
+    pub fn save_response(resp: HttpResponse) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {}\r\n", resp.status()).into_bytes();
+        for (name, value) in resp.headers() {
+            response.extend_from_slice(name.as_str().as_bytes());
+            response.extend_from_slice(b": ");
+            response.extend_from_slice(value.as_bytes());
+            response.extend_from_slice(b"\r\n");
+        }
+        response.extend_from_slice(b"\r\n");
+        response.extend_from_slice(&resp.into_body().try_into_bytes().unwrap());
+
+        response
+    }
+
+    fn signature(signer: &SigningKey, timestamp: u64, response_body: &[u8]) -> Vec<u8> {
+        let mut hasher = MessageHasher::new(
+            SignatureVersion::V1,
+            &domain(),
+            timestamp,
+            None,
+            "POST",
+            "/factors?x=1",
+            "abcd.localhost:6000",
+            b"",
+        );
+        hasher.request_body(b"{\"num\":10}");
+        hasher.response(200, b"");
+        hasher.response_body(response_body);
+        let (rs, v) = signer.sign_prehash_recoverable(&hasher.finalize()).unwrap();
+
+        rs.to_bytes().append(27 + v.to_byte()).to_vec()
+    }
+
+    #[test]
+    fn address_test() {
+        let key = SigningKey::from_slice(&[[0u8; 31].as_slice(), &[1]].concat()).unwrap();
+        assert_eq!(
+            verify::address(key.verifying_key()),
+            "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf"
+        );
+    }
+
+    #[test]
+    fn exchange_test() {
+        let signer = SigningKey::random(&mut rand::rngs::OsRng);
+        let address = verify::address(signer.verifying_key());
+        let signature = signature(&signer, 1700000000, b"[2,5]");
+
+        let response = [
+            b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\nx-oyster-timestamp: 1700000000\r\nx-oyster-signature: ".as_slice(),
+            hex::encode(&signature).as_bytes(),
+            b"\r\n\r\n[2,5]",
+        ]
+        .concat();
+        let exchange = Exchange::parse(REQUEST, &response).unwrap();
+        assert_eq!(exchange.method, "POST");
+        assert_eq!(exchange.path_and_query, "/factors?x=1");
+        assert_eq!(exchange.host, "abcd.localhost:6000");
+        assert_eq!(exchange.response_body, b"[2,5]");
+        exchange
+            .verify(
+                &address.to_uppercase().replace("0X", "0x"),
+                SignatureVersion::V1,
+                &domain(),
+            )
+            .unwrap();
+
+        // signed streamed responses carry the signature at the end of the body
+        let response = [
+            b"HTTP/1.1 200 OK\r\nx-oyster-stream: true\r\nx-oyster-stream-signature: appended\r\nx-oyster-timestamp: 1700000000\r\n\r\n[2,5]".as_slice(),
+            &signature,
+        ]
+        .concat();
+        let streamed = Exchange::parse(REQUEST, &response).unwrap();
+        assert_eq!(streamed, exchange);
+
+        // unsigned ones have nothing to verify
+        let response = b"HTTP/1.1 200 OK\r\nx-oyster-stream: true\r\nx-oyster-timestamp: 1700000000\r\n\r\n[2,5]";
+        assert!(matches!(
+            Exchange::parse(REQUEST, response),
+            Err(VerifyError::MissingHeader("X-Oyster-Signature"))
+        ));
+
+        let other = verify::address(SigningKey::random(&mut rand::rngs::OsRng).verifying_key());
+        assert!(matches!(
+            exchange.verify(&other, SignatureVersion::V1, &domain()),
+            Err(VerifyError::SignerMismatch(x, y)) if x == address && y == other
+        ));
+
+        let mut tampered = exchange.clone();
+        tampered.response_body = b"[2,7]".to_vec();
+        assert!(tampered
+            .verify(&address, SignatureVersion::V1, &domain())
+            .is_err());
+    }
+
+    #[test]
+    fn invalid_exchange_test() {
+        assert!(matches!(
+            Exchange::parse(b"POST / HTTP/1.1\r\n\r\n", b"HTTP/1.1 200 OK\r\n\r\n"),
+            Err(VerifyError::MissingHeader("Host"))
+        ));
+        assert!(matches!(
+            Exchange::parse(REQUEST, b"HTTP/1.1 200 OK\r\n\r\n"),
+            Err(VerifyError::MissingHeader("X-Oyster-Timestamp"))
+        ));
+        assert!(matches!(
+            Exchange::parse(REQUEST, b"HTTP/1.1 200 OK\r\nX-Oyster-Timestamp: 1\r\n\r\n"),
+            Err(VerifyError::MissingHeader("X-Oyster-Signature"))
+        ));
+        assert!(matches!(
+            Exchange::parse(
+                REQUEST,
+                b"HTTP/1.1 200 OK\r\nX-Oyster-Timestamp: 1\r\nX-Oyster-Signature: zz\r\n\r\n"
+            ),
+            Err(VerifyError::InvalidHeader("X-Oyster-Signature"))
+        ));
+        assert!(matches!(
+            verify::recover(&[0; 32], &[0; 64]),
+            Err(VerifyError::SignatureLength)
+        ));
+    }
+
+    #[test]
+    fn canonical_headers_test() {
+        let scheme = SignatureScheme::new(
+            SignatureVersion::V2,
+            SignatureVersion::V1,
+            domain(),
+            vec![
+                "X-B".to_owned(),
+                "content-type".to_owned(),
+                "x-a".to_owned(),
+                "X-a".to_owned(),
+            ],
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(scheme.request_headers(), ["content-type", "x-a", "x-b"]);
+
+        let headers = [
+            ("X-A", b" 1 ".as_slice()),
+            ("Content-Type", b"application/json"),
+            ("x-a", b"2"),
+        ];
+        assert_eq!(
+            verify::canonical_headers(scheme.request_headers(), headers),
+            b"content-type:application/json\nx-a:1, 2\nx-b\n"
+        );
+
+        for name in ["Content-Length", "transfer-encoding", "bad header", ""] {
+            assert!(matches!(
+                SignatureScheme::new(
+                    SignatureVersion::V2,
+                    SignatureVersion::V1,
+                    domain(),
+                    vec![],
+                    vec![name.to_owned()]
+                ),
+                Err(VerifyError::UnsignableHeader(_))
+            ));
+        }
+    }
+
+    #[actix_web::test]
+    async fn v2_exchange_test() {
+        let (port, handle) = echo_worker().await;
+        let signer = SigningKey::random(&mut rand::rngs::OsRng);
+        let address = verify::address(signer.verifying_key());
+        let scheme = SignatureScheme::new(
+            SignatureVersion::V2,
+            SignatureVersion::V1,
+            domain(),
+            vec!["Content-Type".to_owned()],
+            vec!["content-type".to_owned(), "location".to_owned()],
+        )
+        .unwrap();
+
+        let req = TestRequest::post()
+            .uri("/factors?x=1")
+            .insert_header(("Host", "abcd.localhost:6000"))
+            .insert_header(("Content-Type", "application/json"))
+            .to_http_request();
+        let resp = workerd::get_workerd_response(
+            port,
+            req,
+            actix_web::web::Bytes::from_static(b"{\"num\":10}"),
+            &signer,
+            "abcd.localhost:6000",
+            &scheme,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            resp.headers()
+                .get("X-Oyster-Signed-Request-Headers")
+                .unwrap(),
+            "content-type"
+        );
+        assert_eq!(
+            resp.headers()
+                .get("X-Oyster-Signed-Response-Headers")
+                .unwrap(),
+            "content-type, location"
+        );
+
+        let response = save_response(resp);
+        let request = b"POST /factors?x=1 HTTP/1.1\r\nHost: abcd.localhost:6000\r\nContent-Type: application/json\r\n\r\n{\"num\":10}";
+
+        let exchange = Exchange::parse(request, &response).unwrap();
+        assert_eq!(exchange.version, SignatureVersion::V2);
+        assert_eq!(exchange.status, 200);
+        exchange
+            .verify(&address, SignatureVersion::V1, &domain())
+            .unwrap();
+
+        // status, signed headers and the list of signed headers are all covered
+        let mut tampered = exchange.clone();
+        tampered.status = 500;
+        assert!(tampered
+            .verify(&address, SignatureVersion::V1, &domain())
+            .is_err());
+
+        let tampered = String::from_utf8(response.clone())
+            .unwrap()
+            .replace("\r\n\r\n", "\r\nlocation: /elsewhere\r\n\r\n");
+        let tampered = Exchange::parse(request, tampered.as_bytes()).unwrap();
+        assert!(tampered
+            .verify(&address, SignatureVersion::V1, &domain())
+            .is_err());
+
+        let tampered = String::from_utf8(response.clone())
+            .unwrap()
+            .replace("content-type, location", "content-type");
+        let tampered = Exchange::parse(request, tampered.as_bytes()).unwrap();
+        assert!(tampered
+            .verify(&address, SignatureVersion::V1, &domain())
+            .is_err());
+
+        let request = b"POST /factors?x=1 HTTP/1.1\r\nHost: abcd.localhost:6000\r\nContent-Type: text/plain\r\n\r\n{\"num\":10}";
+        let tampered = Exchange::parse(request, &response).unwrap();
+        assert!(tampered
+            .verify(&address, SignatureVersion::V1, &domain())
+            .is_err());
+
+        handle.stop(false).await;
+    }
+
+    #[test]
+    fn eip712_test() {
+        let keccak256 = |data: &[u8]| {
+            let mut hasher = Keccak::v256();
+            hasher.update(data);
+            let mut hash = [0u8; 32];
+            hasher.finalize(&mut hash);
+            hash
+        };
+        let word = |value: u64| [[0u8; 24].as_slice(), &value.to_be_bytes()].concat();
+
+        let mut hasher = MessageHasher::new(
+            SignatureVersion::Eip712,
+            &domain(),
+            1700000000,
+            None,
+            "POST",
+            "/factors?x=1",
+            "abcd.localhost:6000",
+            b"content-type\n",
+        );
+        hasher.request_body(b"{\"num\"");
+        hasher.request_body(b":10}");
+        hasher.response(200, b"content-type:application/json\n");
+        hasher.response_body(b"[2,");
+        hasher.response_body(b"5]");
+
+        let separator = keccak256(
+            &[
+                keccak256(b"EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)").to_vec(),
+                keccak256(b"Oyster Serverless").to_vec(),
+                keccak256(b"1").to_vec(),
+                word(421614),
+                [[0u8; 12].as_slice(), &hex::decode("44fe06d2940b8782a0a9a9ffd09c65852c0156b1").unwrap()].concat(),
+            ]
+            .concat(),
+        );
+        assert_eq!(domain().separator(), separator);
+        let struct_hash = keccak256(
+            &[
+                keccak256(b"OysterResponse(uint64 timestamp,bool hasNonce,bytes nonce,string method,string pathAndQuery,string host,bytes requestHeaders,bytes requestBody,uint16 status,bytes responseHeaders,bytes responseBody)").to_vec(),
+                word(1700000000),
+                word(0),
+                keccak256(b"").to_vec(),
+                keccak256(b"POST").to_vec(),
+                keccak256(b"/factors?x=1").to_vec(),
+                keccak256(b"abcd.localhost:6000").to_vec(),
+                keccak256(b"content-type\n").to_vec(),
+                keccak256(b"{\"num\":10}").to_vec(),
+                word(200),
+                keccak256(b"content-type:application/json\n").to_vec(),
+                keccak256(b"[2,5]").to_vec(),
+            ]
+            .concat(),
+        );
+        let digest = keccak256(&[b"\x19\x01".as_slice(), &separator, &struct_hash].concat());
+        assert_eq!(hasher.finalize(), digest);
+
+        // an empty nonce is not the same as a missing one
+        let hash = |nonce: Option<&[u8]>| {
+            MessageHasher::new(
+                SignatureVersion::Eip712,
+                &domain(),
+                1700000000,
+                nonce,
+                "POST",
+                "/",
+                "abcd.localhost:6000",
+                b"",
+            )
+            .finalize()
+        };
+        assert_ne!(hash(None), hash(Some(b"")));
+
+        // verifiers pick the layout from the version header
+        let signer = SigningKey::random(&mut rand::rngs::OsRng);
+        let (rs, v) = signer.sign_prehash_recoverable(&digest).unwrap();
+        let signature = rs.to_bytes().append(27 + v.to_byte());
+        let request =
+            b"POST /factors?x=1 HTTP/1.1\r\nHost: abcd.localhost:6000\r\n\r\n{\"num\":10}";
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nX-Oyster-Timestamp: 1700000000\r\nX-Oyster-Signature: {}\r\nX-Oyster-Signature-Version: eip712\r\nX-Oyster-Signed-Request-Headers: content-type\r\nX-Oyster-Signed-Response-Headers: content-type\r\n\r\n[2,5]",
+            hex::encode(signature)
+        );
+        let exchange = Exchange::parse(request, response.as_bytes()).unwrap();
+        assert_eq!(exchange.version, SignatureVersion::Eip712);
+        exchange
+            .verify(
+                &verify::address(signer.verifying_key()),
+                SignatureVersion::Eip712,
+                &domain(),
+            )
+            .unwrap();
+
+        // the signature is bound to the chain and contract
+        let other = Eip712Domain::new(1, "0x44fe06d2940b8782a0a9a9ffd09c65852c0156b1").unwrap();
+        assert!(exchange
+            .verify(
+                &verify::address(signer.verifying_key()),
+                SignatureVersion::Eip712,
+                &other,
+            )
+            .is_err());
+
+        assert!(matches!(
+            Exchange::parse(
+                request,
+                response.replace("Version: eip712", "Version: 3").as_bytes()
+            ),
+            Err(VerifyError::InvalidHeader("X-Oyster-Signature-Version"))
+        ));
+        assert!(matches!(
+            Exchange::parse(
+                request,
+                response
+                    .replace("X-Oyster-Signed-Response-Headers", "X-Other")
+                    .as_bytes()
+            ),
+            Err(VerifyError::MissingHeader(
+                "X-Oyster-Signed-Response-Headers"
+            ))
+        ));
+    }
+
+    #[test]
+    fn signature_version_test() {
+        for version in [
+            SignatureVersion::V1,
+            SignatureVersion::V2,
+            SignatureVersion::Eip712,
+        ] {
+            assert_eq!(
+                version.to_string().parse::<SignatureVersion>().unwrap(),
+                version
+            );
+        }
+        assert!("3".parse::<SignatureVersion>().is_err());
+
+        assert!(SignatureVersion::V1 < SignatureVersion::V2);
+        assert!(SignatureVersion::V2 < SignatureVersion::Eip712);
+        let scheme = SignatureScheme::new(
+            SignatureVersion::V2,
+            SignatureVersion::V2,
+            domain(),
+            vec![],
+            vec![],
+        )
+        .unwrap();
+        assert!(matches!(
+            scheme.with_version(SignatureVersion::V1),
+            Err(VerifyError::VersionBelowMinimum(
+                SignatureVersion::V1,
+                SignatureVersion::V2
+            ))
+        ));
+        assert_eq!(
+            scheme
+                .with_version(SignatureVersion::Eip712)
+                .unwrap()
+                .version,
+            SignatureVersion::Eip712
+        );
+        assert!(SignatureScheme::new(
+            SignatureVersion::V1,
+            SignatureVersion::V2,
+            domain(),
+            vec![],
+            vec![]
+        )
+        .is_err());
+    }
+
+    #[actix_web::test]
+    async fn min_version_test() {
+        let mut appstate = app_state(Cgroups { free: vec![] });
+        appstate.signature.min_version = SignatureVersion::V2;
+        let address = verify::address(appstate.signer.verifying_key());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(appstate))
+                .default_service(web::to(handler::serverless)),
+        )
+        .await;
+
+        // clients cannot downgrade the signature below the minimum of the node
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header((
+                "Host",
+                "SRULW2UOQXWRDYUSZDFMBQKTTX3JDSGY5RROPW72T4N5P5IE4RXA.oyster.run",
+            ))
+            .insert_header(("X-Oyster-Signature-Version", "1"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        assert_eq!(
+            resp.headers().get("X-Oyster-Signature-Version").unwrap(),
+            "2"
+        );
+        let response = save_response(resp.map_into_boxed_body().into_parts().1);
+        let body = &response[response.windows(4).position(|x| x == b"\r\n\r\n").unwrap() + 4..];
+        let body: ErrorBody = serde_json::from_slice(body).unwrap();
+        assert_eq!(body.code, ErrorCode::InvalidSignatureVersion);
+
+        let request = b"POST / HTTP/1.1\r\nHost: SRULW2UOQXWRDYUSZDFMBQKTTX3JDSGY5RROPW72T4N5P5IE4RXA.oyster.run\r\n\r\n";
+        let exchange = Exchange::parse(request, &response).unwrap();
+        exchange
+            .verify(&address, SignatureVersion::V2, &domain())
+            .unwrap();
+
+        // verifiers do not take the version claimed by the response at face value
+        let downgraded = Exchange {
+            version: SignatureVersion::V1,
+            ..exchange
+        };
+        assert!(matches!(
+            downgraded.verify(&address, SignatureVersion::V2, &domain()),
+            Err(VerifyError::VersionBelowMinimum(
+                SignatureVersion::V1,
+                SignatureVersion::V2
+            ))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn nonce_test() {
+        let (port, handle) = echo_worker().await;
+        let signer = SigningKey::random(&mut rand::rngs::OsRng);
+        let address = verify::address(signer.verifying_key());
+
+        for version in [
+            SignatureVersion::V1,
+            SignatureVersion::V2,
+            SignatureVersion::Eip712,
+        ] {
+            let scheme =
+                SignatureScheme::new(version, SignatureVersion::V1, domain(), vec![], vec![])
+                    .unwrap();
+            let req = TestRequest::post()
+                .uri("/")
+                .insert_header(("Host", "abcd.localhost:6000"))
+                .insert_header(("X-Oyster-Nonce", "challenge-1"))
+                .to_http_request();
+            let resp = workerd::get_workerd_response(
+                port,
+                req,
+                actix_web::web::Bytes::from_static(b"hello"),
+                &signer,
+                "abcd.localhost:6000",
+                &scheme,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(resp.headers().get("X-Oyster-Nonce").unwrap(), "challenge-1");
+            let response = save_response(resp);
+
+            let request = b"POST / HTTP/1.1\r\nHost: abcd.localhost:6000\r\nX-Oyster-Nonce: challenge-1\r\n\r\nhello";
+            let exchange = Exchange::parse(request, &response).unwrap();
+            assert_eq!(exchange.nonce.as_deref(), Some(b"challenge-1".as_slice()));
+            exchange
+                .verify(&address, SignatureVersion::V1, &domain())
+                .unwrap();
+
+            // the response cannot be passed off as the answer to another challenge
+            let request = b"POST / HTTP/1.1\r\nHost: abcd.localhost:6000\r\nX-Oyster-Nonce: challenge-2\r\n\r\nhello";
+            let exchange = Exchange::parse(request, &response).unwrap();
+            assert!(
+                exchange
+                    .verify(&address, SignatureVersion::V1, &domain())
+                    .is_err(),
+                "{version}"
+            );
+
+            let request = b"POST / HTTP/1.1\r\nHost: abcd.localhost:6000\r\n\r\nhello";
+            let exchange = Exchange::parse(request, &response).unwrap();
+            assert!(
+                exchange
+                    .verify(&address, SignatureVersion::V1, &domain())
+                    .is_err(),
+                "{version}"
+            );
+        }
+
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn error_test() {
+        let appstate = app_state(Cgroups { free: vec![] });
+        let address = verify::address(appstate.signer.verifying_key());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(appstate))
+                .default_service(web::to(handler::serverless)),
+        )
+        .await;
+
+        for version in ["1", "2", "eip712"] {
+            // the host is not a valid tx hash
+            let req = TestRequest::post()
+                .uri("/factors?x=1")
+                .insert_header(("Host", "abcd!.localhost:6000"))
+                .insert_header(("X-Oyster-Nonce", "challenge-1"))
+                .insert_header(("X-Oyster-Signature-Version", version))
+                .set_payload("{\"num\":10}")
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+            assert_eq!(resp.headers().get("X-Oyster-Error").unwrap(), "true");
+            assert_eq!(resp.headers().get("X-Oyster-Nonce").unwrap(), "challenge-1");
+            let response = save_response(resp.map_into_boxed_body().into_parts().1);
+
+            // the request body is signed even though the request was refused before reading it
+            let request = b"POST /factors?x=1 HTTP/1.1\r\nHost: abcd!.localhost:6000\r\nX-Oyster-Nonce: challenge-1\r\n\r\n{\"num\":10}";
+            let exchange = Exchange::parse(request, &response).unwrap();
+            assert!(exchange.error);
+            assert!(exchange.request_body_signed);
+            assert_eq!(exchange.version.to_string(), version);
+            exchange
+                .verify(&address, SignatureVersion::V1, &domain())
+                .unwrap();
+
+            let request = b"POST /factors?x=1 HTTP/1.1\r\nHost: abcd!.localhost:6000\r\nX-Oyster-Nonce: challenge-1\r\n\r\n{\"num\":11}";
+            let exchange = Exchange::parse(request, &response).unwrap();
+            assert!(
+                exchange
+                    .verify(&address, SignatureVersion::V1, &domain())
+                    .is_err(),
+                "{version}"
+            );
+
+            let request = b"POST /factors?x=2 HTTP/1.1\r\nHost: abcd!.localhost:6000\r\nX-Oyster-Nonce: challenge-1\r\n\r\n{\"num\":10}";
+            let exchange = Exchange::parse(request, &response).unwrap();
+            assert!(
+                exchange
+                    .verify(&address, SignatureVersion::V1, &domain())
+                    .is_err(),
+                "{version}"
+            );
+
+            // the error cannot be passed off as a response from the worker
+            let response = String::from_utf8(response)
+                .unwrap()
+                .replace("x-oyster-error: true\r\n", "");
+            assert!(
+                Exchange::parse(request, response.as_bytes())
+                    .and_then(|exchange| exchange.verify(&address, SignatureVersion::V1, &domain()))
+                    .is_err(),
+                "{version}"
+            );
+
+            // streamed requests are not read to sign an error, which says so
+            let req = TestRequest::post()
+                .uri("/factors?x=1")
+                .insert_header(("Host", "abcd!.localhost:6000"))
+                .insert_header(("X-Oyster-Stream", "true"))
+                .insert_header(("X-Oyster-Signature-Version", version))
+                .set_payload("{\"num\":10}")
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(
+                resp.headers().get("X-Oyster-Signed-Request-Body").unwrap(),
+                "false"
+            );
+            let response = save_response(resp.map_into_boxed_body().into_parts().1);
+
+            let request = b"POST /factors?x=1 HTTP/1.1\r\nHost: abcd!.localhost:6000\r\nX-Oyster-Stream: true\r\n\r\n{\"num\":10}";
+            let exchange = Exchange::parse(request, &response).unwrap();
+            assert!(!exchange.request_body_signed);
+            exchange
+                .verify(&address, SignatureVersion::V1, &domain())
+                .unwrap();
+
+            // the marker is signed too
+            let response = String::from_utf8(response)
+                .unwrap()
+                .replace("x-oyster-signed-request-body: false\r\n", "");
+            assert!(
+                Exchange::parse(request, response.as_bytes())
+                    .and_then(|exchange| exchange.verify(&address, SignatureVersion::V1, &domain()))
+                    .is_err(),
+                "{version}"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod identitytest {
+    use super::serverlesstest::app_state;
+    use crate::cgroups::Cgroups;
+    use crate::handler;
+    use crate::model::AppState;
+    use crate::verify;
+    use actix_web::{http, test, web, App, HttpResponse, HttpServer};
+    use serde_json::Value;
+
+    async fn get_identity(appstate: AppState) -> (http::StatusCode, Value) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(appstate))
+                .route("/oyster/identity", web::get().to(handler::identity)),
+        )
+        .await;
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/oyster/identity")
+                .to_request(),
+        )
+        .await;
+        let status = resp.status();
+        let body = test::read_body(resp).await;
+
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[actix_web::test]
+    async fn identity_test() {
+        let appstate = app_state(Cgroups { free: vec![] });
+        let address = verify::address(appstate.signer.verifying_key());
+        let secrets_public_key = format!(
+            "0x{}",
+            hex::encode(appstate.secrets_key.public_key().to_sec1_bytes())
+        );
+
+        let (status, identity) = get_identity(appstate).await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(identity["address"], address);
+        assert_eq!(identity["secrets_public_key"], secrets_public_key);
+        assert!(identity["public_key"].as_str().unwrap().starts_with("0x04"));
+        assert!(identity.get("attestation").is_none());
+    }
+
+    #[actix_web::test]
+    async fn attestation_test() {
+        let mut appstate = app_state(Cgroups { free: vec![] });
+        let public_key = hex::encode(
+            &appstate
+                .signer
+                .verifying_key()
+                .to_encoded_point(false)
+                .as_bytes()[1..],
+        );
+
+        let secrets_public_key = hex::encode(appstate.secrets_key.public_key().to_sec1_bytes());
+
+        // attestation server that only attests to the keys of the node
+        let server = HttpServer::new(move || {
+            let (public_key, secrets_public_key) = (public_key.clone(), secrets_public_key.clone());
+            App::new().route(
+                "/attestation/raw",
+                web::get().to(move |query: web::Query<Vec<(String, String)>>| {
+                    let valid = query.0
+                        == [
+                            ("public_key".to_owned(), public_key.clone()),
+                            ("user_data".to_owned(), secrets_public_key.clone()),
+                        ];
+                    async move {
+                        match valid {
+                            true => HttpResponse::Ok().body(b"\x01\x02".as_slice()),
+                            false => HttpResponse::BadRequest().finish(),
+                        }
+                    }
+                }),
+            )
+        })
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        appstate.attestation_url = Some(format!("http://127.0.0.1:{port}/attestation/raw"));
+        let (status, identity) = get_identity(appstate).await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(identity["attestation"], "0x0102");
+
+        // other keys are refused
+        let mut appstate = app_state(Cgroups { free: vec![] });
+        appstate.attestation_url = Some(format!("http://127.0.0.1:{port}/attestation/raw"));
+        let (status, _) = get_identity(appstate).await;
+        assert_eq!(status, http::StatusCode::BAD_GATEWAY);
+
+        handle.stop(false).await;
+    }
+}
+
+#[cfg(test)]
+pub mod errortest {
+    use super::serverlesstest::app_state;
+    use crate::cgroups::{Cgroups, CgroupsError};
+    use crate::error::{error_response, ErrorBody, ErrorCode};
+    use crate::handler;
+    use crate::workerd::ServerlessError;
+    use actix_web::body::MessageBody;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{http, web, App};
+
+    #[test]
+    fn code_test() {
+        // codes are part of the api and must not change
+        assert_eq!(
+            serde_json::to_string(&ErrorCode::WrongContract).unwrap(),
+            "\"wrong_contract\""
+        );
+        assert_eq!(ErrorCode::NoCapacity.to_string(), "no_capacity");
+
+        let code = ErrorCode::from(&ServerlessError::InvalidTxToValue(
+            "0x01".to_owned(),
+            "0x02".to_owned(),
+        ));
+        assert_eq!(code, ErrorCode::WrongContract);
+        assert_eq!(code.status(), http::StatusCode::BAD_REQUEST);
+        assert!(!code.retryable());
+
+        let code = ErrorCode::from(&ServerlessError::Execute(CgroupsError::NoFree));
+        assert_eq!(code, ErrorCode::NoCapacity);
+        assert_eq!(code.status(), http::StatusCode::TOO_MANY_REQUESTS);
+        assert!(code.retryable());
+
+        let code = ErrorCode::from(&ServerlessError::CodeFileCreate(std::io::Error::other(
+            "disk full",
+        )));
+        assert_eq!(code, ErrorCode::Internal);
+        assert_eq!(code.status(), http::StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(code.retryable());
+    }
+
+    #[test]
+    fn error_response_test() {
+        let err = ServerlessError::InvalidBundle("duplicate module name");
+        let resp = error_response((&err).into(), &err);
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        assert_eq!(
+            resp.headers().get(http::header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+
+        let body: ErrorBody =
+            serde_json::from_slice(&resp.into_body().try_into_bytes().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            ErrorBody {
+                code: ErrorCode::InvalidBundle,
+                message: "invalid bundle: duplicate module name".to_owned(),
+                retryable: false,
+            }
+        );
+    }
+
+    #[actix_web::test]
+    async fn handler_error_test() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(app_state(Cgroups { free: vec![] })))
+                .default_service(web::to(handler::serverless)),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header(("Host", "mfrggza.localhost:6000"))
+            .insert_header(("X-Oyster-Nonce", "a".repeat(257)))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        assert!(resp.headers().contains_key("X-Oyster-Signature"));
+
+        let body: ErrorBody = read_body_json(resp).await;
+        assert_eq!(body.code, ErrorCode::InvalidNonce);
+        assert_eq!(body.message, "nonce is longer than 256 bytes");
+        assert!(!body.retryable);
+    }
+}
+
+#[cfg(test)]
+pub mod admintest {
+    use super::serverlesstest::app_state;
+    use crate::admin::{self, AdminAuth, AdminError, MAX_CLOCK_SKEW};
+    use crate::cache::CodeCache;
+    use crate::cgroups::Cgroups;
+    use crate::drain::{self, InFlight};
+    use crate::egress::EgressMode;
+    use crate::error::{ErrorBody, ErrorCode};
+    use crate::handler;
+    use crate::pool::{Resources, Worker, WorkerPool};
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{http, web, App};
+    use k256::ecdsa::SigningKey;
+    use serde_json::{json, Value};
+    use std::process::Command;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    // signer address of the node under test
+    const NODE: &str = "0x1111111111111111111111111111111111111111";
+
+    fn signed(key: &SigningKey, timestamp: u64, method: &str, path: &str) -> TestRequest {
+        signed_with_nonce(key, timestamp, &admin::nonce(), method, path)
+    }
+
+    fn signed_with_nonce(
+        key: &SigningKey,
+        timestamp: u64,
+        nonce: &str,
+        method: &str,
+        path: &str,
+    ) -> TestRequest {
+        let signature = admin::sign(key, NODE, timestamp, nonce, method, path, b"").unwrap();
+        TestRequest::default()
+            .method(method.parse().unwrap())
+            .uri(path)
+            .insert_header(("X-Oyster-Admin-Timestamp", timestamp.to_string()))
+            .insert_header(("X-Oyster-Admin-Nonce", nonce))
+            .insert_header(("X-Oyster-Admin-Signature", signature))
+    }
+
+    #[test]
+    fn auth_test() {
+        let operator = SigningKey::random(&mut rand::rngs::OsRng);
+        // operator addresses are case insensitive
+        let auth = AdminAuth::new(
+            &crate::verify::address(operator.verifying_key())
+                .to_uppercase()
+                .replace("0X", "0x"),
+            NODE,
+        );
+        let now = now();
+
+        let req = TestRequest::post().uri("/admin/drain").to_http_request();
+        assert!(matches!(
+            auth.check(&req, b"", now),
+            Err(AdminError::MissingHeader("X-Oyster-Admin-Timestamp"))
+        ));
+
+        let req = signed(&operator, now, "POST", "/admin/drain").to_http_request();
+        auth.check(&req, b"", now).unwrap();
+        // the same request cannot be used again
+        assert!(matches!(
+            auth.check(&req, b"", now),
+            Err(AdminError::Replayed)
+        ));
+        // but the same command can be sent again with a fresh nonce
+        let req = signed(&operator, now, "POST", "/admin/drain").to_http_request();
+        auth.check(&req, b"", now).unwrap();
+        // nonces cannot be reused for another command either
+        let req =
+            signed_with_nonce(&operator, now, "abcd", "POST", "/admin/drain").to_http_request();
+        auth.check(&req, b"", now).unwrap();
+        let req =
+            signed_with_nonce(&operator, now, "abcd", "POST", "/admin/undrain").to_http_request();
+        assert!(matches!(
+            auth.check(&req, b"", now),
+            Err(AdminError::Replayed)
+        ));
+
+        let req = TestRequest::post()
+            .uri("/admin/drain")
+            .insert_header(("X-Oyster-Admin-Timestamp", now.to_string()))
+            .to_http_request();
+        assert!(matches!(
+            auth.check(&req, b"", now),
+            Err(AdminError::MissingHeader("X-Oyster-Admin-Nonce"))
+        ));
+        let req = signed_with_nonce(&operator, now, &"a".repeat(65), "POST", "/admin/drain")
+            .to_http_request();
+        assert!(matches!(
+            auth.check(&req, b"", now),
+            Err(AdminError::InvalidHeader("X-Oyster-Admin-Nonce"))
+        ));
+
+        // signatures only cover the request they were made for
+        let req = signed(&operator, now, "POST", "/admin/drain")
+            .uri("/admin/undrain")
+            .to_http_request();
+        assert!(matches!(
+            auth.check(&req, b"", now),
+            Err(AdminError::NotOperator)
+        ));
+        let req = signed(&operator, now, "POST", "/admin/retire-idle").to_http_request();
+        assert!(matches!(
+            auth.check(&req, b"{}", now),
+            Err(AdminError::NotOperator)
+        ));
+        // and the node they were made for
+        let other_node = AdminAuth::new(
+            &crate::verify::address(operator.verifying_key()),
+            "0x2222222222222222222222222222222222222222",
+        );
+        let req = signed(&operator, now, "POST", "/admin/drain").to_http_request();
+        assert!(matches!(
+            other_node.check(&req, b"", now),
+            Err(AdminError::NotOperator)
+        ));
+        auth.check(&req, b"", now).unwrap();
+
+        let other = SigningKey::random(&mut rand::rngs::OsRng);
+        let req = signed(&other, now, "POST", "/admin/drain").to_http_request();
+        assert!(matches!(
+            auth.check(&req, b"", now),
+            Err(AdminError::NotOperator)
+        ));
+
+        let req =
+            signed(&operator, now - MAX_CLOCK_SKEW - 1, "POST", "/admin/drain").to_http_request();
+        assert!(matches!(
+            auth.check(&req, b"", now),
+            Err(AdminError::Expired)
+        ));
+
+        let req = TestRequest::post()
+            .uri("/admin/drain")
+            .insert_header(("X-Oyster-Admin-Timestamp", now.to_string()))
+            .insert_header(("X-Oyster-Admin-Nonce", "abcd"))
+            .insert_header(("X-Oyster-Admin-Signature", "0x1234"))
+            .to_http_request();
+        assert!(matches!(
+            auth.check(&req, b"", now),
+            Err(AdminError::InvalidSignature(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn drain_signal_test() {
+        let mut appstate = app_state(Cgroups { free: vec![] });
+        appstate.worker_pool = Some(WorkerPool::new(Duration::from_secs(60)).into());
+        let appstate = web::Data::new(appstate);
+        let mut resources = Resources::new("0xaa", "1234", "./runtime/", appstate.cgroups.clone());
+        resources.track_cgroup("workerd_1".to_owned());
+        resources.track_child(Command::new("sleep").arg("10").spawn().unwrap());
+        let pid = resources.child().id();
+        appstate
+            .worker_pool
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .put(Worker {
+                port: 11001,
+                last_used: Instant::now(),
+                resources,
+                console: Default::default(),
+            });
+
+        actix_web::rt::spawn(drain::drain_on_signal(appstate.clone()));
+        // let the task install the handler before the signal is sent
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+        assert!(!drain::drained(&appstate));
+
+        let status = Command::new("kill")
+            .args(["-USR1", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        // idle warm workers are retired along with the drain
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            drain::wait_drained(appstate.clone()),
+        )
+        .await
+        .unwrap();
+        let start = Instant::now();
+        while appstate.cgroups.lock().unwrap().free.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            actix_web::rt::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(appstate
+            .worker_pool
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .is_empty());
+        assert_eq!(appstate.cgroups.lock().unwrap().free, vec!["workerd_1"]);
+        assert!(!std::path::Path::new(&format!("/proc/{pid}")).exists());
+    }
+
+    #[actix_web::test]
+    async fn drain_test() {
+        let operator = SigningKey::random(&mut rand::rngs::OsRng);
+        let appstate = web::Data::new(app_state(Cgroups { free: vec![] }));
+        let auth = web::Data::new(AdminAuth::new(
+            &crate::verify::address(operator.verifying_key()),
+            NODE,
+        ));
+        let admin_app = init_service(
+            App::new()
+                .app_data(appstate.clone())
+                .app_data(auth)
+                .route("/admin/status", web::get().to(admin::status))
+                .route("/admin/drain", web::post().to(admin::drain))
+                .route("/admin/undrain", web::post().to(admin::undrain)),
+        )
+        .await;
+        let app = init_service(
+            App::new()
+                .app_data(appstate.clone())
+                .default_service(web::to(handler::serverless)),
+        )
+        .await;
+        let execute = |host: &str| {
+            TestRequest::post()
+                .uri("/")
+                .insert_header(("Host", host.to_owned() + ".oyster.run"))
+                .to_request()
+        };
+
+        // the public port cannot drain the server anymore
+        let resp = call_service(&app, execute("unregister")).await;
+        let body: ErrorBody = read_body_json(resp).await;
+        assert_eq!(body.code, ErrorCode::InvalidTxHash);
+
+        // unsigned admin requests are refused
+        let resp = call_service(
+            &admin_app,
+            TestRequest::post().uri("/admin/drain").to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+        let body: ErrorBody = read_body_json(resp).await;
+        assert_eq!(body.code, ErrorCode::Unauthorized);
+
+        let status: Value = read_body_json(
+            call_service(
+                &admin_app,
+                signed(&operator, now(), "GET", "/admin/status").to_request(),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(
+            status,
+            json!({"running": true, "in_flight": 0, "drained": false})
+        );
+
+        // an execution that is still running holds up the drain
+        let in_flight = InFlight::new(appstate.clone());
+        let status: Value = read_body_json(
+            call_service(
+                &admin_app,
+                signed(&operator, now(), "POST", "/admin/drain").to_request(),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(
+            status,
+            json!({"running": false, "in_flight": 1, "drained": false})
+        );
+
+        // new executions are refused while draining
+        let resp = call_service(&app, execute("mfrggza")).await;
+        assert_eq!(resp.status(), http::StatusCode::GONE);
+        let body: ErrorBody = read_body_json(resp).await;
+        assert_eq!(body.code, ErrorCode::Draining);
+
+        let wait = actix_web::rt::spawn(drain::wait_drained(appstate.clone()));
+        actix_web::rt::time::sleep(Duration::from_millis(150)).await;
+        assert!(!wait.is_finished());
+        drop(in_flight);
+        tokio::time::timeout(Duration::from_secs(1), wait)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let status: Value = read_body_json(
+            call_service(
+                &admin_app,
+                signed(&operator, now(), "POST", "/admin/undrain").to_request(),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(
+            status,
+            json!({"running": true, "in_flight": 0, "drained": false})
+        );
+    }
+
+    #[actix_web::test]
+    async fn reload_config_test() {
+        let dir = std::env::temp_dir().join(format!("oyster-config-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("config.json");
+
+        let operator = SigningKey::random(&mut rand::rngs::OsRng);
+        let mut appstate = app_state(Cgroups { free: vec![] });
+        appstate.code_cache = CodeCache::new(dir.join("code-cache"), 1 << 26).unwrap();
+        appstate
+            .code_cache
+            .insert(&format!("0x{}", "a".repeat(64)), &[0; 100])
+            .await
+            .unwrap();
+        appstate.config_source.path = Some(path.clone());
+        let appstate = web::Data::new(appstate);
+        let auth = web::Data::new(AdminAuth::new(
+            &crate::verify::address(operator.verifying_key()),
+            NODE,
+        ));
+        let admin_app = init_service(
+            App::new()
+                .app_data(appstate.clone())
+                .app_data(auth)
+                .route("/admin/reload-config", web::post().to(admin::reload_config)),
+        )
+        .await;
+        let reload = || signed(&operator, now(), "POST", "/admin/reload-config").to_request();
+
+        // fields left out keep their command line value
+        std::fs::write(
+            &path,
+            r#"{"egress": "per-function", "egress_allow": ["8.8.8.8/32"], "console_limit": 1024, "code_cache_size": 10}"#,
+        )
+        .unwrap();
+        let resp = call_service(&admin_app, reload()).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let config: Value = read_body_json(resp).await;
+        assert_eq!(
+            config,
+            json!({
+                "egress": "per-function",
+                "egress_allow": ["8.8.8.8/32"],
+                "default_compatibility_date": "2023-03-07",
+                "max_compatibility_date": "2023-03-07",
+                "compatibility_flags": [],
+                "console_limit": 1024,
+                "code_cache_size": 10,
+            })
+        );
+        {
+            let config = appstate.config.read().unwrap();
+            assert_eq!(config.egress.mode, EgressMode::PerFunction);
+            assert_eq!(config.console_limit, Some(1024));
+        }
+        // the cache shrinks right away
+        assert!(appstate.code_cache.is_empty());
+
+        // a broken file keeps the current settings
+        for file in [
+            r#"{"egress": "everything"}"#,
+            r#"{"egress_allow": ["localhost"]}"#,
+            r#"{"unknown": 1}"#,
+            "{",
+        ] {
+            std::fs::write(&path, file).unwrap();
+            let resp = call_service(&admin_app, reload()).await;
+            assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST, "{file}");
+            let body: ErrorBody = read_body_json(resp).await;
+            assert_eq!(body.code, ErrorCode::InvalidConfig);
+            assert_eq!(appstate.config.read().unwrap().console_limit, Some(1024));
+        }
+
+        // null turns debug requests off again
+        std::fs::write(&path, r#"{"console_limit": null}"#).unwrap();
+        let resp = call_service(&admin_app, reload()).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        {
+            let config = appstate.config.read().unwrap();
+            assert_eq!(config.console_limit, None);
+            assert_eq!(config.egress.mode, EgressMode::Public);
+            assert_eq!(config.code_cache_size, 1 << 26);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn workers_test() {
+        let operator = SigningKey::random(&mut rand::rngs::OsRng);
+        let mut appstate = app_state(Cgroups { free: vec![] });
+        appstate.worker_pool = Some(WorkerPool::new(Duration::from_secs(60)).into());
+        let appstate = web::Data::new(appstate);
+        let auth = web::Data::new(AdminAuth::new(
+            &crate::verify::address(operator.verifying_key()),
+            NODE,
+        ));
+        let admin_app = init_service(
+            App::new()
+                .app_data(appstate.clone())
+                .app_data(auth)
+                .route("/admin/retire-idle", web::post().to(admin::retire_idle))
+                .route("/admin/workers", web::get().to(admin::workers)),
+        )
+        .await;
+
+        let mut resources = Resources::new("0xaa", "1234", "./runtime/", appstate.cgroups.clone());
+        resources.track_cgroup("workerd_1".to_owned());
+        resources.track_child(Command::new("sleep").arg("10").spawn().unwrap());
+        appstate
+            .worker_pool
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .put(Worker {
+                port: 11001,
+                last_used: Instant::now(),
+                resources,
+                console: Default::default(),
+            });
+
+        let workers: Value = read_body_json(
+            call_service(
+                &admin_app,
+                signed(&operator, now(), "GET", "/admin/workers").to_request(),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(
+            workers,
+            json!({"in_flight": 0, "idle": [{"tx_hash": "0xaa", "cgroup": "workerd_1", "port": 11001, "idle_secs": 0}]})
+        );
+
+        // idle workers are retired and their cgroups freed
+        let retired: Value = read_body_json(
+            call_service(
+                &admin_app,
+                signed(&operator, now(), "POST", "/admin/retire-idle").to_request(),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(retired, json!({"retired": 1}));
+        assert!(appstate
+            .worker_pool
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .is_empty());
+        assert_eq!(appstate.cgroups.lock().unwrap().free, vec!["workerd_1"]);
+    }
+}
+
+#[cfg(test)]
+pub mod gatewaytest {
+    use super::serverlesstest::app_state;
+    use crate::cgroups::Cgroups;
+    use crate::gateway::{self, GatewayClient, GatewayError, Heartbeat, Registration};
+    use crate::verify;
+    use actix_web::dev::ServerHandle;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use k256::ecdsa::SigningKey;
+    use serde_json::{json, Value};
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    type Calls = Arc<Mutex<Vec<(String, Value)>>>;
+
+    // signer of a call, checked the way a gateway would
+    fn call_signer(req: &HttpRequest, call: &str, body: &[u8]) -> Option<String> {
+        let header = |name| req.headers().get(name)?.to_str().ok();
+        let timestamp = header("X-Oyster-Timestamp")?.parse().ok()?;
+        let signature = hex::decode(header("X-Oyster-Signature")?).ok()?;
+
+        verify::recover(&gateway::gateway_hash(timestamp, call, body), &signature).ok()
+    }
+
+    // gateway recording every call it receives, calls not signed by the address in their body are rejected
+    pub async fn mock_gateway() -> (u16, ServerHandle, Calls) {
+        let calls = Calls::default();
+        let recorded = calls.clone();
+        let server = HttpServer::new(move || {
+            let calls = recorded.clone();
+            App::new().route(
+                "/{call:register|heartbeat|deregister}",
+                web::post().to(
+                    move |req: HttpRequest, call: web::Path<String>, body: web::Bytes| {
+                        let call = call.into_inner();
+                        let value = serde_json::from_slice::<Value>(&body).unwrap();
+                        let signed = call_signer(&req, &call, &body)
+                            .is_some_and(|signer| value["address"] == signer);
+                        if signed {
+                            calls.lock().unwrap().push((call, value));
+                        }
+                        async move {
+                            match signed {
+                                true => HttpResponse::Ok().finish(),
+                                false => HttpResponse::Unauthorized().finish(),
+                            }
+                        }
+                    },
+                ),
+            )
+        })
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        (port, handle, calls)
+    }
+
+    // wait for the next call that is not a heartbeat
+    async fn next_change(calls: &Calls, seen: &mut usize) -> (String, Value) {
+        for _ in 0..100 {
+            let calls = calls.lock().unwrap().clone();
+            while *seen < calls.len() {
+                *seen += 1;
+                if calls[*seen - 1].0 != "heartbeat" {
+                    return calls[*seen - 1].clone();
+                }
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("gateway was not called");
+    }
+
+    #[actix_web::test]
+    async fn client_test() {
+        let (port, handle, calls) = mock_gateway().await;
+        let signer = SigningKey::random(&mut rand::rngs::OsRng);
+        let address = verify::address(signer.verifying_key());
+
+        let client = GatewayClient::new(&format!("http://127.0.0.1:{port}/"), signer.clone());
+        let heartbeat = Heartbeat {
+            address: address.clone(),
+            in_flight: 2,
+            free_cgroups: 3,
+            idle_workers: 1,
+        };
+        client.heartbeat(&heartbeat).await.unwrap();
+        assert_eq!(
+            calls.lock().unwrap()[0],
+            (
+                "heartbeat".to_owned(),
+                json!({"address": address, "in_flight": 2, "free_cgroups": 3, "idle_workers": 1})
+            )
+        );
+
+        // calls are signed, others cannot speak for the node
+        let other = SigningKey::random(&mut rand::rngs::OsRng);
+        let client = GatewayClient::new(&format!("http://127.0.0.1:{port}"), other);
+        assert!(matches!(
+            client.heartbeat(&heartbeat).await,
+            Err(GatewayError::Rejected(401))
+        ));
+
+        let client =
+            GatewayClient::new(&format!("http://127.0.0.1:{port}/missing"), signer.clone());
+        assert!(matches!(
+            client.heartbeat(&heartbeat).await,
+            Err(GatewayError::Rejected(404))
+        ));
+
+        handle.stop(false).await;
+
+        let client = GatewayClient::new(&format!("http://127.0.0.1:{port}"), signer);
+        assert!(matches!(
+            client.heartbeat(&heartbeat).await,
+            Err(GatewayError::Request(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn registration_test() {
+        let (port, handle, calls) = mock_gateway().await;
+        let appstate = web::Data::new(app_state(Cgroups {
+            free: vec!["workerd_1".to_owned(), "workerd_2".to_owned()],
+        }));
+        let registration = gateway::registration(&appstate, 6001);
+        assert_eq!(
+            registration,
+            Registration {
+                address: crate::verify::address(appstate.signer.verifying_key()),
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                port: 6001,
+                capacity: 2,
+            }
+        );
+
+        let task = actix_web::rt::spawn(gateway::run(
+            GatewayClient::new(&format!("http://127.0.0.1:{port}"), appstate.signer.clone()),
+            appstate.clone(),
+            registration.clone(),
+            Duration::from_millis(20),
+        ));
+        let mut seen = 0;
+
+        let (call, body) = next_change(&calls, &mut seen).await;
+        assert_eq!(call, "register");
+        assert_eq!(body, serde_json::to_value(&registration).unwrap());
+
+        // heartbeats report the current load
+        appstate.in_flight.fetch_add(1, Ordering::Relaxed);
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+        let (call, body) = calls.lock().unwrap().last().unwrap().clone();
+        assert_eq!(call, "heartbeat");
+        assert_eq!(body["in_flight"], 1);
+        assert_eq!(body["free_cgroups"], 2);
+
+        // draining deregisters, undraining registers again
+        appstate.running.store(false, Ordering::Relaxed);
+        let (call, body) = next_change(&calls, &mut seen).await;
+        assert_eq!(call, "deregister");
+        assert_eq!(body, json!({"address": registration.address}));
+
+        appstate.running.store(true, Ordering::Relaxed);
+        let (call, _) = next_change(&calls, &mut seen).await;
+        assert_eq!(call, "register");
+
+        task.abort();
+        handle.stop(false).await;
+    }
+}
+
+#[cfg(test)]
+pub mod metricstest {
+    use super::serverlesstest::app_state;
+    use crate::cgroups::Cgroups;
+    use crate::handler;
+    use crate::metrics;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{http, web, App};
+    use std::time::Duration;
+
+    #[test]
+    fn render_test() {
+        let appstate = app_state(Cgroups {
+            free: vec!["a".to_owned(), "b".to_owned(), "c".to_owned()],
+        });
+        appstate.cgroups.lock().unwrap().free.pop();
+
+        appstate.metrics.requests.inc("ok");
+        appstate.metrics.requests.inc("ok");
+        appstate.metrics.requests.inc("worker_timeout");
+        appstate.metrics.timeouts.inc();
+        appstate
+            .metrics
+            .execution_seconds
+            .observe_duration(Duration::from_millis(30));
+        appstate
+            .metrics
+            .execution_seconds
+            .observe_duration(Duration::from_secs(20));
+        appstate.metrics.response_bytes.observe(1000.0);
+
+        let out = metrics::render(&appstate);
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert!(lines.contains(&"# TYPE oyster_serverless_requests_total counter"));
+        assert!(lines.contains(&"oyster_serverless_requests_total{outcome=\"ok\"} 2"));
+        assert!(lines.contains(&"oyster_serverless_requests_total{outcome=\"worker_timeout\"} 1"));
+        assert!(lines.contains(&"oyster_serverless_timeouts_total 1"));
+
+        // buckets are cumulative, observations above the largest bucket only count towards +Inf
+        assert!(lines.contains(&"# TYPE oyster_serverless_execution_seconds histogram"));
+        assert!(lines.contains(&"oyster_serverless_execution_seconds_bucket{le=\"0.025\"} 0"));
+        assert!(lines.contains(&"oyster_serverless_execution_seconds_bucket{le=\"0.05\"} 1"));
+        assert!(lines.contains(&"oyster_serverless_execution_seconds_bucket{le=\"10\"} 1"));
+        assert!(lines.contains(&"oyster_serverless_execution_seconds_bucket{le=\"+Inf\"} 2"));
+        assert!(lines.contains(&"oyster_serverless_execution_seconds_count 2"));
+        assert!(lines.contains(&"oyster_serverless_response_bytes_bucket{le=\"1024\"} 1"));
+        assert!(lines.contains(&"oyster_serverless_response_bytes_sum 1000"));
+        assert!(lines.contains(&"oyster_serverless_spawn_seconds_count 0"));
+
+        assert!(lines.contains(&"oyster_serverless_cgroups_free 2"));
+        assert!(lines.contains(&"oyster_serverless_cgroups_reserved 1"));
+    }
+
+    #[actix_web::test]
+    async fn outcome_test() {
+        let appstate = web::Data::new(app_state(Cgroups { free: vec![] }));
+        let app = init_service(
+            App::new()
+                .app_data(appstate.clone())
+                .route("/metrics", web::get().to(metrics::metrics))
+                .default_service(web::to(handler::serverless)),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header(("Host", "mfrggza.localhost:6000"))
+            .insert_header(("X-Oyster-Nonce", "a".repeat(257)))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header(("Host", "a1.localhost:6000"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+
+        assert_eq!(appstate.metrics.requests.get("invalid_nonce"), 1);
+        assert_eq!(appstate.metrics.requests.get("invalid_tx_hash"), 1);
+        assert_eq!(appstate.metrics.requests.get("ok"), 0);
+
+        let req = TestRequest::get()
+            .uri("/metrics")
+            .insert_header(("Host", "localhost:6000"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(http::header::CONTENT_TYPE).unwrap(),
+            "text/plain; version=0.0.4"
+        );
+    }
+}
+
+#[cfg(test)]
+pub mod loggingtest {
+    use super::serverlesstest::app_state;
+    use crate::cgroups::Cgroups;
+    use crate::handler;
+    use crate::logging::{self, LogFormat};
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::display;
+    use tracing::{debug, info, info_span};
+    use tracing_subscriber::fmt::MakeWriter;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Buffer;
+
+        fn make_writer(&'a self) -> Buffer {
+            self.clone()
+        }
+    }
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(str::to_owned)
+                .collect()
+        }
+    }
+
+    fn log(format: LogFormat) -> Vec<String> {
+        let buffer = Buffer::default();
+        let logger = logging::subscriber(format, tracing::Level::INFO, buffer.clone());
+
+        tracing::subscriber::with_default(logger, || {
+            let span = info_span!(
+                "request",
+                request_id = %"0badcafe",
+                tx_hash = tracing::field::Empty
+            );
+            let _request = span.enter();
+            span.record("tx_hash", display("0x01"));
+
+            info_span!("fetch").in_scope(|| info!(status = 200, "fetched"));
+            // filtered out by the level
+            debug!("worker started");
+            info!(cached = false, "request done");
+        });
+        info!("not logged, the logger is gone");
+
+        buffer.lines()
+    }
+
+    #[test]
+    fn text_test() {
+        let lines = log(LogFormat::Text);
+        assert_eq!(lines.len(), 2);
+
+        assert!(lines[0].contains("  INFO "));
+        assert!(lines[0].ends_with(
+            "request{request_id=0badcafe tx_hash=0x01}:fetch: serverless::tests::loggingtest: fetched status=200"
+        ));
+        assert!(lines[1].ends_with(
+            "request{request_id=0badcafe tx_hash=0x01}: serverless::tests::loggingtest: request done cached=false"
+        ));
+    }
+
+    #[test]
+    fn json_test() {
+        let lines = log(LogFormat::Json);
+        assert_eq!(lines.len(), 2);
+
+        let line: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(
+            line["fields"],
+            serde_json::json!({ "message": "fetched", "status": 200 })
+        );
+        assert_eq!(
+            line["spans"],
+            serde_json::json!([
+                { "name": "request", "request_id": "0badcafe", "tx_hash": "0x01" },
+                { "name": "fetch" },
+            ])
+        );
+        assert!(line["timestamp"].as_str().unwrap().ends_with('Z'));
+
+        let line: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(line["fields"]["message"], "request done");
+        assert_eq!(line["spans"].as_array().unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn request_id_test() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(app_state(Cgroups { free: vec![] })))
+                .default_service(web::to(handler::serverless)),
+        )
+        .await;
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let req = TestRequest::post()
+                .uri("/")
+                .insert_header(("Host", "a1.localhost:6000"))
+                .to_request();
+            let resp = call_service(&app, req).await;
+
+            let id = resp.headers().get("X-Oyster-Request-Id").unwrap();
+            let id = id.to_str().unwrap().to_owned();
+            assert_eq!(id.len(), 8);
+            assert!(id.bytes().all(|x| x.is_ascii_hexdigit()));
+            ids.push(id);
+        }
+        assert_ne!(ids[0], ids[1]);
+    }
+}
+
+#[cfg(test)]
+pub mod consoletest {
+    use super::serverlesstest::{app_state, domain};
+    use super::verifytest::save_response;
+    use crate::cgroups::Cgroups;
+    use crate::console::{self, Captured, Console};
+    use crate::error::{ErrorBody, ErrorCode};
+    use crate::verify::{self, Exchange, SignatureScheme, SignatureVersion};
+    use crate::{handler, workerd};
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{http, web, App, HttpResponse, HttpServer};
+    use k256::ecdsa::SigningKey;
+    use std::process::{Command, Stdio};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn capture_test() {
+        let console = Arc::<Console>::default();
+
+        // nothing is kept unless a request asked for it
+        console.push("before");
+        console.start(1024);
+
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg("echo one; printf 'two\\r\\n\\377'")
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        console::capture(console.clone(), child.stdout.take().unwrap())
+            .join()
+            .unwrap();
+        child.wait().unwrap();
+
+        assert_eq!(
+            console.stop(),
+            Captured {
+                output: "one\ntwo\n\u{fffd}\n".to_owned(),
+                truncated: false,
+            }
+        );
+
+        // stopped again
+        console.push("after");
+        assert_eq!(console.stop().output, "");
+    }
+
+    #[test]
+    fn limit_test() {
+        let console = Console::default();
+        console.start(10);
+        console.push("1234");
+        console.push("56789");
+        // lines after a dropped one are dropped too, even if they would fit
+        console.push("a");
+
+        assert_eq!(
+            console.stop(),
+            Captured {
+                output: "1234\n".to_owned(),
+                truncated: true,
+            }
+        );
+
+        // every request starts fresh
+        console.start(10);
+        console.push("a");
+        assert_eq!(
+            console.stop(),
+            Captured {
+                output: "a\n".to_owned(),
+                truncated: false,
+            }
+        );
+    }
+
+    #[actix_web::test]
+    async fn finish_test() {
+        // lines still in flight when the response is in are waited for
+        let console = Arc::<Console>::default();
+        console.start(1024);
+        let late = console.clone();
+        let writer = std::thread::spawn(move || {
+            for line in ["one", "two"] {
+                std::thread::sleep(Duration::from_millis(5));
+                late.push(line);
+            }
+        });
+        assert_eq!(console.finish().await.output, "one\ntwo\n");
+        writer.join().unwrap();
+
+        // but not forever for a worker that keeps writing, even once its output is dropped
+        console.start(16);
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = std::thread::spawn({
+            let console = console.clone();
+            let done = done.clone();
+            move || {
+                while !done.load(Ordering::Relaxed) {
+                    console.push("spam");
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            }
+        });
+        let start = Instant::now();
+        assert!(console.finish().await.truncated);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        done.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+    }
+
+    #[actix_web::test]
+    async fn signed_console_test() {
+        // claims output of its own, which the node has to replace
+        let server = HttpServer::new(|| {
+            App::new().default_service(web::to(|| async {
+                HttpResponse::Ok()
+                    .insert_header((console::CONSOLE_HEADER, "Zm9yZ2Vk"))
+                    .insert_header((console::TRUNCATED_HEADER, "true"))
+                    .body("hello")
+            }))
+        })
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let signer = SigningKey::random(&mut rand::rngs::OsRng);
+        let address = verify::address(signer.verifying_key());
+        let scheme = SignatureScheme::new(
+            SignatureVersion::V2,
+            SignatureVersion::V1,
+            domain(),
+            vec![],
+            vec!["content-type".to_owned()],
+        )
+        .unwrap();
+
+        let console = Console::default();
+        console.start(1024);
+        console.push("log");
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header(("Host", "abcd.localhost:6000"))
+            .to_http_request();
+        let resp = workerd::get_workerd_response(
+            port,
+            req,
+            web::Bytes::new(),
+            &signer,
+            "abcd.localhost:6000",
+            &scheme,
+            None,
+            Some(&console),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            resp.headers().get(console::CONSOLE_HEADER).unwrap(),
+            "bG9nCg=="
+        );
+        assert!(!resp.headers().contains_key(console::TRUNCATED_HEADER));
+        assert_eq!(
+            resp.headers()
+                .get("X-Oyster-Signed-Response-Headers")
+                .unwrap(),
+            "content-type, x-oyster-console, x-oyster-console-truncated"
+        );
+
+        let response = save_response(resp);
+        let request = b"POST / HTTP/1.1\r\nHost: abcd.localhost:6000\r\n\r\n";
+        Exchange::parse(request, &response)
+            .unwrap()
+            .verify(&address, SignatureVersion::V2, &domain())
+            .unwrap();
+
+        // the output cannot be changed without breaking the signature
+        let tampered = String::from_utf8(response)
+            .unwrap()
+            .replace("bG9nCg==", "Zm9yZ2Vk");
+        assert!(Exchange::parse(request, tampered.as_bytes())
+            .unwrap()
+            .verify(&address, SignatureVersion::V2, &domain())
+            .is_err());
+
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn debug_not_allowed_test() {
+        for (console_limit, stream, version, message) in [
+            (None, false, "2", "debug mode is not enabled on this node"),
+            (
+                Some(1024),
+                true,
+                "2",
+                "debug mode is not supported for streamed requests",
+            ),
+            (
+                Some(1024),
+                false,
+                "1",
+                "debug mode needs signature version 2 or later",
+            ),
+        ] {
+            let mut appstate = app_state(Cgroups { free: vec![] });
+            appstate.config.get_mut().unwrap().console_limit = console_limit;
+            let app = init_service(
+                App::new()
+                    .app_data(web::Data::new(appstate))
+                    .default_service(web::to(handler::serverless)),
+            )
+            .await;
+
+            let req = TestRequest::post()
+                .uri("/")
+                .insert_header(("Host", "mfrggza.localhost:6000"))
+                .insert_header(("X-Oyster-Debug", "true"))
+                .insert_header(("X-Oyster-Stream", stream.to_string()))
+                .insert_header(("X-Oyster-Signature-Version", version))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+
+            let body: ErrorBody = read_body_json(resp).await;
+            assert_eq!(body.code, ErrorCode::DebugNotAllowed);
+            assert_eq!(body.message, message);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod usagetest {
+    use super::serverlesstest::domain;
+    use super::streamingtest::echo_worker;
+    use super::verifytest::save_response;
+    use crate::usage::{self, Usage, UsageMeter};
+    use crate::verify::{self, Exchange, SignatureScheme, SignatureVersion};
+    use crate::workerd;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use k256::ecdsa::SigningKey;
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn meter_test() {
+        assert_eq!(
+            usage::parse_cpu_stat("usage_usec 1234\nuser_usec 1000\nsystem_usec 234\n"),
+            Some(1234)
+        );
+        assert_eq!(usage::parse_cpu_stat("user_usec 1000\n"), None);
+
+        let dir = std::env::temp_dir().join(format!("oyster-cgroup-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("cpu.stat"), "usage_usec 1000\nuser_usec 800\n").unwrap();
+
+        let meter = UsageMeter::start(&dir).await;
+        std::fs::write(dir.join("cpu.stat"), "usage_usec 1500\nuser_usec 1200\n").unwrap();
+        let usage = meter.finish().await;
+        assert_eq!(usage.cpu_usec, Some(500));
+        // no memory.peak to reset
+        assert_eq!(usage.memory_peak, None);
+
+        // stats that cannot be read are left out
+        let headers = usage.headers();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].0, usage::CPU_HEADER);
+        assert_eq!(headers[0].1, "500");
+        assert_eq!(headers[1].0, usage::WALL_HEADER);
+
+        let usage = UsageMeter::start(dir.join("missing")).await.finish().await;
+        assert_eq!(usage.cpu_usec, None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let headers = Usage {
+            cpu_usec: Some(1),
+            memory_peak: Some(2),
+            wall_usec: 3,
+        }
+        .headers();
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers[1].0, usage::MEMORY_HEADER);
+        assert_eq!(headers[1].1, "2");
+    }
+
+    #[actix_web::test]
+    async fn signed_usage_test() {
+        // claims usage of its own, which the node has to replace
+        let server = HttpServer::new(|| {
+            App::new().default_service(web::to(|| async {
+                HttpResponse::Ok()
+                    .insert_header((usage::CPU_HEADER, "1"))
+                    .body("hello")
+            }))
+        })
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let dir = std::env::temp_dir().join(format!("oyster-cgroup-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("cpu.stat"), "usage_usec 1000\n").unwrap();
+
+        let signer = SigningKey::random(&mut rand::rngs::OsRng);
+        let address = verify::address(signer.verifying_key());
+        let scheme = SignatureScheme::new(
+            SignatureVersion::V2,
+            SignatureVersion::V1,
+            domain(),
+            vec![],
+            vec!["content-type".to_owned()],
+        )
+        .unwrap();
+
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header(("Host", "abcd.localhost:6000"))
+            .to_http_request();
+        let meter = UsageMeter::start(&dir).await;
+        std::fs::write(dir.join("cpu.stat"), "usage_usec 1250\n").unwrap();
+        let resp = workerd::get_workerd_response(
+            port,
+            req,
+            web::Bytes::new(),
+            &signer,
+            "abcd.localhost:6000",
+            &scheme,
+            Some(meter),
+            None,
+        )
+        .await
+        .unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(resp.headers().get(usage::CPU_HEADER).unwrap(), "250");
+        assert!(resp.headers().contains_key(usage::WALL_HEADER));
+        assert_eq!(
+            resp.headers()
+                .get("X-Oyster-Signed-Response-Headers")
+                .unwrap(),
+            "content-type, x-oyster-usage-cpu-us, x-oyster-usage-memory-peak, x-oyster-usage-wall-us"
+        );
+
+        let response = save_response(resp);
+        let request = b"POST / HTTP/1.1\r\nHost: abcd.localhost:6000\r\n\r\n";
+        Exchange::parse(request, &response)
+            .unwrap()
+            .verify(&address, SignatureVersion::V1, &domain())
+            .unwrap();
+
+        // usage cannot be changed without breaking the signature
+        let tampered = String::from_utf8(response)
+            .unwrap()
+            .replace("x-oyster-usage-cpu-us: 250", "x-oyster-usage-cpu-us: 25");
+        assert!(Exchange::parse(request, tampered.as_bytes())
+            .unwrap()
+            .verify(&address, SignatureVersion::V1, &domain())
+            .is_err());
+
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn stream_usage_test() {
+        let (port, handle) = echo_worker().await;
+        let signer = SigningKey::random(&mut rand::rngs::OsRng);
+        let address = verify::address(signer.verifying_key());
+
+        let dir = std::env::temp_dir().join(format!("oyster-cgroup-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("cpu.stat"), "usage_usec 1000\n").unwrap();
+
+        let cgroup = dir.clone();
+        let app = test::init_service(App::new().app_data(web::Data::new(signer.clone())).route(
+            "/echo",
+            web::post().to(
+                move |req: HttpRequest, payload: web::Payload, signer: web::Data<SigningKey>| {
+                    let cgroup = cgroup.clone();
+                    async move {
+                        let scheme = SignatureScheme::new(
+                            SignatureVersion::V2,
+                            SignatureVersion::V2,
+                            domain(),
+                            vec![],
+                            vec!["content-type".to_owned()],
+                        )
+                        .unwrap();
+                        let meter = UsageMeter::start(&cgroup).await;
+                        std::fs::write(cgroup.join("cpu.stat"), "usage_usec 1300\n").unwrap();
+                        let (mut response, body) = workerd::get_workerd_stream(
+                            port,
+                            req,
+                            payload,
+                            signer.get_ref().clone(),
+                            "abcd.localhost:6000",
+                            &scheme,
+                            Duration::from_secs(5),
+                            Some(meter),
+                        )
+                        .await
+                        .unwrap();
+                        response.streaming(body)
+                    }
+                },
+            ),
+        ))
+        .await;
+
+        let resp = test::call_service(
+            &app,
+            TestRequest::post()
+                .uri("/echo")
+                .insert_header(("Host", "abcd.localhost:6000"))
+                .insert_header(("X-Oyster-Stream-Signature", "appended"))
+                .set_payload("hello")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(
+            resp.headers().get("X-Oyster-Stream-Usage").unwrap(),
+            "appended"
+        );
+
+        let mut response = format!("HTTP/1.1 {}\r\n", resp.status()).into_bytes();
+        for (name, value) in resp.headers() {
+            response.extend_from_slice(name.as_str().as_bytes());
+            response.extend_from_slice(b": ");
+            response.extend_from_slice(value.as_bytes());
+            response.extend_from_slice(b"\r\n");
+        }
+        response.extend_from_slice(b"\r\n");
+        response.extend_from_slice(&test::read_body(resp).await);
+
+        // the usage is signed as part of the body
+        let request = b"POST /echo HTTP/1.1\r\nHost: abcd.localhost:6000\r\n\r\nhello";
+        let exchange = Exchange::parse(request, &response).unwrap();
+        exchange
+            .verify(&address, SignatureVersion::V2, &domain())
+            .unwrap();
+        let (body, usage) = exchange.split_usage().unwrap();
+        assert_eq!(body, b"hello");
+        let usage = String::from_utf8(usage.unwrap().to_vec()).unwrap();
+        assert!(usage.starts_with("x-oyster-usage-cpu-us:300\nx-oyster-usage-memory-peak\n"));
+        assert!(usage.contains("x-oyster-usage-wall-us:"));
+
+        // unsigned streams do not carry it
+        let resp = test::call_service(
+            &app,
+            TestRequest::post()
+                .uri("/echo")
+                .insert_header(("Host", "abcd.localhost:6000"))
+                .set_payload("hello")
+                .to_request(),
+        )
+        .await;
+        assert!(resp.headers().get("X-Oyster-Stream-Usage").is_none());
+        assert_eq!(test::read_body(resp).await, "hello");
+
+        std::fs::remove_dir_all(dir).unwrap();
+        handle.stop(false).await;
     }
 }
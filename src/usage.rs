@@ -0,0 +1,146 @@
+// resources used by a worker while serving a request, read from the stats of its cgroup
+// every worker runs in a cgroup of its own, so the stats only cover the worker
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use actix_web::http::header::{HeaderName, HeaderValue};
+
+use crate::verify;
+
+pub const CPU_HEADER: &str = "x-oyster-usage-cpu-us";
+pub const MEMORY_HEADER: &str = "x-oyster-usage-memory-peak";
+pub const WALL_HEADER: &str = "x-oyster-usage-wall-us";
+
+// reported by the node, workers cannot set them
+pub const USAGE_HEADERS: [&str; 3] = [CPU_HEADER, MEMORY_HEADER, WALL_HEADER];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    // cpu time in microseconds, user and system
+    pub cpu_usec: Option<u64>,
+    // peak memory in bytes
+    pub memory_peak: Option<u64>,
+    pub wall_usec: u64,
+}
+
+impl Usage {
+    // stats that could not be read are left out
+    pub fn headers(&self) -> Vec<(HeaderName, HeaderValue)> {
+        [
+            (CPU_HEADER, self.cpu_usec),
+            (MEMORY_HEADER, self.memory_peak),
+            (WALL_HEADER, Some(self.wall_usec)),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((HeaderName::from_static(name), value?.into())))
+        .collect()
+    }
+
+    // appended to the body of signed streams, whose headers are sent before the worker is done
+    // the canonical form of the headers followed by its length as 2 bytes big endian
+    pub fn trailer(&self) -> Vec<u8> {
+        let headers = self.headers();
+        let mut trailer = verify::canonical_headers(
+            &USAGE_HEADERS.map(str::to_owned),
+            headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_bytes())),
+        );
+        let length = trailer.len() as u16;
+        trailer.extend_from_slice(&length.to_be_bytes());
+
+        trailer
+    }
+}
+
+pub struct UsageMeter {
+    path: PathBuf,
+    cpu_usec: Option<u64>,
+    // memory.peak opened for this request, the peak is reset on it so it only covers the request
+    // cgroups are reused by the workers that come after, the cgroup wide peak covers all of them
+    memory_peak: Option<File>,
+    start: Instant,
+}
+
+// the stats are files, they are read on the blocking pool to keep the runtime free
+impl UsageMeter {
+    // path is the directory of the cgroup, e.g. /sys/fs/cgroup/workerd_1
+    pub async fn start(path: impl AsRef<Path>) -> UsageMeter {
+        let path = path.as_ref().to_owned();
+        let start = Instant::now();
+        let (cpu_usec, memory_peak) = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || (cpu_usec(&path), reset_memory_peak(&path))
+        })
+        .await
+        .unwrap_or_default();
+
+        UsageMeter {
+            path,
+            cpu_usec,
+            memory_peak,
+            start,
+        }
+    }
+
+    pub async fn finish(self) -> Usage {
+        let wall_usec = self.start.elapsed().as_micros() as u64;
+        let UsageMeter {
+            path,
+            cpu_usec: start,
+            memory_peak,
+            ..
+        } = self;
+        let (cpu_usec, memory_peak) = tokio::task::spawn_blocking(move || {
+            let cpu_usec = match (start, cpu_usec(&path)) {
+                (Some(start), Some(end)) => Some(end.saturating_sub(start)),
+                _ => None,
+            };
+            let memory_peak = memory_peak.and_then(|mut file| {
+                let mut peak = String::new();
+                file.rewind().ok()?;
+                file.read_to_string(&mut peak).ok()?;
+                peak.trim().parse().ok()
+            });
+
+            (cpu_usec, memory_peak)
+        })
+        .await
+        .unwrap_or_default();
+
+        Usage {
+            cpu_usec,
+            memory_peak,
+            wall_usec,
+        }
+    }
+}
+
+// usage_usec of cpu.stat, e.g.
+//   usage_usec 1234
+//   user_usec 1000
+//   system_usec 234
+pub fn parse_cpu_stat(stat: &str) -> Option<u64> {
+    stat.lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .and_then(|value| value.trim().parse().ok())
+}
+
+fn cpu_usec(path: &Path) -> Option<u64> {
+    parse_cpu_stat(&fs::read_to_string(path.join("cpu.stat")).ok()?)
+}
+
+// resetting the peak needs linux 6.12, the peak is not reported on older kernels
+fn reset_memory_peak(path: &Path) -> Option<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path.join("memory.peak"))
+        .ok()?;
+    file.write_all(b"reset\n").ok()?;
+
+    Some(file)
+}
@@ -0,0 +1,788 @@
+// verification of the X-Oyster-Signature attached to responses
+//
+// the signature is a recoverable secp256k1 signature (r || s || v, v = 27 or 28) over the
+// keccak256 hash of
+//   |oyster-serverless-hasher-v2|
+//   |timestamp|    <X-Oyster-Timestamp as 8 bytes big endian>
+//   |nonce|        <X-Oyster-Nonce of the request, only if it was sent>
+//   |request|
+//   |method|       <request method>
+//   |pathandquery| <request path and query>
+//   |host|         <Host header of the request>
+//   |headers|      <canonical request headers listed in X-Oyster-Signed-Request-Headers>
+//   |body|         <request body>
+//   |response|
+//   |status|       <status code as 2 bytes big endian>
+//   |headers|      <canonical response headers listed in X-Oyster-Signed-Response-Headers>
+//   |body|         <response body>
+//
+// version 1 starts with |oyster-serverless-hasher| instead and has no status and headers
+//
+// the |nonce| tag is only present if a nonce was sent, so a missing nonce and an empty one hash differently
+//
+// the eip712 version signs the same fields as typed data instead, see EIP712_TYPE,
+// in the domain EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)
+// with name "Oyster Serverless", version "1" and the chain and address of the deploy contract
+//
+// the version is sent in X-Oyster-Signature-Version, responses without it are version 2
+// if they list signed headers and version 1 otherwise
+// the header is not covered by the signature, so verifiers have to enforce a minimum version
+// to keep a response from being passed off under a weaker layout
+//
+// buffered responses sign the usage headers reported by the node on top of the configured ones,
+// see SignatureScheme::with_usage, and the console headers of debug requests, see SignatureScheme::with_console
+// signed streams with X-Oyster-Stream-Usage: appended end their body with the usage instead, see Exchange::split_usage
+//
+// errors returned by the node itself instead of the worker have an X-Oyster-Error header and
+// are signed over
+//   |oyster-serverless-error|
+//   |timestamp|    <X-Oyster-Timestamp as 8 bytes big endian>
+//   |nonce|        <X-Oyster-Nonce of the request, only if it was sent>
+//   |request|
+//   |method|       <request method>
+//   |pathandquery| <request path and query>
+//   |host|         <Host header of the request>
+//   |body|         <request body, only if the node read all of it>
+//   |response|
+//   |status|       <status code as 2 bytes big endian>
+//   |body|         <error body>
+// or as typed data, see EIP712_ERROR_TYPE, in the eip712 version
+// errors that do not cover the request body, e.g. for streamed or too large requests,
+// carry X-Oyster-Signed-Request-Body: false
+
+use std::fmt;
+use std::str::FromStr;
+
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use thiserror::Error;
+use tiny_keccak::{Hasher, Keccak};
+
+use crate::console::CONSOLE_HEADERS;
+use crate::usage::USAGE_HEADERS;
+
+#[derive(Error, Debug)]
+pub enum VerifyError {
+    #[error("header {0} cannot be signed")]
+    UnsignableHeader(String),
+    #[error("signature must be 65 bytes")]
+    SignatureLength,
+    #[error("invalid signature")]
+    InvalidSignature(#[source] k256::ecdsa::Error),
+    #[error("invalid recovery id")]
+    InvalidRecoveryId,
+    #[error("response signed by {0}, expected {1}")]
+    SignerMismatch(String, String),
+    #[error("failed to parse http {0}")]
+    BadHttp(&'static str),
+    #[error("missing {0} header")]
+    MissingHeader(&'static str),
+    #[error("invalid {0} header")]
+    InvalidHeader(&'static str),
+    #[error("signature version {0} is below the minimum version {1}")]
+    VersionBelowMinimum(SignatureVersion, SignatureVersion),
+    #[error("invalid address {0}")]
+    InvalidAddress(String),
+    #[error("appended usage is longer than the body")]
+    UsageLength,
+}
+
+// ordered from the weakest layout to the strongest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SignatureVersion {
+    V1,
+    V2,
+    Eip712,
+}
+
+impl FromStr for SignatureVersion {
+    type Err = &'static str;
+
+    fn from_str(version: &str) -> Result<SignatureVersion, Self::Err> {
+        match version {
+            "1" => Ok(SignatureVersion::V1),
+            "2" => Ok(SignatureVersion::V2),
+            "eip712" => Ok(SignatureVersion::Eip712),
+            _ => Err("signature version must be 1, 2 or eip712"),
+        }
+    }
+}
+
+impl fmt::Display for SignatureVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SignatureVersion::V1 => "1",
+            SignatureVersion::V2 => "2",
+            SignatureVersion::Eip712 => "eip712",
+        })
+    }
+}
+
+pub const EIP712_DOMAIN_NAME: &str = "Oyster Serverless";
+pub const EIP712_DOMAIN_VERSION: &str = "1";
+pub const EIP712_DOMAIN_TYPE: &str =
+    "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)";
+// bytes fields are the canonical headers and raw bodies
+// nonce is empty and hasNonce false if the request did not have one
+pub const EIP712_TYPE: &str = "OysterResponse(uint64 timestamp,bool hasNonce,bytes nonce,string method,string pathAndQuery,string host,bytes requestHeaders,bytes requestBody,uint16 status,bytes responseHeaders,bytes responseBody)";
+
+// nonce is empty and hasNonce false if the request did not have one,
+// requestBody is empty and hasRequestBody false if the node did not read all of it
+pub const EIP712_ERROR_TYPE: &str = "OysterError(uint64 timestamp,bool hasNonce,bytes nonce,string method,string pathAndQuery,string host,bool hasRequestBody,bytes requestBody,uint16 status,bytes body)";
+
+fn keccak256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Keccak::v256();
+    hasher.update(data);
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash);
+
+    hash
+}
+
+// abi encoded unsigned integer
+fn word(value: u64) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[24..].copy_from_slice(&value.to_be_bytes());
+
+    word
+}
+
+// chain and contract eip712 signatures are bound to, signatures for another deployment do not verify
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Eip712Domain {
+    pub chain_id: u64,
+    pub verifying_contract: [u8; 20],
+}
+
+impl Eip712Domain {
+    // contract given as a 0x prefixed hex address
+    pub fn new(chain_id: u64, verifying_contract: &str) -> Result<Eip712Domain, VerifyError> {
+        let verifying_contract = verifying_contract
+            .strip_prefix("0x")
+            .and_then(|x| hex::decode(x).ok())
+            .and_then(|x| x.try_into().ok())
+            .ok_or_else(|| VerifyError::InvalidAddress(verifying_contract.to_owned()))?;
+
+        Ok(Eip712Domain {
+            chain_id,
+            verifying_contract,
+        })
+    }
+
+    pub fn separator(&self) -> [u8; 32] {
+        let mut contract = [0u8; 32];
+        contract[12..].copy_from_slice(&self.verifying_contract);
+
+        keccak256(
+            &[
+                keccak256(EIP712_DOMAIN_TYPE.as_bytes()),
+                keccak256(EIP712_DOMAIN_NAME.as_bytes()),
+                keccak256(EIP712_DOMAIN_VERSION.as_bytes()),
+                word(self.chain_id),
+                contract,
+            ]
+            .concat(),
+        )
+    }
+}
+
+// framing headers are rewritten by the server and proxies, so they cannot be signed
+const UNSIGNABLE_HEADERS: [&str; 5] = [
+    "connection",
+    "content-length",
+    "keep-alive",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// what gets signed besides the method, path, host and bodies
+#[derive(Debug, Clone)]
+pub struct SignatureScheme {
+    pub version: SignatureVersion,
+    // versions below it are never used, even if clients ask for them
+    pub min_version: SignatureVersion,
+    pub domain: Eip712Domain,
+    request_headers: Vec<String>,
+    response_headers: Vec<String>,
+}
+
+impl SignatureScheme {
+    pub fn new(
+        version: SignatureVersion,
+        min_version: SignatureVersion,
+        domain: Eip712Domain,
+        request_headers: Vec<String>,
+        response_headers: Vec<String>,
+    ) -> Result<SignatureScheme, VerifyError> {
+        if version < min_version {
+            return Err(VerifyError::VersionBelowMinimum(version, min_version));
+        }
+
+        Ok(SignatureScheme {
+            version,
+            min_version,
+            domain,
+            request_headers: normalize_headers(request_headers)?,
+            response_headers: normalize_headers(response_headers)?,
+        })
+    }
+
+    // same headers signed with another version, used when clients ask for a specific version
+    pub fn with_version(&self, version: SignatureVersion) -> Result<SignatureScheme, VerifyError> {
+        if version < self.min_version {
+            return Err(VerifyError::VersionBelowMinimum(version, self.min_version));
+        }
+
+        Ok(SignatureScheme {
+            version,
+            ..self.clone()
+        })
+    }
+
+    // same scheme also covering the usage headers reported by the node
+    pub fn with_usage(&self) -> SignatureScheme {
+        self.with_response_headers(&USAGE_HEADERS)
+    }
+
+    // same scheme also covering the console output returned to debug requests
+    pub fn with_console(&self) -> SignatureScheme {
+        self.with_response_headers(&CONSOLE_HEADERS)
+    }
+
+    fn with_response_headers(&self, names: &[&str]) -> SignatureScheme {
+        let mut response_headers = self.response_headers.clone();
+        response_headers.extend(names.iter().map(|name| name.to_string()));
+        response_headers.sort();
+        response_headers.dedup();
+
+        SignatureScheme {
+            response_headers,
+            ..self.clone()
+        }
+    }
+
+    // lowercase names in sorted order
+    pub fn request_headers(&self) -> &[String] {
+        &self.request_headers
+    }
+
+    // lowercase names in sorted order
+    pub fn response_headers(&self) -> &[String] {
+        &self.response_headers
+    }
+}
+
+fn normalize_headers(mut names: Vec<String>) -> Result<Vec<String>, VerifyError> {
+    for name in names.iter_mut() {
+        name.make_ascii_lowercase();
+        if name.is_empty()
+            || !name
+                .bytes()
+                .all(|x| x.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&x))
+            || UNSIGNABLE_HEADERS.contains(&name.as_str())
+        {
+            return Err(VerifyError::UnsignableHeader(name.clone()));
+        }
+    }
+    names.sort();
+    names.dedup();
+
+    Ok(names)
+}
+
+// canonical form of the signed headers, one line per name in the given order,
+// `name:value` with the trimmed values of repeated headers joined by ", ", or just `name` if missing
+pub fn canonical_headers<'a>(
+    names: &[String],
+    headers: impl IntoIterator<Item = (&'a str, &'a [u8])>,
+) -> Vec<u8> {
+    let headers = headers.into_iter().collect::<Vec<_>>();
+
+    let mut canonical = Vec::new();
+    for name in names {
+        canonical.extend_from_slice(name.as_bytes());
+        let values = headers
+            .iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim_ascii())
+            .collect::<Vec<_>>();
+        if !values.is_empty() {
+            canonical.push(b':');
+            canonical.extend_from_slice(&values.join(b", ".as_slice()));
+        }
+        canonical.push(b'\n');
+    }
+
+    canonical
+}
+
+// incremental hasher for the signed message, bodies can be fed in chunks
+// headers and status are ignored by version 1
+pub struct MessageHasher {
+    version: SignatureVersion,
+    // eip712 only
+    domain: Eip712Domain,
+    hasher: Keccak,
+    // eip712 only, bytes fields are hashed on their own and only their hash goes into hasher
+    body: Keccak,
+}
+
+impl MessageHasher {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        version: SignatureVersion,
+        domain: &Eip712Domain,
+        timestamp: u64,
+        nonce: Option<&[u8]>,
+        method: &str,
+        path_and_query: &str,
+        host: &str,
+        request_headers: &[u8],
+    ) -> MessageHasher {
+        let mut hasher = Keccak::v256();
+        if version == SignatureVersion::Eip712 {
+            hasher.update(&keccak256(EIP712_TYPE.as_bytes()));
+            hasher.update(&word(timestamp));
+            hasher.update(&word(nonce.is_some().into()));
+            hasher.update(&keccak256(nonce.unwrap_or_default()));
+            hasher.update(&keccak256(method.as_bytes()));
+            hasher.update(&keccak256(path_and_query.as_bytes()));
+            hasher.update(&keccak256(host.as_bytes()));
+            hasher.update(&keccak256(request_headers));
+
+            return MessageHasher {
+                version,
+                domain: *domain,
+                hasher,
+                body: Keccak::v256(),
+            };
+        }
+
+        hasher.update(match version {
+            SignatureVersion::V1 => b"|oyster-serverless-hasher|".as_slice(),
+            _ => b"|oyster-serverless-hasher-v2|",
+        });
+
+        hasher.update(b"|timestamp|");
+        hasher.update(&timestamp.to_be_bytes());
+        if let Some(nonce) = nonce {
+            hasher.update(b"|nonce|");
+            hasher.update(nonce);
+        }
+
+        hasher.update(b"|request|");
+        hasher.update(b"|method|");
+        hasher.update(method.as_bytes());
+        hasher.update(b"|pathandquery|");
+        hasher.update(path_and_query.as_bytes());
+        hasher.update(b"|host|");
+        hasher.update(host.as_bytes());
+        if version == SignatureVersion::V2 {
+            hasher.update(b"|headers|");
+            hasher.update(request_headers);
+        }
+        hasher.update(b"|body|");
+
+        MessageHasher {
+            version,
+            domain: *domain,
+            hasher,
+            body: Keccak::v256(),
+        }
+    }
+
+    pub fn request_body(&mut self, chunk: &[u8]) {
+        self.body_hasher().update(chunk);
+    }
+
+    // marks the end of the request body
+    pub fn response(&mut self, status: u16, response_headers: &[u8]) {
+        if self.version == SignatureVersion::Eip712 {
+            self.finish_body();
+            self.hasher.update(&word(status.into()));
+            self.hasher.update(&keccak256(response_headers));
+            return;
+        }
+
+        self.hasher.update(b"|response|");
+        if self.version == SignatureVersion::V2 {
+            self.hasher.update(b"|status|");
+            self.hasher.update(&status.to_be_bytes());
+            self.hasher.update(b"|headers|");
+            self.hasher.update(response_headers);
+        }
+        self.hasher.update(b"|body|");
+    }
+
+    pub fn response_body(&mut self, chunk: &[u8]) {
+        self.body_hasher().update(chunk);
+    }
+
+    pub fn finalize(mut self) -> [u8; 32] {
+        if self.version == SignatureVersion::Eip712 {
+            self.finish_body();
+            let mut struct_hash = [0u8; 32];
+            self.hasher.finalize(&mut struct_hash);
+
+            return keccak256(
+                &[
+                    b"\x19\x01".as_slice(),
+                    &self.domain.separator(),
+                    &struct_hash,
+                ]
+                .concat(),
+            );
+        }
+
+        let mut hash = [0u8; 32];
+        self.hasher.finalize(&mut hash);
+
+        hash
+    }
+
+    fn body_hasher(&mut self) -> &mut Keccak {
+        match self.version {
+            SignatureVersion::Eip712 => &mut self.body,
+            _ => &mut self.hasher,
+        }
+    }
+
+    fn finish_body(&mut self) {
+        let mut hash = [0u8; 32];
+        std::mem::replace(&mut self.body, Keccak::v256()).finalize(&mut hash);
+        self.hasher.update(&hash);
+    }
+}
+
+// hash of an error returned by the node itself
+#[allow(clippy::too_many_arguments)]
+pub fn error_hash(
+    version: SignatureVersion,
+    domain: &Eip712Domain,
+    timestamp: u64,
+    nonce: Option<&[u8]>,
+    method: &str,
+    path_and_query: &str,
+    host: &str,
+    request_body: Option<&[u8]>,
+    status: u16,
+    body: &[u8],
+) -> [u8; 32] {
+    if version == SignatureVersion::Eip712 {
+        let struct_hash = keccak256(
+            &[
+                keccak256(EIP712_ERROR_TYPE.as_bytes()),
+                word(timestamp),
+                word(nonce.is_some().into()),
+                keccak256(nonce.unwrap_or_default()),
+                keccak256(method.as_bytes()),
+                keccak256(path_and_query.as_bytes()),
+                keccak256(host.as_bytes()),
+                word(request_body.is_some().into()),
+                keccak256(request_body.unwrap_or_default()),
+                word(status.into()),
+                keccak256(body),
+            ]
+            .concat(),
+        );
+
+        return keccak256(&[b"\x19\x01".as_slice(), &domain.separator(), &struct_hash].concat());
+    }
+
+    let mut hasher = Keccak::v256();
+    hasher.update(b"|oyster-serverless-error|");
+
+    hasher.update(b"|timestamp|");
+    hasher.update(&timestamp.to_be_bytes());
+    if let Some(nonce) = nonce {
+        hasher.update(b"|nonce|");
+        hasher.update(nonce);
+    }
+
+    hasher.update(b"|request|");
+    hasher.update(b"|method|");
+    hasher.update(method.as_bytes());
+    hasher.update(b"|pathandquery|");
+    hasher.update(path_and_query.as_bytes());
+    hasher.update(b"|host|");
+    hasher.update(host.as_bytes());
+    if let Some(request_body) = request_body {
+        hasher.update(b"|body|");
+        hasher.update(request_body);
+    }
+
+    hasher.update(b"|response|");
+    hasher.update(b"|status|");
+    hasher.update(&status.to_be_bytes());
+    hasher.update(b"|body|");
+    hasher.update(body);
+
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash);
+
+    hash
+}
+
+// 0x prefixed ethereum address of the key
+pub fn address(key: &VerifyingKey) -> String {
+    let mut hasher = Keccak::v256();
+    hasher.update(&key.to_encoded_point(false).as_bytes()[1..]);
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash);
+
+    "0x".to_owned() + &hex::encode(&hash[12..])
+}
+
+// address of the signer of the hash
+pub fn recover(hash: &[u8; 32], signature: &[u8]) -> Result<String, VerifyError> {
+    if signature.len() != 65 {
+        return Err(VerifyError::SignatureLength);
+    }
+
+    let rs = Signature::from_slice(&signature[..64]).map_err(VerifyError::InvalidSignature)?;
+    let v = signature[64]
+        .checked_sub(27)
+        .and_then(RecoveryId::from_byte)
+        .ok_or(VerifyError::InvalidRecoveryId)?;
+    let key =
+        VerifyingKey::recover_from_prehash(hash, &rs, v).map_err(VerifyError::InvalidSignature)?;
+
+    Ok(address(&key))
+}
+
+// a signed request/response pair
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exchange {
+    pub version: SignatureVersion,
+    // returned by the node instead of the worker, the request headers are not signed
+    pub error: bool,
+    // only errors can leave the request body out
+    pub request_body_signed: bool,
+    pub timestamp: u64,
+    pub nonce: Option<Vec<u8>>,
+    pub method: String,
+    pub path_and_query: String,
+    pub host: String,
+    // canonical form of the signed headers
+    pub request_headers: Vec<u8>,
+    pub request_body: Vec<u8>,
+    pub status: u16,
+    // canonical form of the signed headers
+    pub response_headers: Vec<u8>,
+    pub response_body: Vec<u8>,
+    // the response body ends with the usage of the worker, see Exchange::split_usage
+    pub usage_appended: bool,
+    pub signature: Vec<u8>,
+}
+
+impl Exchange {
+    // parse raw http messages, e.g. the request as sent and the response as saved by `curl -i`
+    // bodies must already be decoded from any transfer encoding
+    // streamed responses with X-Oyster-Stream-Signature: appended have the signature as the final 65 bytes of the body
+    pub fn parse(request: &[u8], response: &[u8]) -> Result<Exchange, VerifyError> {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut parsed = httparse::Request::new(&mut headers);
+        let httparse::Status::Complete(offset) = parsed
+            .parse(request)
+            .map_err(|_| VerifyError::BadHttp("request"))?
+        else {
+            return Err(VerifyError::BadHttp("request"));
+        };
+        let method = parsed.method.unwrap().to_owned();
+        let path_and_query = parsed.path.unwrap().to_owned();
+        let host = header(parsed.headers, "Host")?
+            .ok_or(VerifyError::MissingHeader("Host"))?
+            .to_owned();
+        let nonce = header(parsed.headers, "X-Oyster-Nonce")?.map(|x| x.as_bytes().to_vec());
+        let request_body = request[offset..].to_vec();
+        let request_headers = parsed
+            .headers
+            .iter()
+            .map(|header| (header.name, header.value))
+            .collect::<Vec<_>>();
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut parsed = httparse::Response::new(&mut headers);
+        let httparse::Status::Complete(offset) = parsed
+            .parse(response)
+            .map_err(|_| VerifyError::BadHttp("response"))?
+        else {
+            return Err(VerifyError::BadHttp("response"));
+        };
+        let timestamp = header(parsed.headers, "X-Oyster-Timestamp")?
+            .ok_or(VerifyError::MissingHeader("X-Oyster-Timestamp"))?
+            .parse::<u64>()
+            .map_err(|_| VerifyError::InvalidHeader("X-Oyster-Timestamp"))?;
+        let status = parsed.code.unwrap();
+        let mut response_body = response[offset..].to_vec();
+        let signature = match header(parsed.headers, "X-Oyster-Signature")? {
+            Some(signature) => hex::decode(signature.strip_prefix("0x").unwrap_or(signature))
+                .map_err(|_| VerifyError::InvalidHeader("X-Oyster-Signature"))?,
+            None if header(parsed.headers, "X-Oyster-Stream-Signature")? == Some("appended") => {
+                if response_body.len() < 65 {
+                    return Err(VerifyError::SignatureLength);
+                }
+                response_body.split_off(response_body.len() - 65)
+            }
+            None => return Err(VerifyError::MissingHeader("X-Oyster-Signature")),
+        };
+
+        let error = header(parsed.headers, "X-Oyster-Error")?.is_some();
+        let usage_appended = header(parsed.headers, "X-Oyster-Stream-Usage")? == Some("appended");
+        let request_body_signed =
+            !error || header(parsed.headers, "X-Oyster-Signed-Request-Body")? != Some("false");
+
+        // responses before versioning only list the signed headers in version 2
+        let signed_request_headers = header(parsed.headers, "X-Oyster-Signed-Request-Headers")?;
+        let signed_response_headers = header(parsed.headers, "X-Oyster-Signed-Response-Headers")?;
+        let version = match header(parsed.headers, "X-Oyster-Signature-Version")? {
+            Some(version) => version
+                .parse::<SignatureVersion>()
+                .map_err(|_| VerifyError::InvalidHeader("X-Oyster-Signature-Version"))?,
+            None if signed_request_headers.is_some() => SignatureVersion::V2,
+            None => SignatureVersion::V1,
+        };
+        let (request_headers, response_headers) =
+            match (signed_request_headers, signed_response_headers) {
+                _ if error || version == SignatureVersion::V1 => (Vec::new(), Vec::new()),
+                (Some(signed_request_headers), Some(signed_response_headers)) => {
+                    let names = |list: &str| {
+                        normalize_headers(
+                            list.split(',')
+                                .map(str::trim)
+                                .filter(|x| !x.is_empty())
+                                .map(str::to_owned)
+                                .collect::<Vec<_>>(),
+                        )
+                    };
+                    let response_headers = parsed
+                        .headers
+                        .iter()
+                        .map(|header| (header.name, header.value));
+                    (
+                        canonical_headers(&names(signed_request_headers)?, request_headers),
+                        canonical_headers(&names(signed_response_headers)?, response_headers),
+                    )
+                }
+                (None, _) => {
+                    return Err(VerifyError::MissingHeader(
+                        "X-Oyster-Signed-Request-Headers",
+                    ))
+                }
+                (_, None) => {
+                    return Err(VerifyError::MissingHeader(
+                        "X-Oyster-Signed-Response-Headers",
+                    ))
+                }
+            };
+
+        Ok(Exchange {
+            version,
+            error,
+            request_body_signed,
+            timestamp,
+            nonce,
+            method,
+            path_and_query,
+            host,
+            request_headers,
+            request_body,
+            status,
+            response_headers,
+            response_body,
+            usage_appended,
+            signature,
+        })
+    }
+
+    // the body sent by the worker and the canonical usage headers appended to it,
+    // the usage is the canonical form of the headers followed by its length as 2 bytes big endian
+    pub fn split_usage(&self) -> Result<(&[u8], Option<&[u8]>), VerifyError> {
+        if !self.usage_appended {
+            return Ok((&self.response_body, None));
+        }
+
+        let body = &self.response_body;
+        let length = match body.len().checked_sub(2) {
+            Some(offset) => u16::from_be_bytes([body[offset], body[offset + 1]]) as usize,
+            None => return Err(VerifyError::UsageLength),
+        };
+        let offset = body
+            .len()
+            .checked_sub(2 + length)
+            .ok_or(VerifyError::UsageLength)?;
+
+        Ok((&body[..offset], Some(&body[offset..body.len() - 2])))
+    }
+
+    // the domain is only used by the eip712 version
+    pub fn hash(&self, domain: &Eip712Domain) -> [u8; 32] {
+        if self.error {
+            return error_hash(
+                self.version,
+                domain,
+                self.timestamp,
+                self.nonce.as_deref(),
+                &self.method,
+                &self.path_and_query,
+                &self.host,
+                self.request_body_signed.then_some(&self.request_body[..]),
+                self.status,
+                &self.response_body,
+            );
+        }
+
+        let mut hasher = MessageHasher::new(
+            self.version,
+            domain,
+            self.timestamp,
+            self.nonce.as_deref(),
+            &self.method,
+            &self.path_and_query,
+            &self.host,
+            &self.request_headers,
+        );
+        hasher.request_body(&self.request_body);
+        hasher.response(self.status, &self.response_headers);
+        hasher.response_body(&self.response_body);
+
+        hasher.finalize()
+    }
+
+    // address of the signer of the response
+    pub fn signer(&self, domain: &Eip712Domain) -> Result<String, VerifyError> {
+        recover(&self.hash(domain), &self.signature)
+    }
+
+    // check the response was signed by the expected address with at least the given version,
+    // the version is taken from the response so it cannot be trusted on its own
+    pub fn verify(
+        &self,
+        expected: &str,
+        min_version: SignatureVersion,
+        domain: &Eip712Domain,
+    ) -> Result<(), VerifyError> {
+        if self.version < min_version {
+            return Err(VerifyError::VersionBelowMinimum(self.version, min_version));
+        }
+
+        let signer = self.signer(domain)?;
+        if !signer.eq_ignore_ascii_case(expected) {
+            return Err(VerifyError::SignerMismatch(signer, expected.to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
+fn header<'a>(
+    headers: &[httparse::Header<'a>],
+    name: &'static str,
+) -> Result<Option<&'a str>, VerifyError> {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| {
+            std::str::from_utf8(header.value).map_err(|_| VerifyError::InvalidHeader(name))
+        })
+        .transpose()
+}
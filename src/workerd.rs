@@ -1,20 +1,34 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::pin::Pin;
 use std::process::Child;
 use std::time::{Duration, Instant};
 
 use thiserror::Error;
 
-use actix_web::{HttpRequest, HttpResponse};
+use actix_web::body::MessageBody;
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use anyhow::anyhow;
+use futures::future::{LocalBoxFuture, MaybeDone};
+use futures::{Future, FutureExt, SinkExt, Stream, StreamExt};
 use k256::elliptic_curve::generic_array::sequence::Lengthen;
 use reqwest::redirect::Policy;
-use reqwest::Client;
-use serde_json::{json, Value};
 use tiny_keccak::{Hasher, Keccak};
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 use tokio::time::sleep;
+use tracing::error;
 
+use crate::bundle;
 use crate::cgroups::{Cgroups, CgroupsError};
+use crate::console::Console;
+use crate::egress::{EgressPolicy, EgressRule};
+use crate::logging;
+use crate::secrets::{self, SecretsError};
+use crate::usage::UsageMeter;
+use crate::verify::{self, MessageHasher, SignatureScheme, SignatureVersion};
 
 #[derive(Error, Debug)]
 pub enum ServerlessError {
@@ -30,12 +44,46 @@ pub enum ServerlessError {
     InvalidTxCalldataType,
     #[error("calldata is not a valid hex string")]
     BadCalldata(#[from] hex::FromHexError),
+    #[error("calldata is too short")]
+    CalldataTooShort,
+    #[error("unexpected function selector 0x{0}")]
+    InvalidSelector(String),
+    #[error("offset of code in calldata is out of bounds")]
+    InvalidCodeOffset,
+    #[error("length of code in calldata is out of bounds")]
+    InvalidCodeLength,
+    #[error("code not found")]
+    CodeNotFound,
+    #[error("failed to read code")]
+    CodeRead(#[source] tokio::io::Error),
+    #[error("failed to fetch code")]
+    CodeFetch(#[source] reqwest::Error),
+    #[error("code does not match its content hash")]
+    CodeHashMismatch,
+    #[error("invalid bundle: {0}")]
+    InvalidBundle(&'static str),
+    #[error("failed to decrypt secret {0}")]
+    SecretDecrypt(String, #[source] SecretsError),
+    #[error("compatibility date {0} is invalid or not allowed")]
+    CompatibilityDateNotAllowed(String),
+    #[error("compatibility flag {0} is not allowed")]
+    CompatibilityFlagNotAllowed(String),
+    #[error("egress rule {0} is not allowed")]
+    EgressNotAllowed(String),
+    #[error("egress binding {0} conflicts with another binding")]
+    EgressBindingConflict(String),
     #[error("failed to create code file")]
     CodeFileCreate(#[source] tokio::io::Error),
     #[error("failed to create config file")]
     ConfigFileCreate(#[source] tokio::io::Error),
     #[error("failed to execute workerd")]
     Execute(#[from] CgroupsError),
+    #[error("no free cgroup available to run request")]
+    NoFreeCgroup,
+    #[error("syntax error in the code: {0}")]
+    SyntaxError(String),
+    #[error("failed to execute worker: {0}")]
+    WorkerStart(String),
     #[error("failed to terminate workerd")]
     Terminate(#[source] tokio::io::Error),
     #[error("failed to delete code file")]
@@ -46,97 +94,391 @@ pub enum ServerlessError {
     BadPort(#[source] std::num::ParseIntError),
 }
 
-async fn get_transaction_data(tx_hash: &str, rpc: &str) -> Result<Value, reqwest::Error> {
-    let client = Client::new();
-    let method = "eth_getTransactionByHash";
-    let params = json!([&tx_hash]);
-    let id = 1;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleKind {
+    EsModule,
+    Wasm,
+    Text,
+    Data,
+    Json,
+}
+
+impl ModuleKind {
+    // name of the field in the workerd config module definition
+    fn capnp_field(&self) -> &'static str {
+        match self {
+            ModuleKind::EsModule => "esModule",
+            ModuleKind::Wasm => "wasm",
+            ModuleKind::Text => "text",
+            ModuleKind::Data => "data",
+            ModuleKind::Json => "json",
+        }
+    }
+}
 
-    let request = json!({
-        "jsonrpc": "2.0",
-        "method": method,
-        "params": params,
-        "id": id,
-    });
+// a single module of a worker, the first module is the entrypoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Module {
+    pub name: String,
+    pub kind: ModuleKind,
+    pub content: Vec<u8>,
+}
 
-    let response = client.post(rpc).json(&request).send().await?;
-    let json_response = response.json::<Value>().await?;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindingValue {
+    Text(String),
+    Json(String),
+}
+
+// value exposed to the worker through `env`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binding {
+    pub name: String,
+    pub value: BindingValue,
+}
+
+// text binding encrypted to the secrets key of the enclave
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Secret {
+    pub name: String,
+    pub ciphertext: Vec<u8>,
+}
 
-    Ok(json_response)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Deployment {
+    pub modules: Vec<Module>,
+    pub bindings: Vec<Binding>,
+    pub secrets: Vec<Secret>,
+    pub compatibility_date: Option<String>,
+    pub compatibility_flags: Vec<String>,
+    pub egress: Vec<EgressRule>,
 }
 
+// compatibility settings deployments are allowed to use, configured by the operator
+#[derive(Debug, Clone)]
+pub struct CompatibilityPolicy {
+    pub default_date: String,
+    pub max_date: String,
+    pub allowed_flags: Vec<String>,
+}
+
+impl CompatibilityPolicy {
+    // compatibility date to use for the deployment, errors out if the deployment asks for too much
+    pub fn check(&self, deployment: &Deployment) -> Result<String, ServerlessError> {
+        let date = match &deployment.compatibility_date {
+            Some(date) if !is_valid_date(date) || date > &self.max_date => {
+                return Err(ServerlessError::CompatibilityDateNotAllowed(date.clone()))
+            }
+            Some(date) => date.clone(),
+            None => self.default_date.clone(),
+        };
+
+        if let Some(flag) = deployment
+            .compatibility_flags
+            .iter()
+            .find(|flag| !self.allowed_flags.contains(flag))
+        {
+            return Err(ServerlessError::CompatibilityFlagNotAllowed(flag.clone()));
+        }
+
+        Ok(date)
+    }
+}
+
+// YYYY-MM-DD, compares correctly as a string
+fn is_valid_date(date: &str) -> bool {
+    let parts = date.split('-').collect::<Vec<_>>();
+    let [year, month, day] = parts.as_slice() else {
+        return false;
+    };
+    let number = |part: &str, len: usize| {
+        (part.len() == len && part.bytes().all(|x| x.is_ascii_digit()))
+            .then(|| part.parse::<u16>().unwrap())
+    };
+
+    number(year, 4).is_some()
+        && number(month, 2).is_some_and(|x| (1..=12).contains(&x))
+        && number(day, 2).is_some_and(|x| (1..=31).contains(&x))
+}
+
+// everything needed to generate the workerd config of a worker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerConfig {
+    pub modules: Vec<Module>,
+    pub bindings: Vec<Binding>,
+    pub compatibility_date: String,
+    pub compatibility_flags: Vec<String>,
+    // None keeps the default workerd network access
+    pub egress: Option<Vec<EgressRule>>,
+}
+
+impl Deployment {
+    // deployment without bindings or settings, e.g. plain js or wasm code
+    pub fn from_modules(modules: Vec<Module>) -> Deployment {
+        Deployment {
+            modules,
+            ..Default::default()
+        }
+    }
+
+    // decrypt secrets, check compatibility settings and apply the egress policy
+    pub fn into_config(
+        self,
+        secrets_key: &k256::SecretKey,
+        compatibility: &CompatibilityPolicy,
+        egress: &EgressPolicy,
+    ) -> Result<WorkerConfig, ServerlessError> {
+        let bindings = self.decrypt_bindings(secrets_key)?;
+        let compatibility_date = compatibility.check(&self)?;
+        let egress = egress.resolve(&self.egress)?;
+
+        // egress hosts are exposed as bindings too
+        let mut names = bindings
+            .iter()
+            .map(|binding| binding.name.as_str())
+            .collect::<HashSet<_>>();
+        for rule in egress.iter().flatten() {
+            if let EgressRule::Host { binding, .. } = rule {
+                if !names.insert(binding) {
+                    return Err(ServerlessError::EgressBindingConflict(binding.clone()));
+                }
+            }
+        }
+
+        Ok(WorkerConfig {
+            modules: self.modules,
+            bindings,
+            compatibility_date,
+            compatibility_flags: self.compatibility_flags,
+            egress,
+        })
+    }
+
+    // keccak256 of the bundle encoding of everything but the secrets, i.e. the modules, bindings and settings
+    // secrets are encrypted against it, so they only decrypt as part of the deployment they were made for
+    pub fn code_hash(&self) -> [u8; 32] {
+        let deployment = Deployment {
+            secrets: Vec::new(),
+            ..self.clone()
+        };
+        let mut hasher = Keccak::v256();
+        hasher.update(&bundle::encode(&deployment));
+        let mut hash = [0u8; 32];
+        hasher.finalize(&mut hash);
+
+        hash
+    }
+
+    // bindings along with the decrypted secrets
+    pub fn decrypt_bindings(&self, key: &k256::SecretKey) -> Result<Vec<Binding>, ServerlessError> {
+        let mut bindings = self.bindings.clone();
+        if self.secrets.is_empty() {
+            return Ok(bindings);
+        }
+
+        let code_hash = self.code_hash();
+        for secret in &self.secrets {
+            let value = secrets::decrypt(key, &code_hash, &secret.name, &secret.ciphertext)
+                .map_err(|err| ServerlessError::SecretDecrypt(secret.name.clone(), err))?;
+            bindings.push(Binding {
+                name: secret.name.clone(),
+                value: BindingValue::Text(value),
+            });
+        }
+
+        Ok(bindings)
+    }
+}
+
+const WASM_MAGIC: &[u8] = b"\0asm";
+
+// entrypoint wrapping a raw wasm payload, see the README for the interface the wasm has to export
+const WASM_SHIM: &str = r#"import wasm from "main.wasm";
+
+export default {
+  async fetch(request) {
+    const { memory, alloc, handle } = new WebAssembly.Instance(wasm, {}).exports;
+    const input = new Uint8Array(await request.arrayBuffer());
+    const ptr = alloc(input.length);
+    new Uint8Array(memory.buffer, ptr, input.length).set(input);
+    const output = BigInt.asUintN(64, BigInt(handle(ptr, input.length)));
+    const outputPtr = Number(output >> 32n);
+    const outputLen = Number(output & 0xffffffffn);
+    return new Response(new Uint8Array(memory.buffer, outputPtr, outputLen).slice());
+  },
+};
+"#;
+
+// split the deployed code into worker modules based on the payload type
+pub fn get_deployment(code: Vec<u8>) -> Result<Deployment, ServerlessError> {
+    if code.starts_with(bundle::BUNDLE_MAGIC) {
+        return bundle::parse(&code);
+    }
+
+    if code.starts_with(WASM_MAGIC) {
+        return Ok(Deployment::from_modules(vec![
+            Module {
+                name: "main".to_owned(),
+                kind: ModuleKind::EsModule,
+                content: WASM_SHIM.as_bytes().to_vec(),
+            },
+            Module {
+                name: "main.wasm".to_owned(),
+                kind: ModuleKind::Wasm,
+                content: code,
+            },
+        ]));
+    }
+
+    Ok(Deployment::from_modules(vec![Module {
+        name: "main".to_owned(),
+        kind: ModuleKind::EsModule,
+        content: code,
+    }]))
+}
+
+// modules are written to a directory named <tx_hash>-<slug> in the runtime path
 pub async fn create_code_file(
+    modules: &[Module],
     tx_hash: &str,
     slug: &str,
     workerd_runtime_path: &str,
-    rpc: &str,
-    contract: &str,
 ) -> Result<(), ServerlessError> {
-    // get tx data
-    let mut tx_data = match get_transaction_data(tx_hash, rpc).await?["result"].take() {
-        Value::Null => Err(ServerlessError::TxNotFound),
-        other => Ok(other),
-    }?;
-
-    // get contract address
-    let contract_address = match tx_data["to"].take() {
-        Value::String(value) => Ok(value),
-        _ => Err(ServerlessError::InvalidTxToType),
-    }?;
-
-    // check contract address matches expected
-    if contract_address != contract {
-        return Err(ServerlessError::InvalidTxToValue(
-            contract_address,
-            contract.to_owned(),
-        ));
-    }
-
-    // get calldata
-    let calldata = match tx_data["input"].take() {
-        Value::String(calldata) => Ok(calldata),
-        _ => Err(ServerlessError::InvalidTxCalldataType),
-    }?;
-
-    // hex decode calldata by skipping to the code bytes
-    let mut calldata = hex::decode(&calldata[138..])?;
-
-    // strip trailing zeros
-    let idx = calldata.iter().rev().position(|x| *x != 0).unwrap_or(0);
-    calldata.truncate(calldata.len() - idx);
+    let dir = workerd_runtime_path.to_owned() + "/" + tx_hash + "-" + slug;
+    tokio::fs::create_dir(&dir)
+        .await
+        .map_err(ServerlessError::CodeFileCreate)?;
 
-    // write calldata to file
-    let mut file =
-        File::create(workerd_runtime_path.to_owned() + "/" + tx_hash + "-" + slug + ".js")
+    // write modules to files, names can contain directories
+    for module in modules {
+        let path = Path::new(&dir).join(&module.name);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(ServerlessError::CodeFileCreate)?;
+        }
+        let mut file = File::create(path)
             .await
             .map_err(ServerlessError::CodeFileCreate)?;
-    file.write_all(calldata.as_slice())
-        .await
-        .map_err(ServerlessError::CodeFileCreate)?;
+        file.write_all(&module.content)
+            .await
+            .map_err(ServerlessError::CodeFileCreate)?;
+        // tokio files finish writes in the background, make sure the data is there for workerd
+        file.flush()
+            .await
+            .map_err(ServerlessError::CodeFileCreate)?;
+    }
     Ok(())
 }
 
 pub async fn create_config_file(
+    config: &WorkerConfig,
     tx_hash: &str,
     slug: &str,
     workerd_runtime_path: &str,
     free_port: u16,
 ) -> Result<(), ServerlessError> {
+    let modules = config
+        .modules
+        .iter()
+        .map(|module| {
+            format!(
+                "    (name = \"{}\", {} = embed \"{tx_hash}-{slug}/{}\")",
+                module.name,
+                module.kind.capnp_field(),
+                module.name
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",\n");
+    let mut bindings = config
+        .bindings
+        .iter()
+        .map(|binding| {
+            let (field, value) = match &binding.value {
+                BindingValue::Text(value) => ("text", value),
+                BindingValue::Json(value) => ("json", value),
+            };
+            format!(
+                "    (name = \"{}\", {field} = {})",
+                binding.name,
+                capnp_string(value)
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // outbound access goes through an egress network service,
+    // hosts get their own external services exposed as service bindings
+    let mut services = vec!["(name = \"main\", worker = .oysterWorker)".to_owned()];
+    let mut global_outbound = String::new();
+    if let Some(rules) = &config.egress {
+        let networks = rules
+            .iter()
+            .filter_map(|rule| match rule {
+                EgressRule::Network(net) => Some(capnp_string(&net.to_string())),
+                EgressRule::Host { .. } => None,
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        services.push(format!(
+            "(name = \"egress\", network = (allow = [ {networks} ]))"
+        ));
+        global_outbound = "  globalOutbound = \"egress\",\n".to_owned();
+
+        for (idx, rule) in rules.iter().enumerate() {
+            let EgressRule::Host {
+                binding,
+                https,
+                host,
+                port,
+            } = rule
+            else {
+                continue;
+            };
+            let address = capnp_string(&format!("{host}:{port}"));
+            let protocol = match https {
+                true => format!("https = (certificateHost = {})", capnp_string(host)),
+                false => "http = ()".to_owned(),
+            };
+            services.push(format!(
+                "(name = \"egress-{idx}\", external = (address = {address}, {protocol}))"
+            ));
+            bindings.push(format!(
+                "    (name = \"{binding}\", service = \"egress-{idx}\")"
+            ));
+        }
+    }
+    let services = services.join(",\n    ");
+    let bindings = bindings.join(",\n");
+    let compatibility_date = capnp_string(&config.compatibility_date);
+    let compatibility_flags = config
+        .compatibility_flags
+        .iter()
+        .map(|flag| capnp_string(flag))
+        .collect::<Vec<_>>()
+        .join(", ");
+
     let capnp_data = format!(
         "
 using Workerd = import \"/workerd/workerd.capnp\";
 
 const oysterConfig :Workerd.Config = (
-  services = [ (name = \"main\", worker = .oysterWorker) ],
+  services = [
+    {services}
+  ],
   sockets = [ ( name = \"http\", address = \"*:{free_port}\", http = (), service = \"main\" ) ]
 );
 
 const oysterWorker :Workerd.Worker = (
   modules = [
-    (name = \"main\", esModule = embed \"{tx_hash}-{slug}.js\")
+{modules}
+  ],
+  bindings = [
+{bindings}
   ],
-  compatibilityDate = \"2023-03-07\",
-);"
+  compatibilityDate = {compatibility_date},
+  compatibilityFlags = [ {compatibility_flags} ],
+{global_outbound});"
     );
 
     let mut file =
@@ -146,11 +488,32 @@ const oysterWorker :Workerd.Worker = (
     file.write_all(capnp_data.as_bytes())
         .await
         .map_err(ServerlessError::ConfigFileCreate)?;
+    file.flush()
+        .await
+        .map_err(ServerlessError::ConfigFileCreate)?;
     Ok(())
 }
 
+// quote a string as a capnp text literal
+fn capnp_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_ascii_control() => quoted.push_str(&format!("\\x{:02x}", c as u8)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
 pub fn get_port(cgroup: &str) -> Result<u16, ServerlessError> {
-    u16::from_str_radix(&cgroup[8..], 10)
+    cgroup[8..]
+        .parse::<u16>()
         .map(|x| x + 11000)
         .map_err(ServerlessError::BadPort)
 }
@@ -172,6 +535,13 @@ pub async fn execute(
     Ok(Cgroups::execute(cgroup, args)?)
 }
 
+pub fn terminate(child: &mut Child) -> Result<(), ServerlessError> {
+    child.kill().map_err(ServerlessError::Terminate)?;
+    // reap the process so it does not linger as a zombie
+    child.wait().map_err(ServerlessError::Terminate)?;
+    Ok(())
+}
+
 pub async fn wait_for_port(port: u16) -> bool {
     let start_time = Instant::now();
 
@@ -184,98 +554,445 @@ pub async fn wait_for_port(port: u16) -> bool {
     false
 }
 
-pub async fn cleanup_code_file(
+// cleanup is synchronous since it runs when worker resources are dropped
+pub fn cleanup_code_file(
     tx_hash: &str,
     slug: &str,
     workerd_runtime_path: &str,
 ) -> Result<(), ServerlessError> {
-    tokio::fs::remove_file(workerd_runtime_path.to_owned() + "/" + tx_hash + "-" + slug + ".js")
-        .await
+    std::fs::remove_dir_all(workerd_runtime_path.to_owned() + "/" + tx_hash + "-" + slug)
         .map_err(ServerlessError::CodeFileDelete)?;
     Ok(())
 }
 
-pub async fn cleanup_config_file(
+pub fn cleanup_config_file(
     tx_hash: &str,
     slug: &str,
     workerd_runtime_path: &str,
 ) -> Result<(), ServerlessError> {
-    tokio::fs::remove_file(workerd_runtime_path.to_owned() + "/" + tx_hash + "-" + slug + ".capnp")
-        .await
+    std::fs::remove_file(workerd_runtime_path.to_owned() + "/" + tx_hash + "-" + slug + ".capnp")
         .map_err(ServerlessError::ConfigFileDelete)?;
     Ok(())
 }
 
-pub async fn get_workerd_response(
-    port: u16,
-    req: HttpRequest,
-    body: actix_web::web::Bytes,
-    signer: &k256::ecdsa::SigningKey,
+// hash everything known before the request body
+fn request_hasher(
+    timestamp: u64,
+    req: &HttpRequest,
     host_header: &str,
-) -> Result<HttpResponse, anyhow::Error> {
-    let mut hasher = Keccak::v256();
-    hasher.update(b"|oyster-serverless-hasher|");
+    scheme: &SignatureScheme,
+) -> MessageHasher {
+    let request_headers = verify::canonical_headers(
+        scheme.request_headers(),
+        req.headers()
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_bytes())),
+    );
 
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)?
-        .as_secs();
-    hasher.update(b"|timestamp|");
-    hasher.update(&timestamp.to_be_bytes());
-
-    hasher.update(b"|request|");
-    hasher.update(b"|method|");
-    hasher.update(req.method().to_string().as_bytes());
-    hasher.update(b"|pathandquery|");
-    hasher.update(
-        req.uri()
-            .path_and_query()
-            .map(|x| x.as_str())
-            .unwrap_or("")
-            .as_bytes(),
+    MessageHasher::new(
+        scheme.version,
+        &scheme.domain,
+        timestamp,
+        req.headers().get("X-Oyster-Nonce").map(|x| x.as_bytes()),
+        req.method().as_str(),
+        req.uri().path_and_query().map(|x| x.as_str()).unwrap_or(""),
+        host_header,
+        &request_headers,
+    )
+}
+
+// hash the status and headers of the worker response
+fn response_hasher(
+    hasher: &mut MessageHasher,
+    status: reqwest::StatusCode,
+    headers: &reqwest::header::HeaderMap,
+    scheme: &SignatureScheme,
+) {
+    let response_headers = verify::canonical_headers(
+        scheme.response_headers(),
+        headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_bytes())),
     );
-    hasher.update(b"|host|");
-    hasher.update(host_header.as_bytes());
-    hasher.update(b"|body|");
-    hasher.update(&body);
 
+    hasher.response(status.as_u16(), &response_headers);
+}
+
+// headers of the worker response, without the ones only the node may set
+// every x-oyster-* header belongs to the node, e.g. signatures, usage, console output and errors
+fn worker_headers(response: &reqwest::Response) -> reqwest::header::HeaderMap {
+    let mut headers = response.headers().clone();
+    let reserved = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with("x-oyster-"))
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in reserved {
+        headers.remove(name);
+    }
+
+    headers
+}
+
+// tell verifiers how the response is signed
+fn insert_signed_headers(
+    resp: &mut HttpResponseBuilder,
+    req: &HttpRequest,
+    scheme: &SignatureScheme,
+) {
+    if let Some(nonce) = req.headers().get("X-Oyster-Nonce") {
+        resp.insert_header(("X-Oyster-Nonce", nonce.clone()));
+    }
+    resp.insert_header(("X-Oyster-Signature-Version", scheme.version.to_string()));
+    if scheme.version == SignatureVersion::V1 {
+        return;
+    }
+
+    resp.insert_header((
+        "X-Oyster-Signed-Request-Headers",
+        scheme.request_headers().join(", "),
+    ));
+    resp.insert_header((
+        "X-Oyster-Signed-Response-Headers",
+        scheme.response_headers().join(", "),
+    ));
+}
+
+fn sign(hasher: MessageHasher, signer: &k256::ecdsa::SigningKey) -> Result<Vec<u8>, anyhow::Error> {
+    let (rs, v) = signer.sign_prehash_recoverable(&hasher.finalize())?;
+
+    Ok(rs.to_bytes().append(27 + v.to_byte()).to_vec())
+}
+
+// sign an error returned by the node instead of the worker
+// the request body is left out of the signature if the node did not read all of it
+// the response is returned unsigned if signing fails or the body is not buffered
+pub fn sign_error(
+    resp: HttpResponse,
+    req: &HttpRequest,
+    request_body: Option<&[u8]>,
+    host_header: &str,
+    signer: &k256::ecdsa::SigningKey,
+    scheme: &SignatureScheme,
+) -> HttpResponse {
+    let (resp, body) = resp.into_parts();
+    let body = match body.try_into_bytes() {
+        Ok(body) => body,
+        Err(body) => return resp.set_body(body),
+    };
+
+    let timestamp = match timestamp() {
+        Ok(timestamp) => timestamp,
+        Err(err) => {
+            error!(error = logging::chain(err), "failed to sign error");
+            return resp.set_body(body.boxed());
+        }
+    };
+    let nonce = req.headers().get("X-Oyster-Nonce");
+    let hash = verify::error_hash(
+        scheme.version,
+        &scheme.domain,
+        timestamp,
+        nonce.map(|x| x.as_bytes()),
+        req.method().as_str(),
+        req.uri().path_and_query().map(|x| x.as_str()).unwrap_or(""),
+        host_header,
+        request_body,
+        resp.status().as_u16(),
+        &body,
+    );
+    let signature = match signer.sign_prehash_recoverable(&hash) {
+        Ok((rs, v)) => rs.to_bytes().append(27 + v.to_byte()).to_vec(),
+        Err(err) => {
+            error!(error = logging::chain(err), "failed to sign error");
+            return resp.set_body(body.boxed());
+        }
+    };
+
+    let mut signed = HttpResponse::build(resp.status());
+    for (name, value) in resp.headers() {
+        signed.append_header((name.clone(), value.clone()));
+    }
+    signed.insert_header(("X-Oyster-Error", "true"));
+    signed.insert_header(("X-Oyster-Timestamp", timestamp.to_string()));
+    signed.insert_header(("X-Oyster-Signature", hex::encode(signature)));
+    signed.insert_header(("X-Oyster-Signature-Version", scheme.version.to_string()));
+    if let Some(nonce) = nonce {
+        signed.insert_header(("X-Oyster-Nonce", nonce.clone()));
+    }
+    if request_body.is_none() {
+        signed.insert_header(("X-Oyster-Signed-Request-Body", "false"));
+    }
+
+    signed.body(body)
+}
+
+fn timestamp() -> Result<u64, anyhow::Error> {
+    Ok(std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs())
+}
+
+fn worker_request(
+    port: u16,
+    req: &HttpRequest,
+    body: impl Into<reqwest::Body>,
+) -> Result<reqwest::RequestBuilder, anyhow::Error> {
     let port_str = port.to_string();
     let req_url = "http://127.0.0.1:".to_string() + &port_str + "/";
     let client = reqwest::Client::builder()
         .redirect(Policy::none())
         .build()?;
-    let response = req
+
+    Ok(req
         .headers()
         .into_iter()
         .fold(
             client.request(req.method().clone(), req_url),
             |req, header| req.header(header.0.clone(), header.1.clone()),
         )
-        .body(body)
-        .send()
-        .await?;
-    hasher.update(b"|response|");
-
-    let mut actix_resp = response.headers().into_iter().fold(
-        HttpResponse::build(response.status()),
-        |mut resp, header| {
-            resp.append_header((header.0.clone(), header.1.clone()));
-            resp
-        },
-    );
+        .body(body))
+}
+
+// the console of debug requests is stopped once the response is in and its output returned in signed headers
+#[allow(clippy::too_many_arguments)]
+pub async fn get_workerd_response(
+    port: u16,
+    req: HttpRequest,
+    body: actix_web::web::Bytes,
+    signer: &k256::ecdsa::SigningKey,
+    host_header: &str,
+    scheme: &SignatureScheme,
+    usage: Option<UsageMeter>,
+    console: Option<&Console>,
+) -> Result<HttpResponse, anyhow::Error> {
+    // usage and console output are signed along with the response
+    let mut scheme = match usage {
+        Some(_) => scheme.with_usage(),
+        None => scheme.clone(),
+    };
+    if console.is_some() {
+        scheme = scheme.with_console();
+    }
+    let scheme = &scheme;
+    let timestamp = timestamp()?;
+    let mut hasher = request_hasher(timestamp, &req, host_header, scheme);
+    hasher.request_body(&body);
+
+    let response = worker_request(port, &req, body)?.send().await?;
+    let status = response.status();
+    let mut headers = worker_headers(&response);
     let response_body = response.bytes().await?;
 
-    hasher.update(b"|body|");
-    hasher.update(&response_body);
+    // the worker is only done once the body is
+    if let Some(usage) = usage {
+        for (name, value) in usage.finish().await.headers() {
+            headers.insert(name, value);
+        }
+    }
+    if let Some(console) = console {
+        for (name, value) in console.finish().await.headers() {
+            headers.insert(name, value);
+        }
+    }
 
-    let mut hash = [0u8; 32];
-    hasher.finalize(&mut hash);
+    // the response headers come before the body in the layout, nothing of the body is hashed yet
+    response_hasher(&mut hasher, status, &headers, scheme);
+    hasher.response_body(&response_body);
 
-    let (rs, v) = signer.sign_prehash_recoverable(&hash)?;
+    let mut actix_resp = headers
+        .iter()
+        .fold(HttpResponse::build(status), |mut resp, header| {
+            resp.append_header((header.0.clone(), header.1.clone()));
+            resp
+        });
 
-    let signature = rs.to_bytes().append(27 + v.to_byte());
+    let signature = sign(hasher, signer)?;
 
     actix_resp.insert_header(("X-Oyster-Timestamp", timestamp.to_string()));
-    actix_resp.insert_header(("X-Oyster-Signature", hex::encode(signature.as_slice())));
+    actix_resp.insert_header(("X-Oyster-Signature", hex::encode(signature)));
+    insert_signed_headers(&mut actix_resp, &req, scheme);
 
     Ok(actix_resp.body(response_body))
 }
+
+// parts of a streamed response, hashed once the request body is
+enum ResponsePart {
+    Head(reqwest::StatusCode, reqwest::header::HeaderMap),
+    Chunk(Bytes),
+}
+
+// forwards the request body to the worker, resolving to the signature once the response parts are in
+type Upload = Pin<Box<MaybeDone<LocalBoxFuture<'static, Result<Option<Vec<u8>>, anyhow::Error>>>>>;
+
+// drive the upload until `next` is ready, the payload can only be read from the task serving the request
+async fn with_upload<T>(upload: &mut Upload, next: impl Future<Output = T>) -> T {
+    tokio::pin!(next);
+    loop {
+        let uploading = matches!(upload.as_ref().get_ref(), MaybeDone::Future(_));
+        tokio::select! {
+            _ = upload.as_mut(), if uploading => {}
+            next = &mut next => return next,
+        }
+    }
+}
+
+// streaming variant of get_workerd_response, both bodies are forwarded chunk by chunk
+// the request body keeps being forwarded while the response is, so the response can start before the upload is done
+// clients opt into a signature with X-Oyster-Stream-Signature: appended, the hash then uses the same layout
+// and the signature is sent as the final 65 bytes of the response body since actix cannot send trailers
+// chunks are returned as they arrive from the worker, failing if it stays idle for longer than idle_timeout
+// signed responses append the usage before the signature, see Usage::trailer, it is signed as part of the body
+#[allow(clippy::too_many_arguments)]
+pub async fn get_workerd_stream(
+    port: u16,
+    req: HttpRequest,
+    mut payload: actix_web::web::Payload,
+    signer: k256::ecdsa::SigningKey,
+    host_header: &str,
+    scheme: &SignatureScheme,
+    idle_timeout: Duration,
+    usage: Option<UsageMeter>,
+) -> Result<
+    (
+        HttpResponseBuilder,
+        impl Stream<Item = Result<Bytes, anyhow::Error>>,
+    ),
+    anyhow::Error,
+> {
+    let timestamp = timestamp()?;
+    let signed = req
+        .headers()
+        .get("X-Oyster-Stream-Signature")
+        .is_some_and(|x| x == "appended");
+    let mut hasher = signed.then(|| request_hasher(timestamp, &req, host_header, scheme));
+    let usage = usage.filter(|_| signed);
+
+    // the payload is not Send, forward it to the client through a channel
+    // response parts are hashed after the request body and signed once they are done
+    let (mut tx, rx) = futures::channel::mpsc::channel::<Result<Bytes, std::io::Error>>(16);
+    let (parts_tx, mut parts_rx) = futures::channel::mpsc::unbounded::<ResponsePart>();
+    let upload_scheme = scheme.clone();
+    let mut upload: Upload = Box::pin(futures::future::maybe_done(
+        async move {
+            let mut forwarding = true;
+            while let Some(chunk) = payload.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(err) => {
+                        let _ = tx.send(Err(std::io::Error::other(err.to_string()))).await;
+                        return Err(anyhow!(err).context("failed to read request body"));
+                    }
+                };
+                if let Some(hasher) = &mut hasher {
+                    hasher.request_body(&chunk);
+                }
+                // the worker is free to respond without reading the whole body,
+                // keep hashing what the client sent either way
+                if forwarding && tx.send(Ok(chunk)).await.is_err() {
+                    forwarding = false;
+                }
+            }
+            drop(tx);
+
+            let Some(mut hasher) = hasher else {
+                return Ok(None);
+            };
+            while let Some(part) = parts_rx.next().await {
+                match part {
+                    ResponsePart::Head(status, headers) => {
+                        response_hasher(&mut hasher, status, &headers, &upload_scheme)
+                    }
+                    ResponsePart::Chunk(chunk) => hasher.response_body(&chunk),
+                }
+            }
+
+            sign(hasher, &signer).map(Some)
+        }
+        .boxed_local(),
+    ));
+
+    let request = worker_request(port, &req, reqwest::Body::wrap_stream(rx))?;
+    let response = with_upload(&mut upload, request.send()).await?;
+    let headers = worker_headers(&response);
+    let parts_tx = signed.then_some(parts_tx);
+    if let Some(parts_tx) = &parts_tx {
+        let _ = parts_tx.unbounded_send(ResponsePart::Head(response.status(), headers.clone()));
+    }
+
+    // the body is streamed and may get the signature appended, drop the framing headers of the worker
+    let mut actix_resp = headers
+        .iter()
+        .filter(|header| {
+            header.0 != reqwest::header::CONTENT_LENGTH
+                && header.0 != reqwest::header::TRANSFER_ENCODING
+        })
+        .fold(
+            HttpResponse::build(response.status()),
+            |mut resp, header| {
+                resp.append_header((header.0.clone(), header.1.clone()));
+                resp
+            },
+        );
+    actix_resp.insert_header(("X-Oyster-Timestamp", timestamp.to_string()));
+    actix_resp.insert_header(("X-Oyster-Stream", "true"));
+    if signed {
+        actix_resp.insert_header(("X-Oyster-Stream-Signature", "appended"));
+        insert_signed_headers(&mut actix_resp, &req, scheme);
+    }
+    if usage.is_some() {
+        actix_resp.insert_header(("X-Oyster-Stream-Usage", "appended"));
+    }
+
+    let body = futures::stream::unfold(
+        Some((Box::pin(response.bytes_stream()), parts_tx, upload, usage)),
+        move |state| async move {
+            let (mut body, parts_tx, mut upload, usage) = state?;
+            let next = tokio::time::timeout(idle_timeout, body.next());
+            match with_upload(&mut upload, next).await {
+                Ok(Some(Ok(chunk))) => {
+                    if let Some(parts_tx) = &parts_tx {
+                        let _ = parts_tx.unbounded_send(ResponsePart::Chunk(chunk.clone()));
+                    }
+                    Some((Ok(chunk), Some((body, parts_tx, upload, usage))))
+                }
+                Ok(Some(Err(err))) => Some((
+                    Err(anyhow!(err).context("failed to read response body")),
+                    None,
+                )),
+                // unsigned responses are done, whatever is left of the request body is not needed
+                Ok(None) if parts_tx.is_none() => None,
+                Ok(None) => {
+                    // the worker is done, its usage is signed along with the body
+                    let trailer = match usage {
+                        Some(usage) => usage.finish().await.trailer(),
+                        None => Vec::new(),
+                    };
+                    if let Some(parts_tx) = &parts_tx {
+                        let _ = parts_tx
+                            .unbounded_send(ResponsePart::Chunk(Bytes::from(trailer.clone())));
+                    }
+
+                    // the signature needs the rest of the request body too,
+                    // clients that stop sending it would hold on to the worker forever
+                    drop(parts_tx);
+                    if tokio::time::timeout(idle_timeout, upload.as_mut())
+                        .await
+                        .is_err()
+                    {
+                        return Some((Err(anyhow!("request body timed out")), None));
+                    }
+                    match upload.as_mut().take_output().unwrap() {
+                        Ok(signature) => {
+                            signature.map(|x| (Ok(Bytes::from([trailer, x].concat())), None))
+                        }
+                        Err(err) => Some((Err(err), None)),
+                    }
+                }
+                Err(err) => Some((Err(anyhow!(err).context("worker timed out")), None)),
+            }
+        },
+    );
+
+    Ok((actix_resp, body))
+}